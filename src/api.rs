@@ -0,0 +1,570 @@
+//! HTTP REST API exposing the heating state and the recent frames, and sending commands.
+
+use std::collections::{HashMap, VecDeque};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, SystemTime};
+
+use serde_json::{json, Value};
+use tiny_http::{Header, Method, Response, Server};
+
+use crate::cli::log;
+use crate::commands::{self, Command, ConsigneRange, ZoneCommand};
+use crate::frisquet::proto::{FrisquetData, FrisquetMetadata};
+use crate::metrics;
+use crate::pairing;
+use crate::rf::request::{self, RequestOptions};
+use crate::rf::{unix_seconds, RFClient, ReceivedFrame};
+use crate::settings::parse_setting;
+use crate::sink::Sink;
+use crate::state::{satellite_addr, Device, HeatingState};
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ApiSettings {
+    /// Address the server listens on, e.g. `127.0.0.1:8080`.
+    pub listen: String,
+    /// Token required as `Authorization: Bearer <token>`, when set.
+    pub token: Option<String>,
+    /// Number of recent frames kept for `/frames`.
+    pub frames_capacity: usize,
+    pub consigne_range: ConsigneRange,
+    /// How long the boiler's pairing broadcast is waited for.
+    pub pairing_timeout: Duration,
+}
+
+impl ApiSettings {
+    /// Reads the `http_*` settings, the API being enabled by `http_listen`.
+    pub fn from_settings(
+        settings: &HashMap<String, String>,
+    ) -> Result<Option<ApiSettings>, String> {
+        let Some(listen) = settings.get("http_listen") else {
+            return Ok(None);
+        };
+        Ok(Some(ApiSettings {
+            listen: listen.clone(),
+            token: settings.get("http_token").cloned(),
+            frames_capacity: parse_setting(settings, "http_frames_capacity")?.unwrap_or(500),
+            consigne_range: ConsigneRange::from_settings(settings)?,
+            pairing_timeout: Duration::from_secs(
+                parse_setting(settings, "pairing_timeout_secs")?.unwrap_or(120),
+            ),
+        }))
+    }
+}
+
+/// An action needing the radio, executed by the main loop.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Action {
+    Zone(Command),
+    /// Sends the outdoor temperature as the sonde.
+    SondeTemperature(f64),
+    StartPairing,
+}
+
+/// An action waiting for the main loop, with the channel its HTTP request waits on.
+pub struct ApiRequest {
+    pub action: Action,
+    reply: Sender<Result<Value, String>>,
+}
+
+/// What the server threads read, kept up to date by [`ApiSink`].
+#[derive(Debug, Default)]
+struct Shared {
+    state: HeatingState,
+    frames: VecDeque<(SystemTime, Value)>,
+}
+
+/// Mirrors the decoded frames and the state for the server.
+pub struct ApiSink {
+    shared: Arc<Mutex<Shared>>,
+    capacity: usize,
+}
+
+/// Receives the actions posted to the API and executes them with the radio.
+pub struct Api {
+    requests: Receiver<ApiRequest>,
+    request_options: RequestOptions,
+    request_id: u16,
+    network_id: Option<Vec<u8>>,
+    pairing_timeout: Duration,
+    pub address: String,
+}
+
+/// Starts the server when the API is enabled.
+pub fn new(settings: &HashMap<String, String>) -> Result<Option<(Api, ApiSink)>, String> {
+    let Some(api_settings) = ApiSettings::from_settings(settings)? else {
+        return Ok(None);
+    };
+    let server = Server::http(&api_settings.listen)
+        .map_err(|e| format!("Error listening on {}: {e}", api_settings.listen))?;
+    let address = server.server_addr().to_string();
+    log!(Info, "HTTP API listening on {address}");
+
+    let shared = Arc::new(Mutex::new(Shared::default()));
+    let (tx, requests) = mpsc::channel();
+    let api = Api {
+        requests,
+        request_options: RequestOptions::from_settings(settings)?,
+        request_id: 0x2100,
+        network_id: settings
+            .get("network_id")
+            .map(|id| hex::decode(id).map_err(|e| format!("invalid network_id: {e}")))
+            .transpose()?,
+        pairing_timeout: api_settings.pairing_timeout,
+        address,
+    };
+    let sink = ApiSink {
+        shared: shared.clone(),
+        capacity: api_settings.frames_capacity,
+    };
+    let api_settings = Arc::new(api_settings);
+    thread::spawn(move || {
+        for request in server.incoming_requests() {
+            let (shared, tx, api_settings) = (shared.clone(), tx.clone(), api_settings.clone());
+            // POSTs wait for the main loop, the GETs must not wait behind them.
+            thread::spawn(move || handle(request, &api_settings, &shared, &tx));
+        }
+    });
+    Ok(Some((api, sink)))
+}
+
+fn handle(
+    mut request: tiny_http::Request,
+    settings: &ApiSettings,
+    shared: &Mutex<Shared>,
+    tx: &Sender<ApiRequest>,
+) {
+    let authorization = request
+        .headers()
+        .iter()
+        .find(|h| h.field.equiv("Authorization"))
+        .map(|h| h.value.to_string());
+    // The only endpoint not answering JSON.
+    if *request.method() == Method::Get
+        && request.url() == "/metrics"
+        && authorized(settings, authorization.as_deref())
+    {
+        let response = Response::from_string(metrics::render(SystemTime::now()))
+            .with_header(metrics::content_type());
+        if let Err(e) = request.respond(response) {
+            log!(Error, "Error answering HTTP request: {e}");
+        }
+        return;
+    }
+    let mut body = String::new();
+    let (status, value) = match request.as_reader().read_to_string(&mut body) {
+        Ok(_) => route(
+            request.method(),
+            request.url(),
+            authorization.as_deref(),
+            &body,
+            settings,
+            shared,
+            tx,
+        ),
+        Err(e) => (400, json!({ "error": e.to_string() })),
+    };
+    let response = Response::from_string(value.to_string())
+        .with_status_code(status)
+        .with_header(Header::from_bytes("Content-Type", "application/json").unwrap());
+    if let Err(e) = request.respond(response) {
+        log!(Error, "Error answering HTTP request: {e}");
+    }
+}
+
+fn authorized(settings: &ApiSettings, authorization: Option<&str>) -> bool {
+    match &settings.token {
+        Some(token) => authorization.and_then(|a| a.strip_prefix("Bearer ")) == Some(token),
+        None => true,
+    }
+}
+
+fn error(status: u16, message: impl Into<String>) -> (u16, Value) {
+    (status, json!({ "error": message.into() }))
+}
+
+/// Answers a request, returning the status and the JSON body.
+fn route(
+    method: &Method,
+    url: &str,
+    authorization: Option<&str>,
+    body: &str,
+    settings: &ApiSettings,
+    shared: &Mutex<Shared>,
+    tx: &Sender<ApiRequest>,
+) -> (u16, Value) {
+    let (path, query) = url.split_once('?').unwrap_or((url, ""));
+    if path == "/openapi.json" && *method == Method::Get {
+        return (200, openapi());
+    }
+    if !authorized(settings, authorization) {
+        return error(401, "missing or invalid token");
+    }
+
+    let segments: Vec<&str> = path.trim_matches('/').split('/').collect();
+    let action = match (method, &segments[..]) {
+        (Method::Get, ["state"]) => return (200, json!(shared.lock().unwrap().state)),
+        (Method::Get, ["frames"]) => {
+            let since = match query_param(query, "since").map(str::parse::<f64>) {
+                None => 0.0,
+                Some(Ok(since)) => since,
+                Some(Err(_)) => return error(400, "invalid since"),
+            };
+            return (200, json!(frames_since(&shared.lock().unwrap(), since)));
+        }
+        (Method::Post, ["zones", zone, "consigne"]) => {
+            let Some(zone) = zone.parse().ok().filter(|z| satellite_addr(*z).is_some()) else {
+                return error(404, format!("unknown zone {zone}"));
+            };
+            let consigne = match number_field(body, "consigne") {
+                Ok(consigne) => consigne,
+                Err(e) => return error(400, e),
+            };
+            match settings.consigne_range.validate(consigne) {
+                Ok(consigne) => Action::Zone(Command {
+                    zone,
+                    command: ZoneCommand::Consigne(consigne),
+                }),
+                Err(e) => return error(400, e),
+            }
+        }
+        (Method::Post, ["sonde", "temperature"]) => match number_field(body, "temperature") {
+            Ok(temperature) => Action::SondeTemperature(temperature),
+            Err(e) => return error(400, e),
+        },
+        (Method::Post, ["pairing", "start"]) => Action::StartPairing,
+        _ => return error(404, format!("no route for {method} {path}")),
+    };
+
+    let (reply, result) = mpsc::channel();
+    if tx.send(ApiRequest { action, reply }).is_err() {
+        return error(503, "the radio loop is not running");
+    }
+    match result.recv() {
+        Ok(Ok(value)) => (200, value),
+        Ok(Err(e)) => error(502, e),
+        Err(_) => error(503, "the action was dropped"),
+    }
+}
+
+fn query_param<'a>(query: &'a str, name: &str) -> Option<&'a str> {
+    query
+        .split('&')
+        .filter_map(|pair| pair.split_once('='))
+        .find(|(key, _)| *key == name)
+        .map(|(_, value)| value)
+}
+
+fn number_field(body: &str, field: &str) -> Result<f64, String> {
+    let body: Value = serde_json::from_str(body).map_err(|e| format!("invalid JSON: {e}"))?;
+    body[field]
+        .as_f64()
+        .ok_or_else(|| format!("missing number {field}"))
+}
+
+/// The frames received after `since`, in seconds since the epoch.
+fn frames_since(shared: &Shared, since: f64) -> Vec<Value> {
+    shared
+        .frames
+        .iter()
+        .filter(|(time, _)| unix_seconds(*time) > since)
+        .map(|(_, frame)| frame.clone())
+        .collect()
+}
+
+impl Sink for ApiSink {
+    fn name(&self) -> &str {
+        "HTTP API"
+    }
+
+    fn frame(
+        &mut self,
+        frame: &ReceivedFrame,
+        metadata: &FrisquetMetadata,
+        data: &FrisquetData,
+        state: &HeatingState,
+        _devices: &[Device],
+    ) -> Result<(), String> {
+        let mut shared = self.shared.lock().unwrap();
+        shared.state = state.clone();
+        if shared.frames.len() >= self.capacity {
+            shared.frames.pop_front();
+        }
+        shared.frames.push_back((
+            frame.timestamp,
+            json!({
+                "timestamp": unix_seconds(frame.timestamp),
+                "gateway": frame.gateway,
+                "rssi": frame.rssi,
+                "lqi": frame.lqi,
+                "raw": hex::encode(&frame.data),
+                "metadata": metadata,
+                "data": data,
+            }),
+        ));
+        Ok(())
+    }
+}
+
+impl Api {
+    /// Returns the actions posted since the last call.
+    pub fn poll(&mut self) -> Vec<ApiRequest> {
+        self.requests.try_iter().collect()
+    }
+
+    /// Executes an action and answers its HTTP request, returning the boiler's answer.
+    pub fn execute(
+        &mut self,
+        client: &mut dyn RFClient,
+        state: &HeatingState,
+        request: ApiRequest,
+    ) -> Option<ReceivedFrame> {
+        self.request_id = self.request_id.wrapping_add(4);
+        let (result, answer) = match request.action {
+            Action::Zone(command) => match commands::send_zone_command(
+                client,
+                state,
+                &command,
+                self.request_id,
+                &self.request_options,
+                &mut || {},
+            ) {
+                Ok(answer) => (Ok(json!({ "status": "acknowledged" })), Some(answer)),
+                Err(e) => (Err(e.to_string()), None),
+            },
+            Action::SondeTemperature(temperature) => {
+                match commands::sonde_frame(temperature, self.request_id).and_then(|frame| {
+                    request::request(client, &frame, &self.request_options)
+                        .map_err(|e| e.to_string())
+                }) {
+                    Ok((_, answer)) => (Ok(json!({ "status": "acknowledged" })), Some(answer)),
+                    Err(e) => (Err(e), None),
+                }
+            }
+            Action::StartPairing => {
+                match pairing::pair_sonde(client, self.pairing_timeout, &self.request_options) {
+                    Ok(network_id) => {
+                        self.network_id = Some(network_id.to_vec());
+                        let network_id = hex::encode(network_id);
+                        log!(
+                            Info,
+                            "Paired on network {network_id}, set network_id to keep it"
+                        );
+                        (Ok(json!({ "network_id": network_id })), None)
+                    }
+                    Err(e) => {
+                        // Back to the network we were on.
+                        if let Some(network_id) = &self.network_id {
+                            if let Err(e) = client.set_network_id(network_id.clone()) {
+                                log!(Error, "Error restoring the network id: {e}");
+                            }
+                        }
+                        (Err(e), None)
+                    }
+                }
+            }
+        };
+        // The HTTP client may have gone away.
+        let _ = request.reply.send(result);
+        answer
+    }
+}
+
+/// OpenAPI description of the endpoints, served on `/openapi.json`.
+pub fn openapi() -> Value {
+    let error = json!({ "description": "Error", "content": { "application/json": { "schema": { "$ref": "#/components/schemas/Error" } } } });
+    let json_body = |field: &str| {
+        json!({
+            "required": true,
+            "content": { "application/json": { "schema": {
+                "type": "object",
+                "required": [field],
+                "properties": { field: { "type": "number" } },
+            } } },
+        })
+    };
+    json!({
+        "openapi": "3.0.3",
+        "info": { "title": "Frisquet commander", "version": env!("CARGO_PKG_VERSION") },
+        "components": {
+            "securitySchemes": { "bearer": { "type": "http", "scheme": "bearer" } },
+            "schemas": {
+                "Error": { "type": "object", "properties": { "error": { "type": "string" } } },
+            },
+        },
+        "security": [{ "bearer": [] }],
+        "paths": {
+            "/state": { "get": {
+                "summary": "Heating state gathered from the frames",
+                "responses": { "200": { "description": "Zones, sonde and boiler state" }, "401": error },
+            } },
+            "/frames": { "get": {
+                "summary": "Recent decoded frames",
+                "parameters": [{
+                    "name": "since", "in": "query", "required": false,
+                    "description": "Only the frames received after this time, in seconds since the epoch",
+                    "schema": { "type": "number" },
+                }],
+                "responses": { "200": { "description": "Decoded frames, oldest first" }, "400": error, "401": error },
+            } },
+            "/zones/{zone}/consigne": { "post": {
+                "summary": "Set a zone's target temperature, sent as its satellite",
+                "parameters": [{ "name": "zone", "in": "path", "required": true, "schema": { "type": "integer", "minimum": 1, "maximum": 3 } }],
+                "requestBody": json_body("consigne"),
+                "responses": { "200": { "description": "Acknowledged by the boiler" }, "400": error, "401": error, "404": error, "502": error },
+            } },
+            "/sonde/temperature": { "post": {
+                "summary": "Send the outdoor temperature, as the sonde",
+                "requestBody": json_body("temperature"),
+                "responses": { "200": { "description": "Acknowledged by the boiler" }, "400": error, "401": error, "502": error },
+            } },
+            "/metrics": { "get": {
+                "summary": "Prometheus metrics",
+                "responses": { "200": { "description": "Metrics in the Prometheus text format", "content": { "text/plain": {} } }, "401": error },
+            } },
+            "/pairing/start": { "post": {
+                "summary": "Pair as the sonde, the pairing being started on the boiler",
+                "responses": { "200": { "description": "The boiler's network id" }, "401": error, "502": error },
+            } },
+        },
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::frisquet;
+    use std::io::{Read, Write};
+    use std::net::TcpStream;
+
+    fn settings(token: Option<&str>) -> ApiSettings {
+        ApiSettings {
+            listen: "127.0.0.1:0".to_string(),
+            token: token.map(str::to_string),
+            frames_capacity: 2,
+            consigne_range: ConsigneRange {
+                min: 5.0,
+                max: 28.0,
+            },
+            pairing_timeout: Duration::from_secs(1),
+        }
+    }
+
+    fn get(url: &str, settings: &ApiSettings, shared: &Mutex<Shared>) -> (u16, Value) {
+        let (tx, _rx) = mpsc::channel();
+        route(&Method::Get, url, None, "", settings, shared, &tx)
+    }
+
+    #[test]
+    fn test_frames_and_auth() {
+        let shared = Arc::new(Mutex::new(Shared::default()));
+        let mut sink = ApiSink {
+            shared: shared.clone(),
+            capacity: 2,
+        };
+        let mut state = HeatingState::default();
+        let start = SystemTime::now();
+        for (i, raw) in [
+            "118020ba4001179c540004a029000102005c",
+            "17800819E40117A0290015A02F00040800B200AA002400C6",
+            "0b0080d3c802410405d7199e",
+        ]
+        .iter()
+        .enumerate()
+        {
+            let (metadata, data) = frisquet::parse_data_from_str(raw).unwrap();
+            let mut frame = ReceivedFrame::new(hex::decode(raw).unwrap(), "test");
+            frame.timestamp = start + Duration::from_secs(i as u64);
+            let devices = state.update(&metadata, &data, frame.timestamp);
+            sink.frame(&frame, &metadata, &data, &state, &devices)
+                .unwrap();
+        }
+
+        let open = settings(None);
+        let (status, frames) = get("/frames", &open, &shared);
+        assert_eq!(status, 200);
+        // The oldest frame was dropped.
+        assert_eq!(frames.as_array().unwrap().len(), 2);
+        let since = unix_seconds(start + Duration::from_millis(1500));
+        let (_, frames) = get(&format!("/frames?since={since}"), &open, &shared);
+        assert_eq!(frames[0]["raw"], "0b0080d3c802410405d7199e");
+        assert_eq!(frames.as_array().unwrap().len(), 1);
+        assert_eq!(get("/frames?since=x", &open, &shared).0, 400);
+        assert_eq!(
+            get("/state", &open, &shared).1["zones"]["1"]["consigne"],
+            17.0
+        );
+
+        let closed = settings(Some("secret"));
+        assert_eq!(get("/state", &closed, &shared).0, 401);
+        assert_eq!(get("/openapi.json", &closed, &shared).0, 200);
+        let (tx, _rx) = mpsc::channel();
+        let (status, _) = route(
+            &Method::Get,
+            "/state",
+            Some("Bearer secret"),
+            "",
+            &closed,
+            &shared,
+            &tx,
+        );
+        assert_eq!(status, 200);
+    }
+
+    #[test]
+    fn test_post_consigne() {
+        let (mut api, _sink) = new(&HashMap::from([
+            ("http_listen".to_string(), "127.0.0.1:0".to_string()),
+            ("request_timeout_ms".to_string(), "10".to_string()),
+            ("request_retries".to_string(), "0".to_string()),
+        ]))
+        .unwrap()
+        .unwrap();
+        let post = |path: &str, body: &str| {
+            let mut stream = TcpStream::connect(&api.address).unwrap();
+            write!(
+                stream,
+                "POST {path} HTTP/1.1\r\nHost: test\r\nConnection: close\r\nContent-Length: {}\r\n\r\n{body}",
+                body.len()
+            )
+            .unwrap();
+            stream
+        };
+        let response = |mut stream: TcpStream| {
+            let mut response = String::new();
+            stream.read_to_string(&mut response).unwrap();
+            response
+        };
+
+        assert!(
+            response(post("/zones/1/consigne", r#"{"consigne": 40}"#)).starts_with("HTTP/1.1 400")
+        );
+        assert!(
+            response(post("/zones/7/consigne", r#"{"consigne": 20}"#)).starts_with("HTTP/1.1 404")
+        );
+
+        let stream = post("/zones/1/consigne", r#"{"consigne": 21.5}"#);
+        let request = loop {
+            if let Some(request) = api.poll().pop() {
+                break request;
+            }
+            thread::sleep(Duration::from_millis(10));
+        };
+        assert_eq!(
+            request.action,
+            Action::Zone(Command {
+                zone: 1,
+                command: ZoneCommand::Consigne(21.5)
+            })
+        );
+        // The zone was never heard, its room temperature is unknown.
+        let mut client = crate::rf::testing::FakeClient::default();
+        assert!(api
+            .execute(&mut client, &HeatingState::default(), request)
+            .is_none());
+        let response = response(stream);
+        assert!(response.starts_with("HTTP/1.1 502"), "{response}");
+        assert!(response.contains("room temperature of zone 1 unknown"));
+    }
+}
@@ -0,0 +1,284 @@
+//! Command-line interface: global options and subcommands.
+
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU8, Ordering};
+
+use clap::{Args, Parser, Subcommand, ValueEnum};
+
+use crate::decode;
+use crate::history;
+use crate::state::ZoneMode;
+
+#[derive(Debug, Parser)]
+#[command(version, about = "Talks to Frisquet boilers over the air")]
+pub struct Cli {
+    /// Configuration file, without its extension.
+    #[arg(short, long, global = true, default_value = "config")]
+    pub config: String,
+
+    /// Radio transport, overriding the `transport` setting.
+    #[arg(short, long, global = true, value_parser = ["mqtt", "serial", "tcp", "sdr", "rtl433", "replay"])]
+    pub transport: Option<String>,
+
+    /// Boiler network id in hex, overriding the `network_id` setting.
+    #[arg(short, long, global = true, value_parser = parse_network_id)]
+    pub network_id: Option<String>,
+
+    #[arg(short, long, global = true, value_enum, default_value_t = LogLevel::Info)]
+    pub log_level: LogLevel,
+
+    /// Runs the bridge with the configured sinks and emulated devices when omitted.
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    /// Listens and prints the frames, without transmitting.
+    Sniff,
+    /// Pairs as the sonde, the pairing having to be started on the boiler.
+    Pair {
+        /// Seconds to wait for the boiler's association broadcast.
+        #[arg(long, default_value_t = 120)]
+        timeout: u64,
+    },
+    /// Sends the outdoor temperature as the sonde.
+    EmulateSonde {
+        /// Temperature in °C, instead of the configured outdoor source.
+        #[arg(long, allow_negative_numbers = true)]
+        temperature: Option<f64>,
+        /// Sends once and exits, instead of every `sonde_interval_secs`.
+        #[arg(long)]
+        once: bool,
+    },
+    /// Sends the room temperature and setpoint of a zone as its satellite.
+    EmulateSatellite(SatelliteArgs),
+    /// Decodes frames given in hex, one per line with an optional timestamp prefix.
+    Decode {
+        /// Frames in hex, `-` reading them from stdin, as when there are none.
+        frames: Vec<String>,
+        /// File of frames.
+        #[arg(short, long)]
+        file: Option<String>,
+        #[arg(long, value_enum, default_value_t)]
+        format: decode::Format,
+    },
+    /// Prints the hex of a frame sent by an emulated device.
+    Encode {
+        #[command(subcommand)]
+        frame: EncodeCommand,
+    },
+    /// Plays back a capture file, printing its frames.
+    Replay {
+        file: String,
+        /// Waits between frames as long as they were apart in the capture.
+        #[arg(long)]
+        original_timing: bool,
+    },
+    /// Serves a simulated boiler behind an emulated gateway.
+    SimulateBoiler {
+        /// Connects the gateway to the configured MQTT broker instead of a pseudo-terminal.
+        #[arg(long)]
+        mqtt: bool,
+        /// Broadcasts the network id as when pairing is started on the boiler.
+        #[arg(long)]
+        pairing: bool,
+    },
+    /// Queries the history database.
+    History {
+        #[command(subcommand)]
+        query: HistoryCommand,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq, Subcommand)]
+pub enum HistoryCommand {
+    /// Prints the temperatures, oldest first.
+    Temperatures {
+        /// Device name, e.g. boiler, sonde or zone1.
+        #[arg(long)]
+        device: Option<String>,
+        /// Zone, matching its satellite's frames and the boiler's answers to it.
+        #[arg(long)]
+        zone: Option<u8>,
+        #[arg(long, default_value_t = 7)]
+        days: u32,
+    },
+    /// Prints the latest frames, oldest first.
+    Frames {
+        /// Sender address, in decimal or 0x-prefixed hex.
+        #[arg(long, value_parser = history::parse_addr)]
+        from: Option<u8>,
+        /// Only the frames which didn't decode to a known message.
+        #[arg(long)]
+        unknown: bool,
+        #[arg(long, default_value_t = 7)]
+        days: u32,
+        #[arg(long, default_value_t = 100)]
+        limit: u32,
+    },
+}
+
+#[derive(Debug, Subcommand)]
+pub enum EncodeCommand {
+    Sonde {
+        #[arg(long, allow_negative_numbers = true)]
+        temperature: f64,
+        #[arg(long, default_value_t = 0x1a04)]
+        request_id: u16,
+    },
+    Satellite {
+        #[command(flatten)]
+        satellite: SatelliteArgs,
+        #[arg(long, default_value_t = 0x1c04)]
+        request_id: u16,
+    },
+}
+
+#[derive(Debug, Clone, Args)]
+pub struct SatelliteArgs {
+    #[arg(long, value_parser = clap::value_parser!(u8).range(1..=3))]
+    pub zone: u8,
+    /// Room temperature in °C.
+    #[arg(long)]
+    pub temperature: f64,
+    /// Setpoint in °C.
+    #[arg(long)]
+    pub consigne: f64,
+    /// confort, reduit or hors_gel.
+    #[arg(long, default_value = "confort")]
+    pub mode: ZoneMode,
+    #[arg(long)]
+    pub derogation: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, ValueEnum)]
+pub enum LogLevel {
+    /// Only the errors.
+    Error,
+    /// The decoded frames and the status messages.
+    Info,
+    /// The raw frames and the ignored input too.
+    Debug,
+}
+
+static LOG_LEVEL: AtomicU8 = AtomicU8::new(LogLevel::Info as u8);
+
+pub fn set_log_level(level: LogLevel) {
+    LOG_LEVEL.store(level as u8, Ordering::Relaxed);
+}
+
+/// Whether messages of this level are printed.
+pub fn enabled(level: LogLevel) -> bool {
+    level as u8 <= LOG_LEVEL.load(Ordering::Relaxed)
+}
+
+/// Prints a message when its level is enabled, e.g. `log!(Debug, "Raw: {hex}")`.
+/// Errors go to stderr.
+macro_rules! log {
+    (Error, $($arg:tt)*) => {
+        if $crate::cli::enabled($crate::cli::LogLevel::Error) {
+            eprintln!($($arg)*);
+        }
+    };
+    ($level:ident, $($arg:tt)*) => {
+        if $crate::cli::enabled($crate::cli::LogLevel::$level) {
+            println!($($arg)*);
+        }
+    };
+}
+pub(crate) use log;
+
+fn parse_network_id(value: &str) -> Result<String, String> {
+    match hex::decode(value) {
+        Ok(bytes) if bytes.len() == 4 => Ok(value.to_lowercase()),
+        _ => Err("4 bytes in hex expected, e.g. 05da2ee2".to_string()),
+    }
+}
+
+impl Cli {
+    /// Applies the global options to the settings.
+    pub fn apply(&self, settings: &mut HashMap<String, String>) {
+        if let Some(transport) = &self.transport {
+            settings.insert("transport".to_string(), transport.clone());
+        }
+        if let Some(network_id) = &self.network_id {
+            settings.insert("network_id".to_string(), network_id.clone());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::CommandFactory;
+
+    #[test]
+    fn test_definition() {
+        Cli::command().debug_assert();
+    }
+
+    #[test]
+    fn test_global_options() {
+        let cli = Cli::try_parse_from([
+            "frisquet-commander",
+            "emulate-satellite",
+            "--zone",
+            "2",
+            "--temperature",
+            "19.5",
+            "--consigne",
+            "20",
+            "--mode",
+            "reduit",
+            "-n",
+            "05DA2EE2",
+            "--transport",
+            "serial",
+        ])
+        .unwrap();
+        let mut settings = HashMap::new();
+        cli.apply(&mut settings);
+        assert_eq!(settings["network_id"], "05da2ee2");
+        assert_eq!(settings["transport"], "serial");
+        let Some(Command::EmulateSatellite(satellite)) = cli.command else {
+            panic!("{:?}", cli.command);
+        };
+        assert_eq!((satellite.zone, satellite.mode), (2, ZoneMode::Reduit));
+
+        assert!(Cli::try_parse_from(["frisquet-commander", "-n", "05da", "sniff"]).is_err());
+        let cli = Cli::try_parse_from([
+            "frisquet-commander",
+            "history",
+            "frames",
+            "--from",
+            "0x80",
+            "--unknown",
+        ])
+        .unwrap();
+        assert_eq!(
+            cli.command.map(|command| match command {
+                Command::History { query } => query,
+                command => panic!("{command:?}"),
+            }),
+            Some(HistoryCommand::Frames {
+                from: Some(0x80),
+                unknown: true,
+                days: 7,
+                limit: 100,
+            })
+        );
+        assert!(Cli::try_parse_from([
+            "frisquet-commander",
+            "history",
+            "frames",
+            "--from",
+            "boiler"
+        ])
+        .is_err());
+        assert!(
+            Cli::try_parse_from(["frisquet-commander", "emulate-satellite", "--zone", "4"])
+                .is_err()
+        );
+    }
+}
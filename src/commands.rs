@@ -0,0 +1,430 @@
+//! MQTT command topics changing the zones' setpoint and mode, sent from an emulated satellite.
+
+extern crate paho_mqtt as mqtt;
+
+use std::collections::HashMap;
+
+use mqtt::{Message, Receiver};
+use serde::Serialize;
+use serde_json::json;
+
+use crate::cli::log;
+use crate::frisquet;
+use crate::frisquet::proto::satellite::SatellitePayload;
+use crate::frisquet::proto::sonde::SondePayload;
+use crate::rf::mqtt::MqttSettings;
+use crate::rf::request::{self, RequestError, RequestOptions};
+use crate::rf::{RFClient, ReceivedFrame};
+use crate::settings::parse_setting;
+use crate::state::{satellite_addr, HeatingState, ZoneMode, ZoneState, BOILER_ADDR, SONDE_ADDR};
+
+const MSG_TYPE_TEMPERATURE: u8 = 23;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ZoneCommand {
+    /// Target temperature in °C.
+    Consigne(f64),
+    Mode(ZoneMode),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Command {
+    pub zone: u8,
+    pub command: ZoneCommand,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Outcome {
+    Accepted,
+    Sent,
+    /// The boiler answered the satellite frame.
+    Acknowledged,
+    Failed,
+}
+
+/// Safe setpoints, from the `min_consigne` and `max_consigne` settings.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ConsigneRange {
+    pub min: f64,
+    pub max: f64,
+}
+
+impl ConsigneRange {
+    pub fn from_settings(settings: &HashMap<String, String>) -> Result<ConsigneRange, String> {
+        let range = ConsigneRange {
+            min: parse_setting(settings, "min_consigne")?.unwrap_or(5.0),
+            max: parse_setting(settings, "max_consigne")?.unwrap_or(28.0),
+        };
+        if range.min > range.max {
+            return Err(format!(
+                "invalid consigne range: {} > {}",
+                range.min, range.max
+            ));
+        }
+        Ok(range)
+    }
+
+    /// Checks a setpoint, rounded to the tenth of degree sent to the boiler.
+    pub fn validate(&self, consigne: f64) -> Result<f64, String> {
+        if !(self.min..=self.max).contains(&consigne) {
+            return Err(format!(
+                "consigne {consigne} out of range {}..{}",
+                self.min, self.max
+            ));
+        }
+        Ok((consigne * 10.0).round() / 10.0)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct CommandSettings {
+    pub prefix: String,
+    pub consigne_range: ConsigneRange,
+}
+
+impl CommandSettings {
+    /// Reads the `mqtt_commands_*` settings, commands being enabled by `mqtt_commands`.
+    pub fn from_settings(
+        settings: &HashMap<String, String>,
+    ) -> Result<Option<CommandSettings>, String> {
+        if settings.get("mqtt_commands").map(|v| v.as_str()) != Some("true") {
+            return Ok(None);
+        }
+        Ok(Some(CommandSettings {
+            prefix: settings
+                .get("mqtt_commands_prefix")
+                .cloned()
+                .unwrap_or_else(|| "frisquet".to_string()),
+            consigne_range: ConsigneRange::from_settings(settings)?,
+        }))
+    }
+
+    fn subscription(&self) -> String {
+        format!("{}/+/set/+", self.prefix)
+    }
+
+    fn result_topic(&self, zone: &str) -> String {
+        format!("{}/{zone}/result", self.prefix)
+    }
+}
+
+/// Parses and validates a command from its topic, e.g. `frisquet/zone1/set/consigne`.
+pub fn parse_command(
+    settings: &CommandSettings,
+    topic: &str,
+    payload: &str,
+) -> Result<Command, String> {
+    let path = topic
+        .strip_prefix(&settings.prefix)
+        .and_then(|path| path.strip_prefix('/'))
+        .ok_or_else(|| format!("unexpected topic {topic}"))?;
+    let (zone, setting) = match path.split('/').collect::<Vec<_>>()[..] {
+        [zone, "set", setting] => (zone, setting),
+        _ => return Err(format!("unexpected topic {topic}")),
+    };
+    let zone = zone
+        .strip_prefix("zone")
+        .and_then(|zone| zone.parse().ok())
+        .filter(|zone| satellite_addr(*zone).is_some())
+        .ok_or_else(|| format!("unknown zone {zone}"))?;
+
+    let payload = payload.trim();
+    let command = match setting {
+        "consigne" => {
+            let consigne: f64 = payload
+                .parse()
+                .map_err(|_| format!("invalid consigne {payload}"))?;
+            ZoneCommand::Consigne(settings.consigne_range.validate(consigne)?)
+        }
+        "mode" => ZoneCommand::Mode(payload.parse()?),
+        _ => return Err(format!("unknown setting {setting}")),
+    };
+    Ok(Command { zone, command })
+}
+
+/// Builds the frame the satellite of the zone sends to apply the command.
+///
+/// The satellite reports the room temperature along with the setpoint,
+/// so the zone must have been heard before.
+pub fn satellite_frame(
+    command: &Command,
+    zone: &ZoneState,
+    request_id: u16,
+) -> Result<Vec<u8>, String> {
+    let temperature = zone
+        .temperature
+        .ok_or_else(|| format!("room temperature of zone {} unknown", command.zone))?;
+    let mut consigne = zone.consigne.unwrap_or(20.0);
+    let mut mode = zone.mode.unwrap_or(ZoneMode::Confort);
+    match command.command {
+        ZoneCommand::Consigne(value) => consigne = value,
+        ZoneCommand::Mode(value) => mode = value,
+    }
+    setpoint_frame(
+        command.zone,
+        temperature,
+        consigne,
+        mode,
+        zone.derogation,
+        request_id,
+    )
+}
+
+/// Builds the frame a satellite sends to report the room temperature along with its setpoint.
+pub fn setpoint_frame(
+    zone: u8,
+    temperature: f64,
+    consigne: f64,
+    mode: ZoneMode,
+    derogation: bool,
+    request_id: u16,
+) -> Result<Vec<u8>, String> {
+    let addr = satellite_addr(zone).ok_or("unknown zone")?;
+    // The constant parts are the ones sent by the satellites seen so far.
+    let message = SatellitePayload::SatelliteSetTemperatureMessage {
+        static_part: [160, 41, 0],
+        unknown1: 21,
+        static_part_end: [160, 47, 0],
+        unknown2: 4,
+        message_static_part: [addr, 0],
+        temperature: (temperature * 10.0).round() as i16,
+        consigne: (consigne * 10.0).round() as i16,
+        unknown_mode1: 1,
+        hors_gel: mode == ZoneMode::HorsGel,
+        unknown_mode2: 1,
+        derogation,
+        soleil: mode == ZoneMode::Confort,
+        signature: [0, 198],
+    };
+    frisquet::encode_data(
+        addr,
+        BOILER_ADDR,
+        request_id,
+        1,
+        MSG_TYPE_TEMPERATURE,
+        &message,
+    )
+    .map(|(_, data)| data)
+    .map_err(|e| e.to_string())
+}
+
+/// Builds the frame the sonde sends to report the outdoor temperature.
+pub fn sonde_frame(temperature: f64, request_id: u16) -> Result<Vec<u8>, String> {
+    if !(-40.0..=50.0).contains(&temperature) {
+        return Err(format!("outdoor temperature {temperature} out of range"));
+    }
+    let message = SondePayload::SondeTemperatureMessage {
+        data: [156, 84, 0, 4, 160, 41, 0, 1, 2],
+        temperature: (temperature * 10.0).round() as i16,
+    };
+    frisquet::encode_data(
+        SONDE_ADDR,
+        BOILER_ADDR,
+        request_id,
+        1,
+        MSG_TYPE_TEMPERATURE,
+        &message,
+    )
+    .map(|(_, data)| data)
+    .map_err(|e| e.to_string())
+}
+
+/// Sends a command from the zone's satellite and waits for the boiler's answer,
+/// calling `sent` once the frame went out.
+pub fn send_zone_command(
+    client: &mut dyn RFClient,
+    state: &HeatingState,
+    command: &Command,
+    request_id: u16,
+    options: &RequestOptions,
+    sent: &mut dyn FnMut(),
+) -> Result<ReceivedFrame, RequestError> {
+    let zone_state = state.zones.get(&command.zone).cloned().unwrap_or_default();
+    let frame =
+        satellite_frame(command, &zone_state, request_id).map_err(RequestError::InvalidRequest)?;
+    request::request_with(client, &frame, options, sent).map(|(_, answer)| answer)
+}
+
+/// Listens to the command topics and publishes the outcome of each command.
+pub struct CommandInterface {
+    client: mqtt::Client,
+    rx: Receiver<Option<Message>>,
+    settings: CommandSettings,
+    mqtt_settings: MqttSettings,
+    request_options: RequestOptions,
+    request_id: u16,
+}
+
+/// Connects to the broker when commands are enabled.
+pub fn new(settings: &HashMap<String, String>) -> Result<Option<CommandInterface>, String> {
+    let Some(command_settings) = CommandSettings::from_settings(settings)? else {
+        return Ok(None);
+    };
+    let mqtt_settings = MqttSettings::from_settings(settings)?;
+    let mqtt_settings =
+        mqtt_settings.with_client_id(format!("{}-commands", mqtt_settings.client_id));
+    let (client, rx) = crate::rf::mqtt::connect(&mqtt_settings)?;
+    client
+        .subscribe(&command_settings.subscription(), mqtt_settings.qos)
+        .map_err(|e| format!("Error subscribing to commands: {e}"))?;
+    Ok(Some(CommandInterface {
+        client,
+        rx,
+        settings: command_settings,
+        mqtt_settings,
+        request_options: RequestOptions::from_settings(settings)?,
+        request_id: 0x1900,
+    }))
+}
+
+impl CommandInterface {
+    fn publish_outcome(&self, zone: &str, value: &str, outcome: Outcome, error: Option<&str>) {
+        let payload = json!({
+            "value": value,
+            "status": outcome,
+            "error": error,
+        });
+        let message = Message::new(
+            self.settings.result_topic(zone),
+            payload.to_string(),
+            self.mqtt_settings.qos,
+        );
+        if let Err(e) = self.client.publish(message) {
+            log!(Error, "Error publishing command outcome: {e}");
+        }
+    }
+
+    /// Returns the valid commands received since the last call.
+    pub fn poll(&mut self) -> Vec<Command> {
+        let mut commands = vec![];
+        while let Ok(received) = self.rx.try_recv() {
+            let Some(msg) = received else {
+                crate::rf::mqtt::reconnect(&self.client, &self.mqtt_settings);
+                if let Err(e) = self
+                    .client
+                    .subscribe(&self.settings.subscription(), self.mqtt_settings.qos)
+                {
+                    log!(Error, "Error subscribing to commands: {e}");
+                }
+                continue;
+            };
+            let payload = msg.payload_str();
+            match parse_command(&self.settings, msg.topic(), &payload) {
+                Ok(command) => {
+                    self.publish_outcome(
+                        &format!("zone{}", command.zone),
+                        &payload,
+                        Outcome::Accepted,
+                        None,
+                    );
+                    commands.push(command);
+                }
+                Err(e) => {
+                    log!(Error, "Refusing command {}: {e}", msg.topic());
+                    let zone = msg.topic().split('/').rev().nth(2).unwrap_or_default();
+                    self.publish_outcome(zone, &payload, Outcome::Failed, Some(&e));
+                }
+            }
+        }
+        commands
+    }
+
+    /// Sends a command from the zone's satellite, returning the boiler's answer.
+    pub fn execute(
+        &mut self,
+        client: &mut dyn RFClient,
+        state: &HeatingState,
+        command: &Command,
+    ) -> Option<ReceivedFrame> {
+        let zone = format!("zone{}", command.zone);
+        let value = match command.command {
+            ZoneCommand::Consigne(consigne) => consigne.to_string(),
+            ZoneCommand::Mode(mode) => json!(mode).as_str().unwrap_or_default().to_string(),
+        };
+        self.request_id = self.request_id.wrapping_add(4);
+        match send_zone_command(
+            client,
+            state,
+            command,
+            self.request_id,
+            &self.request_options,
+            &mut || self.publish_outcome(&zone, &value, Outcome::Sent, None),
+        ) {
+            Ok(answer) => {
+                self.publish_outcome(&zone, &value, Outcome::Acknowledged, None);
+                Some(answer)
+            }
+            Err(e) => {
+                self.publish_outcome(&zone, &value, Outcome::Failed, Some(&e.to_string()));
+                None
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::frisquet::proto::FrisquetData;
+
+    fn settings() -> CommandSettings {
+        CommandSettings::from_settings(&HashMap::from([(
+            "mqtt_commands".to_string(),
+            "true".to_string(),
+        )]))
+        .unwrap()
+        .unwrap()
+    }
+
+    #[test]
+    fn test_parse_command() {
+        let settings = settings();
+        assert_eq!(
+            parse_command(&settings, "frisquet/zone2/set/consigne", "19.55"),
+            Ok(Command {
+                zone: 2,
+                command: ZoneCommand::Consigne(19.6)
+            })
+        );
+        assert_eq!(
+            parse_command(&settings, "frisquet/zone1/set/mode", "hors_gel"),
+            Ok(Command {
+                zone: 1,
+                command: ZoneCommand::Mode(ZoneMode::HorsGel)
+            })
+        );
+        assert!(parse_command(&settings, "frisquet/zone1/set/consigne", "35").is_err());
+        assert!(parse_command(&settings, "frisquet/zone4/set/consigne", "19").is_err());
+        assert!(parse_command(&settings, "frisquet/zone1/set/mode", "turbo").is_err());
+        assert!(parse_command(&settings, "other/zone1/set/mode", "reduit").is_err());
+    }
+
+    #[test]
+    fn test_satellite_frame() {
+        let command = Command {
+            zone: 1,
+            command: ZoneCommand::Consigne(21.5),
+        };
+        assert!(satellite_frame(&command, &ZoneState::default(), 0x19e4).is_err());
+
+        let zone = ZoneState {
+            temperature: Some(17.8),
+            consigne: Some(17.0),
+            mode: Some(ZoneMode::Reduit),
+            ..ZoneState::default()
+        };
+        let frame = satellite_frame(&command, &zone, 0x19e4).unwrap();
+        let (metadata, data) = frisquet::parse_data_from_str(&hex::encode(frame)).unwrap();
+        assert_eq!((metadata.from_addr, metadata.to_addr), (0x08, 0x80));
+        match data {
+            FrisquetData::Satellite(SatellitePayload::SatelliteSetTemperatureMessage {
+                temperature,
+                consigne,
+                soleil,
+                ..
+            }) => assert_eq!((temperature, consigne, soleil), (178, 215, false)),
+            data => panic!("unexpected {data:?}"),
+        }
+    }
+}
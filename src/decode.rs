@@ -0,0 +1,401 @@
+//! Offline decoding of frames pasted from logs, captures or forums.
+
+use std::io::BufRead;
+
+use clap::ValueEnum;
+use serde_json::{json, Value};
+
+use crate::frisquet;
+use crate::frisquet::proto::{FrisquetData, FrisquetMetadata};
+use crate::state::device_label;
+
+/// Size of the metadata heading each frame.
+const HEADER_LENGTH: usize = 7;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, ValueEnum)]
+pub enum Format {
+    /// One line per frame.
+    #[default]
+    Human,
+    /// One JSON object per frame.
+    Json,
+    /// Each field with its bytes.
+    Dissector,
+}
+
+/// A frame found on an input line.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Line {
+    pub timestamp: Option<String>,
+    pub hex: String,
+}
+
+/// Splits a line into its optional timestamp and the frame, e.g.
+/// `2024-01-05 12:00:01 118020...`, or the bytes of the frame spaced out.
+/// Blank lines and `#` comments are skipped.
+pub fn parse_line(line: &str) -> Option<Line> {
+    let line = line.trim();
+    if line.is_empty() || line.starts_with('#') {
+        return None;
+    }
+    let tokens: Vec<&str> = line.split_whitespace().collect();
+    if tokens
+        .iter()
+        .all(|token| token.len() == 2 && token.chars().all(|c| c.is_ascii_hexdigit()))
+    {
+        return Some(Line {
+            timestamp: None,
+            hex: tokens.concat().to_lowercase(),
+        });
+    }
+    let (frame, prefix) = tokens.split_last()?;
+    let timestamp = prefix.join(" ");
+    let timestamp = timestamp.trim_matches(|c| matches!(c, '[' | ']' | ':' | ' '));
+    Some(Line {
+        timestamp: (!timestamp.is_empty()).then(|| timestamp.to_string()),
+        hex: frame.trim_start_matches("0x").to_lowercase(),
+    })
+}
+
+/// Decodes a frame, rejecting what isn't hex or is shorter than its header.
+pub fn decode(hex: &str) -> Result<(FrisquetMetadata, FrisquetData), String> {
+    let bytes = hex::decode(hex).map_err(|e| format!("invalid hex: {e}"))?;
+    if bytes.len() < HEADER_LENGTH {
+        return Err(format!("{} bytes, shorter than the header", bytes.len()));
+    }
+    frisquet::parse_data_from_str(hex).map_err(|e| e.to_string())
+}
+
+fn direction(req_or_answer: u8) -> &'static str {
+    if req_or_answer & 0x80 != 0 {
+        "answer"
+    } else {
+        "request"
+    }
+}
+
+/// Renders a decoded frame, or the error when it doesn't decode.
+pub fn render(
+    format: Format,
+    line: &Line,
+    decoded: &Result<(FrisquetMetadata, FrisquetData), String>,
+) -> String {
+    match format {
+        Format::Human => human(line, decoded),
+        Format::Json => json(line, decoded).to_string(),
+        Format::Dissector => dissector(line, decoded),
+    }
+}
+
+fn human(line: &Line, decoded: &Result<(FrisquetMetadata, FrisquetData), String>) -> String {
+    let timestamp = line
+        .timestamp
+        .as_ref()
+        .map_or_else(String::new, |timestamp| format!("[{timestamp}] "));
+    match decoded {
+        Ok((metadata, data)) => format!(
+            "{timestamp}{} -> {} {} {:#06x} type {}: {data:?}",
+            device_label(metadata.from_addr),
+            device_label(metadata.to_addr),
+            direction(metadata.req_or_answer),
+            metadata.request_id,
+            metadata.msg_type,
+        ),
+        Err(e) => format!("{timestamp}{}: {e}", line.hex),
+    }
+}
+
+fn json(line: &Line, decoded: &Result<(FrisquetMetadata, FrisquetData), String>) -> Value {
+    match decoded {
+        Ok((metadata, data)) => {
+            let (variant, fields) = data.variant().unwrap_or((String::new(), Value::Null));
+            json!({
+                "timestamp": line.timestamp,
+                "hex": line.hex,
+                "metadata": metadata,
+                "from": device_label(metadata.from_addr),
+                "to": device_label(metadata.to_addr),
+                "variant": variant,
+                "unknown": data.is_unknown(),
+                "data": fields,
+            })
+        }
+        Err(e) => json!({"timestamp": line.timestamp, "hex": line.hex, "error": e}),
+    }
+}
+
+/// Fields of a payload variant in order, with their size in bytes, `None` taking
+/// the bytes left. Bit fields sharing a byte are listed together.
+type Layout = &'static [(&'static [&'static str], Option<usize>)];
+
+const LAYOUTS: &[(&str, Layout)] = &[
+    (
+        "SondeTemperatureMessage",
+        &[(&["data"], Some(9)), (&["temperature"], Some(2))],
+    ),
+    ("SondeAssociationAnnounceMessage", &[]),
+    ("SondeInitMessage", &[(&["data"], Some(2))]),
+    (
+        "SatelliteInitMessage",
+        &[(&["static_part"], Some(7)), (&["message_part"], Some(3))],
+    ),
+    (
+        "SatelliteAssocationAnnounceMessage",
+        &[(&["unknown"], Some(1)), (&["version"], Some(3))],
+    ),
+    (
+        "SatelliteSetTemperatureMessage",
+        &[
+            (&["static_part"], Some(3)),
+            (&["unknown1"], Some(1)),
+            (&["static_part_end"], Some(3)),
+            (&["unknown2"], Some(1)),
+            (&["message_static_part"], Some(2)),
+            (&["temperature"], Some(2)),
+            (&["consigne"], Some(2)),
+            (
+                &[
+                    "unknown_mode1",
+                    "hors_gel",
+                    "unknown_mode2",
+                    "derogation",
+                    "soleil",
+                ],
+                Some(1),
+            ),
+            (&["signature"], Some(2)),
+        ],
+    ),
+    (
+        "ChaudiereAssociationBroadcast",
+        &[(&["unknown"], Some(1)), (&["network_id"], Some(4))],
+    ),
+    (
+        "ChaudiereSondeResponseMessage",
+        &[
+            (&["unknown_start"], Some(1)),
+            (&["year"], Some(1)),
+            (&["month"], Some(1)),
+            (&["day"], Some(1)),
+            (&["hour"], Some(1)),
+            (&["minute"], Some(1)),
+            (&["second"], Some(1)),
+            (&["data"], None),
+        ],
+    ),
+    (
+        "ChaudiereSetTemperatureMessageResponse",
+        &[
+            (&["unknown_start"], Some(2)),
+            (&["temperature_exterieure"], Some(2)),
+            (&["unknown"], Some(1)),
+            (&["year"], Some(1)),
+            (&["month"], Some(1)),
+            (&["day"], Some(1)),
+            (&["hour"], Some(1)),
+            (&["minute"], Some(1)),
+            (&["second"], Some(1)),
+            (&["unknown_1"], Some(3)),
+            (&["temperature"], Some(2)),
+            (&["consigne"], Some(2)),
+            (&["unknown_2"], Some(2)),
+            (&["signature"], Some(3)),
+            (&["static_part_2"], Some(20)),
+        ],
+    ),
+];
+
+/// The catch-all variants only carry their bytes.
+const UNKNOWN_LAYOUT: Layout = &[(&["data"], None)];
+
+fn offsets(first: usize, last: usize) -> String {
+    if first == last {
+        format!("{first:02}")
+    } else {
+        format!("{first:02}-{last:02}")
+    }
+}
+
+fn dissector(line: &Line, decoded: &Result<(FrisquetMetadata, FrisquetData), String>) -> String {
+    let mut out = match &line.timestamp {
+        Some(timestamp) => format!("{timestamp} {}\n", line.hex),
+        None => format!("{}\n", line.hex),
+    };
+    let (metadata, data) = match decoded {
+        Ok(decoded) => decoded,
+        Err(e) => return out + &format!("  error: {e}\n"),
+    };
+    let bytes = |first: usize, last: usize| &line.hex[first * 2..(last + 1) * 2];
+    let field = |first: usize, last: usize, description: String| {
+        format!(
+            "  {:<6} {:<8} {description}\n",
+            offsets(first, last),
+            bytes(first, last)
+        )
+    };
+    out += &field(0, 0, format!("length {}", metadata.length));
+    out += &field(1, 1, format!("to {}", device_label(metadata.to_addr)));
+    out += &field(2, 2, format!("from {}", device_label(metadata.from_addr)));
+    out += &field(3, 4, format!("request_id {:#06x}", metadata.request_id));
+    out += &field(
+        5,
+        5,
+        format!("req_or_answer {}", direction(metadata.req_or_answer)),
+    );
+    out += &field(6, 6, format!("msg_type {}", metadata.msg_type));
+
+    let (variant, values) = data.variant().unwrap_or((String::new(), Value::Null));
+    let end = line.hex.len() / 2;
+    out += &format!("  payload {variant}\n");
+    let layout = LAYOUTS
+        .iter()
+        .find(|(name, _)| *name == variant)
+        .map_or(UNKNOWN_LAYOUT, |(_, layout)| layout);
+    let mut offset = HEADER_LENGTH;
+    for (names, size) in layout {
+        let next = size.map_or(end, |size| (offset + size).min(end));
+        let description = names
+            .iter()
+            .map(|name| format!("{name}: {}", values.get(name).unwrap_or(&Value::Null)))
+            .collect::<Vec<_>>()
+            .join(", ");
+        if next > offset {
+            out += &field(offset, next - 1, description);
+        } else {
+            out += &format!("  {:<6} {:<8} {description}\n", "", "");
+        }
+        offset = next;
+    }
+    if offset < end {
+        out += &field(offset, end - 1, "trailing".to_string());
+    }
+    out
+}
+
+fn read_lines(reader: &mut dyn BufRead, name: &str) -> Result<Vec<Line>, String> {
+    let mut lines = vec![];
+    for line in reader.lines() {
+        let line = line.map_err(|e| format!("Failed to read {name}: {e}"))?;
+        lines.extend(parse_line(&line));
+    }
+    Ok(lines)
+}
+
+/// Decodes the frames of the arguments, `-` standing for stdin, and of a file.
+///
+/// Reads stdin when there's neither. Fails when any frame doesn't decode.
+pub fn command(frames: &[String], file: Option<&str>, format: Format) -> Result<(), String> {
+    let mut lines: Vec<Line> = vec![];
+    if let Some(path) = file {
+        let file = std::fs::File::open(path).map_err(|e| format!("Failed to open {path}: {e}"))?;
+        lines.extend(read_lines(&mut std::io::BufReader::new(file), path)?);
+    }
+    for frame in frames {
+        if frame == "-" {
+            lines.extend(read_lines(&mut std::io::stdin().lock(), "stdin")?);
+        } else {
+            lines.extend(parse_line(frame));
+        }
+    }
+    if frames.is_empty() && file.is_none() {
+        lines.extend(read_lines(&mut std::io::stdin().lock(), "stdin")?);
+    }
+
+    let mut failures = 0;
+    for line in &lines {
+        let decoded = decode(&line.hex);
+        if decoded.is_err() {
+            failures += 1;
+        }
+        println!("{}", render(format, line, &decoded));
+    }
+    match failures {
+        0 => Ok(()),
+        failures => Err(format!(
+            "{failures} of {} frame(s) failed to decode",
+            lines.len()
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SONDE: &str = "118020ba4001179c540004a029000102005c";
+
+    #[test]
+    fn test_parse_line() {
+        assert_eq!(parse_line("  # a comment"), None);
+        assert_eq!(parse_line(""), None);
+        assert_eq!(
+            parse_line(&format!("2024-01-05 12:00:01: {}", SONDE.to_uppercase())),
+            Some(Line {
+                timestamp: Some("2024-01-05 12:00:01".to_string()),
+                hex: SONDE.to_string(),
+            })
+        );
+        assert_eq!(
+            parse_line("[1680709763.0] 0x0b0080d3c802410405d7199e").map(|line| line.timestamp),
+            Some(Some("1680709763.0".to_string()))
+        );
+        assert_eq!(
+            parse_line("11 80 20 ba 40 01 17").map(|line| line.hex),
+            Some("118020ba400117".to_string())
+        );
+    }
+
+    #[test]
+    fn test_render() {
+        let line = parse_line(&format!("12:00:01 {SONDE}")).unwrap();
+        let decoded = decode(&line.hex);
+        assert!(render(Format::Human, &line, &decoded)
+            .starts_with("[12:00:01] sonde -> boiler request 0xba40 type 23: Sonde("));
+
+        let json: Value = serde_json::from_str(&render(Format::Json, &line, &decoded)).unwrap();
+        assert_eq!(json["variant"], "SondeTemperatureMessage");
+        assert_eq!(json["data"]["temperature"], 92);
+        assert_eq!(json["metadata"]["request_id"], 0xba40);
+
+        let dissected = render(Format::Dissector, &line, &decoded);
+        assert!(dissected.contains("  03-04  ba40     request_id 0xba40\n"));
+        assert!(dissected.contains("  payload SondeTemperatureMessage\n"));
+        assert!(dissected.contains("  16-17  005c     temperature: 92\n"));
+    }
+
+    #[test]
+    fn test_layouts() {
+        // Each fixed layout covers the whole payload of a sample frame.
+        for frame in [
+            SONDE,
+            "17800819E40117A0290015A02F00040800B200AA002400C6",
+            "0b0080d3c802410405d7199e",
+            "310880194881172A050A0000230423171012000000C000BE002500C600C604F6000000000000000004F60000000000000000",
+        ] {
+            let line = parse_line(frame).unwrap();
+            let dissected = render(Format::Dissector, &line, &decode(&line.hex));
+            assert!(!dissected.contains("trailing"), "{dissected}");
+            assert!(!dissected.contains(": null"), "{dissected}");
+        }
+        let line = parse_line("17800819E40117A0290015A02F00040800B200AA002400C6").unwrap();
+        let dissected = render(Format::Dissector, &line, &decode(&line.hex));
+        assert!(dissected.contains("  17-18  b200     temperature: 178\n"));
+        assert!(dissected.contains(
+            "  21     24       unknown_mode1: 1, hors_gel: false, unknown_mode2: 1, derogation: false, soleil: false\n"
+        ));
+
+        let line = parse_line("0b0080d3c802410405d7199e").unwrap();
+        let dissected = render(Format::Dissector, &line, &decode(&line.hex));
+        assert!(dissected.contains("  08-11  05d7199e network_id: [5,215,25,158]\n"));
+    }
+
+    #[test]
+    fn test_errors() {
+        assert!(decode("zz").unwrap_err().starts_with("invalid hex"));
+        assert!(decode("118020").is_err());
+        let line = parse_line("1180").unwrap();
+        let json: Value =
+            serde_json::from_str(&render(Format::Json, &line, &decode(&line.hex))).unwrap();
+        assert_eq!(json["error"], "2 bytes, shorter than the header");
+    }
+}
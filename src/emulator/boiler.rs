@@ -0,0 +1,298 @@
+use std::collections::{BTreeMap, BTreeSet};
+
+use chrono::{DateTime, Datelike, Local, Timelike};
+
+use crate::frisquet;
+use crate::frisquet::proto::chaudiere::ChaudierePayload;
+use crate::frisquet::proto::satellite::SatellitePayload;
+use crate::frisquet::proto::sonde::SondePayload;
+use crate::frisquet::proto::FrisquetData;
+
+pub const BOILER_ADDR: u8 = 0x80;
+/// Network id used by the boiler to broadcast its network id while pairing.
+pub const PAIRING_NETWORK_ID: [u8; 4] = [0xff, 0xff, 0xff, 0xff];
+
+const MSG_TYPE_TEMPERATURE: u8 = 23;
+const MSG_TYPE_ASSOCIATION: u8 = 65;
+const MSG_TYPE_INIT: u8 = 67;
+const ANSWER: u8 = 0x80;
+
+/// A frame transmitted on the air, with the network id used as sync word.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Transmission {
+    pub network_id: Vec<u8>,
+    pub data: Vec<u8>,
+}
+
+/// Last values reported by a satellite.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct Zone {
+    pub temperature: i16,
+    pub consigne: i16,
+}
+
+/// Boiler answering the sonde and the satellites like a real one would.
+#[derive(Debug)]
+pub struct SimulatedBoiler {
+    pub network_id: [u8; 4],
+    /// Outdoor temperature reported by the sonde, * 10.
+    pub outdoor_temperature: Option<i16>,
+    pub zones: BTreeMap<u8, Zone>,
+    pub paired: BTreeSet<u8>,
+    pairing: bool,
+    request_id: u16,
+}
+
+fn bcd(value: u32) -> u8 {
+    (((value / 10 % 10) << 4) | (value % 10)) as u8
+}
+
+impl SimulatedBoiler {
+    pub fn new(network_id: [u8; 4]) -> SimulatedBoiler {
+        SimulatedBoiler {
+            network_id,
+            outdoor_temperature: None,
+            zones: BTreeMap::new(),
+            paired: BTreeSet::new(),
+            pairing: false,
+            request_id: 0xd3c8,
+        }
+    }
+
+    /// Starts pairing, returning the broadcast of the network id.
+    pub fn start_pairing(&mut self) -> Transmission {
+        self.pairing = true;
+        self.request_id = self.request_id.wrapping_add(4);
+        let (_, data) = frisquet::encode_data(
+            BOILER_ADDR,
+            0,
+            self.request_id,
+            2,
+            MSG_TYPE_ASSOCIATION,
+            &ChaudierePayload::ChaudiereAssociationBroadcast {
+                unknown: 4,
+                network_id: self.network_id,
+            },
+        )
+        .unwrap();
+        Transmission {
+            network_id: PAIRING_NETWORK_ID.to_vec(),
+            data,
+        }
+    }
+
+    fn answer(
+        &self,
+        to: u8,
+        request: &frisquet::proto::FrisquetMetadata,
+        message: ChaudierePayload,
+    ) -> Transmission {
+        let (_, data) = frisquet::encode_data(
+            BOILER_ADDR,
+            to,
+            request.request_id,
+            request.req_or_answer | ANSWER,
+            request.msg_type,
+            &message,
+        )
+        .unwrap();
+        Transmission {
+            network_id: self.network_id.to_vec(),
+            data,
+        }
+    }
+
+    /// Handles a frame heard on `network_id`, returning the answers to transmit.
+    pub fn handle(&mut self, network_id: &[u8], frame: &[u8]) -> Vec<Transmission> {
+        let pairing = self.pairing && network_id == PAIRING_NETWORK_ID;
+        if network_id != self.network_id && !pairing {
+            return vec![];
+        }
+        let Ok((metadata, data)) = frisquet::parse_data_from_str(&hex::encode(frame)) else {
+            return vec![];
+        };
+        if metadata.to_addr != BOILER_ADDR || metadata.req_or_answer & ANSWER != 0 && !pairing {
+            return vec![];
+        }
+        let now = Local::now();
+
+        match data {
+            FrisquetData::Sonde(SondePayload::SondeAssociationAnnounceMessage { .. })
+            | FrisquetData::Satellite(SatellitePayload::SatelliteAssocationAnnounceMessage {
+                ..
+            }) if pairing => {
+                self.paired.insert(metadata.from_addr);
+                self.pairing = false;
+                vec![]
+            }
+            FrisquetData::Sonde(SondePayload::SondeInitMessage { .. })
+                if metadata.msg_type == MSG_TYPE_INIT =>
+            {
+                vec![self.answer(
+                    metadata.from_addr,
+                    &metadata,
+                    ChaudierePayload::ChaudiereUnknownMessage { data: vec![] },
+                )]
+            }
+            FrisquetData::Sonde(SondePayload::SondeTemperatureMessage { temperature, .. }) => {
+                self.outdoor_temperature = Some(temperature);
+                let (unknown_start, year, month, day, hour, minute, second) = clock(&now);
+                vec![self.answer(
+                    metadata.from_addr,
+                    &metadata,
+                    ChaudierePayload::ChaudiereSondeResponseMessage {
+                        unknown_start,
+                        year,
+                        month,
+                        day,
+                        hour,
+                        minute,
+                        second,
+                        data: vec![40, 3],
+                    },
+                )]
+            }
+            FrisquetData::Satellite(SatellitePayload::SatelliteSetTemperatureMessage {
+                temperature,
+                consigne,
+                ..
+            }) if metadata.msg_type == MSG_TYPE_TEMPERATURE => {
+                self.zones.insert(
+                    metadata.from_addr,
+                    Zone {
+                        temperature,
+                        consigne,
+                    },
+                );
+                let (_, year, month, day, hour, minute, second) = clock(&now);
+                vec![self.answer(
+                    metadata.from_addr,
+                    &metadata,
+                    ChaudierePayload::ChaudiereSetTemperatureMessageResponse {
+                        unknown_start: [42, 5],
+                        temperature_exterieure: self.outdoor_temperature.unwrap_or(0),
+                        unknown: 0,
+                        year,
+                        month,
+                        day,
+                        hour,
+                        minute,
+                        second,
+                        unknown_1: [0, 0, 0],
+                        temperature,
+                        consigne,
+                        unknown_2: [37, 0],
+                        signature: [198, 0, 198],
+                        static_part_2: [
+                            4, 246, 0, 0, 0, 0, 0, 0, 0, 0, 4, 246, 0, 0, 0, 0, 0, 0, 0, 0,
+                        ],
+                    },
+                )]
+            }
+            _ => vec![],
+        }
+    }
+}
+
+/// The boiler clock, in BCD as sent in the frames.
+fn clock(now: &DateTime<Local>) -> (u8, u8, u8, u8, u8, u8, u8) {
+    (
+        8,
+        bcd(now.year() as u32 % 100),
+        bcd(now.month()),
+        bcd(now.day()),
+        bcd(now.hour()),
+        bcd(now.minute()),
+        bcd(now.second()),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NETWORK_ID: [u8; 4] = [0x05, 0xda, 0x2e, 0xe2];
+
+    fn parse(transmission: &Transmission) -> (frisquet::proto::FrisquetMetadata, FrisquetData) {
+        frisquet::parse_data_from_str(&hex::encode(&transmission.data)).unwrap()
+    }
+
+    #[test]
+    fn test_sonde_temperature() {
+        let mut boiler = SimulatedBoiler::new(NETWORK_ID);
+        let frame = hex::decode("118020ba4001179c540004a029000102005c").unwrap();
+
+        assert!(boiler.handle(&[1, 2, 3, 4], &frame).is_empty());
+        let answers = boiler.handle(&NETWORK_ID, &frame);
+        assert_eq!(answers.len(), 1);
+        assert_eq!(answers[0].network_id, NETWORK_ID);
+        let (metadata, data) = parse(&answers[0]);
+        assert_eq!(metadata.to_addr, 32);
+        assert_eq!(metadata.request_id, 47680);
+        assert_eq!(metadata.req_or_answer, 129);
+        assert!(matches!(
+            data,
+            FrisquetData::Chaudiere(ChaudierePayload::ChaudiereSondeResponseMessage { .. })
+        ));
+        assert_eq!(boiler.outdoor_temperature, Some(92));
+    }
+
+    #[test]
+    fn test_satellite_temperature() {
+        let mut boiler = SimulatedBoiler::new(NETWORK_ID);
+        let frame = hex::decode("17800819E40117A0290015A02F00040800B200AA002400C6").unwrap();
+        let answers = boiler.handle(&NETWORK_ID, &frame);
+        let (metadata, data) = parse(&answers[0]);
+        assert_eq!(metadata.to_addr, 8);
+        assert_eq!(metadata.length, 49);
+        match data {
+            FrisquetData::Chaudiere(ChaudierePayload::ChaudiereSetTemperatureMessageResponse {
+                temperature,
+                consigne,
+                ..
+            }) => assert_eq!((temperature, consigne), (178, 170)),
+            other => panic!("unexpected answer {other:?}"),
+        }
+        assert_eq!(
+            boiler.zones.get(&8),
+            Some(&Zone {
+                temperature: 178,
+                consigne: 170
+            })
+        );
+    }
+
+    #[test]
+    fn test_pairing() {
+        let mut boiler = SimulatedBoiler::new(NETWORK_ID);
+        let broadcast = boiler.start_pairing();
+        assert_eq!(broadcast.network_id, PAIRING_NETWORK_ID);
+        let (metadata, data) = parse(&broadcast);
+        assert_eq!(
+            data,
+            FrisquetData::Chaudiere(ChaudierePayload::ChaudiereAssociationBroadcast {
+                unknown: 4,
+                network_id: NETWORK_ID
+            })
+        );
+
+        let (_, announce) = frisquet::encode_data(
+            32,
+            BOILER_ADDR,
+            metadata.request_id,
+            metadata.req_or_answer | ANSWER,
+            metadata.msg_type,
+            &SondePayload::SondeAssociationAnnounceMessage { data: vec![] },
+        )
+        .unwrap();
+        assert!(boiler.handle(&PAIRING_NETWORK_ID, &announce).is_empty());
+        assert!(boiler.paired.contains(&32));
+    }
+
+    #[test]
+    fn test_bcd() {
+        assert_eq!(bcd(23), 0x23);
+        assert_eq!(bcd(2023 % 100), 0x23);
+        assert_eq!(bcd(7), 0x07);
+    }
+}
@@ -0,0 +1,310 @@
+use std::collections::HashMap;
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+const CONNECT: u8 = 1;
+const PUBLISH: u8 = 3;
+const PUBREL: u8 = 6;
+const SUBSCRIBE: u8 = 8;
+const UNSUBSCRIBE: u8 = 10;
+const PINGREQ: u8 = 12;
+const DISCONNECT: u8 = 14;
+
+/// Minimal MQTT 3.1.1 broker standing in for a real one in tests and demos.
+///
+/// Messages are delivered at QoS 0, retained messages and last wills are supported.
+pub struct MqttBroker {
+    pub address: String,
+    state: Arc<Mutex<BrokerState>>,
+    stopped: Arc<AtomicBool>,
+}
+
+impl MqttBroker {
+    /// Stops listening and drops the clients, as a broker going down.
+    pub fn stop(&self) {
+        self.stopped.store(true, Ordering::Relaxed);
+        // Wakes the listener up.
+        let _ = TcpStream::connect(&self.address);
+        for session in self.state.lock().unwrap().sessions.values() {
+            let _ = session.stream.shutdown(Shutdown::Both);
+        }
+    }
+}
+
+#[derive(Default)]
+struct BrokerState {
+    sessions: HashMap<usize, Session>,
+    retained: HashMap<String, Vec<u8>>,
+}
+
+struct Session {
+    stream: TcpStream,
+    filters: Vec<String>,
+}
+
+/// Returns true when a topic matches a subscription filter, with `+` and `#` wildcards.
+pub fn topic_matches(filter: &str, topic: &str) -> bool {
+    let mut topic_levels = topic.split('/');
+    for level in filter.split('/') {
+        match (level, topic_levels.next()) {
+            ("#", _) => return true,
+            ("+", Some(_)) => {}
+            (level, Some(topic_level)) if level == topic_level => {}
+            _ => return false,
+        }
+    }
+    topic_levels.next().is_none()
+}
+
+fn read_packet(stream: &mut TcpStream) -> io::Result<(u8, Vec<u8>)> {
+    let mut header = [0; 1];
+    stream.read_exact(&mut header)?;
+    let mut length = 0_usize;
+    for shift in (0..28).step_by(7) {
+        let mut byte = [0; 1];
+        stream.read_exact(&mut byte)?;
+        length |= ((byte[0] & 0x7f) as usize) << shift;
+        if byte[0] & 0x80 == 0 {
+            break;
+        }
+    }
+    let mut body = vec![0; length];
+    stream.read_exact(&mut body)?;
+    Ok((header[0], body))
+}
+
+fn packet(header: u8, body: &[u8]) -> Vec<u8> {
+    let mut out = vec![header];
+    let mut length = body.len();
+    loop {
+        let mut byte = (length % 128) as u8;
+        length /= 128;
+        if length > 0 {
+            byte |= 0x80;
+        }
+        out.push(byte);
+        if length == 0 {
+            break;
+        }
+    }
+    out.extend_from_slice(body);
+    out
+}
+
+fn publish_packet(topic: &str, payload: &[u8], retain: bool) -> Vec<u8> {
+    let mut body = encode_string(topic);
+    body.extend_from_slice(payload);
+    packet((PUBLISH << 4) | retain as u8, &body)
+}
+
+fn encode_string(value: &str) -> Vec<u8> {
+    let mut out = (value.len() as u16).to_be_bytes().to_vec();
+    out.extend_from_slice(value.as_bytes());
+    out
+}
+
+/// Cursor over the fields of a packet body.
+struct Fields<'a> {
+    body: &'a [u8],
+}
+
+impl Fields<'_> {
+    fn u8(&mut self) -> io::Result<u8> {
+        let (&value, rest) = self.body.split_first().ok_or(io::ErrorKind::InvalidData)?;
+        self.body = rest;
+        Ok(value)
+    }
+
+    fn u16(&mut self) -> io::Result<u16> {
+        Ok(u16::from_be_bytes([self.u8()?, self.u8()?]))
+    }
+
+    fn bytes(&mut self) -> io::Result<Vec<u8>> {
+        let length = self.u16()? as usize;
+        if self.body.len() < length {
+            return Err(io::ErrorKind::InvalidData.into());
+        }
+        let (value, rest) = self.body.split_at(length);
+        self.body = rest;
+        Ok(value.to_vec())
+    }
+
+    fn string(&mut self) -> io::Result<String> {
+        String::from_utf8(self.bytes()?).map_err(|_| io::ErrorKind::InvalidData.into())
+    }
+}
+
+impl BrokerState {
+    fn publish(&mut self, topic: &str, payload: &[u8], retain: bool) {
+        if retain {
+            if payload.is_empty() {
+                self.retained.remove(topic);
+            } else {
+                self.retained.insert(topic.to_string(), payload.to_vec());
+            }
+        }
+        let packet = publish_packet(topic, payload, false);
+        for session in self.sessions.values_mut() {
+            if session.filters.iter().any(|f| topic_matches(f, topic)) {
+                let _ = session.stream.write_all(&packet);
+            }
+        }
+    }
+}
+
+pub fn start(bind: &str) -> Result<MqttBroker, String> {
+    let listener = TcpListener::bind(bind).map_err(|e| format!("Failed to bind {bind}: {e}"))?;
+    let address = listener
+        .local_addr()
+        .map_err(|e| e.to_string())?
+        .to_string();
+    let state = Arc::new(Mutex::new(BrokerState::default()));
+    let stopped = Arc::new(AtomicBool::new(false));
+    let next_id = AtomicUsize::new(0);
+
+    let (listener_state, listener_stopped) = (state.clone(), stopped.clone());
+    thread::spawn(move || {
+        for stream in listener.incoming().flatten() {
+            if listener_stopped.load(Ordering::Relaxed) {
+                break;
+            }
+            let state = listener_state.clone();
+            let id = next_id.fetch_add(1, Ordering::Relaxed);
+            thread::spawn(move || {
+                let will = serve(id, stream, &state);
+                let mut state = state.lock().unwrap();
+                state.sessions.remove(&id);
+                // The will is only published when the client didn't disconnect cleanly.
+                if let Ok(Some((topic, payload, retain))) = will {
+                    state.publish(&topic, &payload, retain);
+                }
+            });
+        }
+    });
+    Ok(MqttBroker {
+        address,
+        state,
+        stopped,
+    })
+}
+
+type Will = Option<(String, Vec<u8>, bool)>;
+
+/// Serves a client until it disconnects, returning its last will if it has to be published.
+fn serve(id: usize, mut stream: TcpStream, state: &Mutex<BrokerState>) -> io::Result<Will> {
+    let (header, body) = read_packet(&mut stream)?;
+    if header >> 4 != CONNECT {
+        return Ok(None);
+    }
+    let mut fields = Fields { body: &body };
+    let _protocol = fields.string()?;
+    let _level = fields.u8()?;
+    let flags = fields.u8()?;
+    let _keep_alive = fields.u16()?;
+    let _client_id = fields.string()?;
+    let will = if flags & 0x04 != 0 {
+        Some((fields.string()?, fields.bytes()?, flags & 0x20 != 0))
+    } else {
+        None
+    };
+    stream.write_all(&packet(0x20, &[0, 0]))?;
+    state.lock().unwrap().sessions.insert(
+        id,
+        Session {
+            stream: stream.try_clone()?,
+            filters: vec![],
+        },
+    );
+
+    loop {
+        let (header, body) = match read_packet(&mut stream) {
+            Ok(packet) => packet,
+            Err(_) => return Ok(will),
+        };
+        let mut fields = Fields { body: &body };
+        match header >> 4 {
+            PUBLISH => {
+                let qos = (header >> 1) & 3;
+                let topic = fields.string()?;
+                if qos > 0 {
+                    let packet_id = fields.u16()?;
+                    // PUBACK, or PUBREC for QoS 2.
+                    let ack = if qos == 1 { 0x40 } else { 0x50 };
+                    stream.write_all(&packet(ack, &packet_id.to_be_bytes()))?;
+                }
+                state
+                    .lock()
+                    .unwrap()
+                    .publish(&topic, fields.body, header & 1 != 0);
+            }
+            PUBREL => {
+                let packet_id = fields.u16()?;
+                stream.write_all(&packet(0x70, &packet_id.to_be_bytes()))?;
+            }
+            SUBSCRIBE => {
+                let packet_id = fields.u16()?;
+                let mut filters = vec![];
+                while !fields.body.is_empty() {
+                    filters.push(fields.string()?);
+                    let _qos = fields.u8()?;
+                }
+                let mut ack = packet_id.to_be_bytes().to_vec();
+                ack.extend(filters.iter().map(|_| 0));
+                let mut state = state.lock().unwrap();
+                stream.write_all(&packet(0x90, &ack))?;
+                for (topic, payload) in &state.retained {
+                    if filters.iter().any(|f| topic_matches(f, topic)) {
+                        stream.write_all(&publish_packet(topic, payload, true))?;
+                    }
+                }
+                if let Some(session) = state.sessions.get_mut(&id) {
+                    session.filters.extend(filters);
+                }
+            }
+            UNSUBSCRIBE => {
+                let packet_id = fields.u16()?;
+                let mut filters = vec![];
+                while !fields.body.is_empty() {
+                    filters.push(fields.string()?);
+                }
+                if let Some(session) = state.lock().unwrap().sessions.get_mut(&id) {
+                    session.filters.retain(|f| !filters.contains(f));
+                }
+                stream.write_all(&packet(0xb0, &packet_id.to_be_bytes()))?;
+            }
+            PINGREQ => stream.write_all(&packet(0xd0, &[]))?,
+            DISCONNECT => return Ok(None),
+            _ => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_topic_matches() {
+        assert!(topic_matches("frisquet/receive", "frisquet/receive"));
+        assert!(topic_matches(
+            "frisquet/+/temperature",
+            "frisquet/zone1/temperature"
+        ));
+        assert!(topic_matches("frisquet/#", "frisquet/zone1/set/consigne"));
+        assert!(topic_matches("#", "frisquet"));
+        assert!(!topic_matches("frisquet/+", "frisquet/zone1/temperature"));
+        assert!(!topic_matches("frisquet/receive", "frisquet/command"));
+        assert!(!topic_matches("frisquet/receive/#", "frisquet"));
+    }
+
+    #[test]
+    fn test_packet_length() {
+        let body = vec![0; 200];
+        let out = packet(0x30, &body);
+        assert_eq!(&out[..3], &[0x30, 0xc8, 0x01]);
+        assert_eq!(out.len(), 203);
+    }
+}
@@ -0,0 +1,117 @@
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+
+use crate::emulator::boiler::{SimulatedBoiler, Transmission};
+use crate::rf::serial::protocol::Command;
+
+/// Signal strength reported for the frames heard by the emulated gateway.
+const RSSI: i16 = -60;
+
+/// Radio side of the reference gateway firmware, shared by the serial and MQTT front ends.
+///
+/// Frames sent by the client reach the boiler when they are on its network,
+/// and the frames on the air are only reported while listening on their network.
+pub struct GatewayEmulator {
+    boiler: Arc<Mutex<SimulatedBoiler>>,
+    network_id: Option<Vec<u8>>,
+    listening: bool,
+    /// Transmissions on the air, not yet looked at by the radio.
+    air: Arc<Mutex<VecDeque<Transmission>>>,
+    received: VecDeque<Vec<u8>>,
+}
+
+impl GatewayEmulator {
+    pub fn new(boiler: Arc<Mutex<SimulatedBoiler>>) -> GatewayEmulator {
+        GatewayEmulator {
+            boiler,
+            network_id: None,
+            listening: false,
+            air: Arc::new(Mutex::new(VecDeque::new())),
+            received: VecDeque::new(),
+        }
+    }
+
+    /// Returns a handle to transmit frames from outside the gateway, e.g. the pairing broadcast.
+    pub fn air(&self) -> Arc<Mutex<VecDeque<Transmission>>> {
+        self.air.clone()
+    }
+
+    pub fn handle(&mut self, command: Command, argument: Option<&str>) -> Result<(), String> {
+        match command {
+            Command::NetworkId => {
+                let network_id = hex::decode(argument.unwrap_or_default().trim())
+                    .map_err(|e| format!("invalid network id: {e}"))?;
+                if network_id.len() != 4 {
+                    return Err("invalid network id: 4 bytes expected".to_string());
+                }
+                self.network_id = Some(network_id);
+            }
+            Command::Listen => self.listening = true,
+            Command::Sleep => self.listening = false,
+            Command::Send => {
+                let network_id = self.network_id.clone().ok_or("no network id")?;
+                let data = hex::decode(argument.unwrap_or_default().trim())
+                    .map_err(|e| format!("invalid frame: {e}"))?;
+                if data.is_empty() {
+                    return Err("empty frame".to_string());
+                }
+                let answers = self.boiler.lock().unwrap().handle(&network_id, &data);
+                self.air.lock().unwrap().extend(answers);
+                // The radio goes back to receive after transmitting, to hear the answer.
+                self.listening = true;
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns the frames heard since the last call, with their RSSI.
+    pub fn take_received(&mut self) -> Vec<(Vec<u8>, i16)> {
+        let transmissions: Vec<Transmission> = self.air.lock().unwrap().drain(..).collect();
+        for transmission in transmissions {
+            if self.listening && self.network_id.as_ref() == Some(&transmission.network_id) {
+                self.received.push_back(transmission.data);
+            }
+        }
+        self.received.drain(..).map(|data| (data, RSSI)).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_network_filter() {
+        let boiler = Arc::new(Mutex::new(SimulatedBoiler::new([5, 218, 46, 226])));
+        let mut gateway = GatewayEmulator::new(boiler.clone());
+        let sonde = "118020ba4001179c540004a029000102005c";
+
+        assert!(gateway.handle(Command::Send, Some(sonde)).is_err());
+        assert!(gateway.handle(Command::NetworkId, Some("0102")).is_err());
+
+        gateway
+            .handle(Command::NetworkId, Some("01020304"))
+            .unwrap();
+        gateway.handle(Command::Send, Some(sonde)).unwrap();
+        assert!(gateway.take_received().is_empty());
+
+        gateway
+            .handle(Command::NetworkId, Some("05da2ee2"))
+            .unwrap();
+        gateway.handle(Command::Send, Some(sonde)).unwrap();
+        assert_eq!(gateway.take_received().len(), 1);
+
+        // Asleep, the pairing broadcast is missed.
+        gateway.handle(Command::Sleep, None).unwrap();
+        let broadcast = boiler.lock().unwrap().start_pairing();
+        gateway.air().lock().unwrap().push_back(broadcast.clone());
+        assert!(gateway.take_received().is_empty());
+
+        gateway
+            .handle(Command::NetworkId, Some("ffffffff"))
+            .unwrap();
+        gateway.handle(Command::Listen, None).unwrap();
+        gateway.air().lock().unwrap().push_back(broadcast.clone());
+        assert_eq!(gateway.take_received(), vec![(broadcast.data, RSSI)]);
+    }
+}
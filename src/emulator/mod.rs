@@ -0,0 +1,68 @@
+//! Emulated gateways in front of a simulated boiler, to exercise the clients end to end without hardware.
+
+pub mod boiler;
+pub mod broker;
+pub mod gateway;
+pub mod mqtt;
+#[cfg(unix)]
+pub mod serial;
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::sync::{Arc, Mutex};
+
+    use crate::emulator::boiler::SimulatedBoiler;
+    use crate::emulator::gateway::GatewayEmulator;
+    use crate::frisquet::{self, proto::FrisquetData};
+    use crate::rf::mqtt::MqttSettings;
+    use crate::rf::RFClient;
+
+    const SONDE: &str = "118020ba4001179c540004a029000102005c";
+
+    fn exchange(client: &mut dyn RFClient) {
+        client.set_network_id(vec![5, 218, 46, 226]).unwrap();
+        client.send(hex::decode(SONDE).unwrap()).unwrap();
+        let frame = client.receive().unwrap();
+        assert_eq!(frame.rssi, Some(-60));
+        let (metadata, data) = frisquet::parse_data_from_str(&hex::encode(&frame.data)).unwrap();
+        assert_eq!((metadata.from_addr, metadata.to_addr), (0x80, 0x20));
+        assert!(matches!(data, FrisquetData::Chaudiere(_)), "{data:?}");
+    }
+
+    fn boiler() -> Arc<Mutex<SimulatedBoiler>> {
+        Arc::new(Mutex::new(SimulatedBoiler::new([5, 218, 46, 226])))
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_serial_gateway() {
+        let gateway = crate::emulator::serial::spawn(GatewayEmulator::new(boiler())).unwrap();
+        let settings = HashMap::from([
+            ("serial_port".to_string(), gateway.path.clone()),
+            ("serial_speed".to_string(), "115200".to_string()),
+        ]);
+        exchange(&mut crate::rf::serial::new(&settings).unwrap());
+    }
+
+    #[test]
+    fn test_mqtt_gateway() {
+        let broker = crate::emulator::broker::start("127.0.0.1:0").unwrap();
+        let settings = HashMap::from([
+            ("broker".to_string(), format!("tcp://{}", broker.address)),
+            ("mqtt_client".to_string(), "emulator-test".to_string()),
+            (
+                "mqtt_frisquet_topic".to_string(),
+                "frisquet/receive".to_string(),
+            ),
+            (
+                "mqtt_gateway_status_topic".to_string(),
+                "frisquet/status".to_string(),
+            ),
+        ]);
+        let mqtt_settings = MqttSettings::from_settings(&settings).unwrap();
+        let _gateway =
+            crate::emulator::mqtt::spawn(GatewayEmulator::new(boiler()), &mqtt_settings).unwrap();
+        exchange(&mut crate::rf::mqtt::new(&settings).unwrap());
+    }
+}
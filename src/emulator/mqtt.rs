@@ -0,0 +1,146 @@
+extern crate paho_mqtt as mqtt;
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use mqtt::Message;
+
+use crate::cli::log;
+use crate::emulator::gateway::GatewayEmulator;
+use crate::rf::mqtt::messages::{AckMessage, DataMessage, ErrorMessage, GatewayMessage};
+use crate::rf::mqtt::MqttSettings;
+use crate::rf::serial::protocol::Command;
+
+/// Gateway emulator speaking the MQTT gateway protocol.
+pub struct MqttGateway {
+    stop: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Drop for MqttGateway {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+/// Parses a command message into the command and argument of the gateway.
+fn parse_command(payload: &str) -> Result<(String, Command, Option<String>), String> {
+    let value: serde_json::Value = serde_json::from_str(payload).map_err(|e| e.to_string())?;
+    let name = value
+        .get("type")
+        .and_then(|t| t.as_str())
+        .ok_or("command without type")?
+        .to_string();
+    let argument = |key: &str| {
+        value
+            .get(key)
+            .and_then(|v| v.as_str())
+            .map(|v| v.to_string())
+            .ok_or(format!("{name} without {key}"))
+    };
+    let (command, argument) = match name.as_str() {
+        "LISTEN" => (Command::Listen, None),
+        "SLEEP" => (Command::Sleep, None),
+        "SET_NETWORK_ID" => (Command::NetworkId, Some(argument("network_id")?)),
+        "SEND" => (Command::Send, Some(argument("payload")?)),
+        _ => return Err(format!("unknown command {name}")),
+    };
+    Ok((name, command, argument))
+}
+
+fn publish(client: &mqtt::Client, settings: &MqttSettings, message: &dyn GatewayMessage) {
+    let json = serde_json::to_vec(message).unwrap();
+    if let Err(e) = client.publish(Message::new(&settings.receive_topic, json, settings.qos)) {
+        log!(Error, "Emulator failed to publish: {e}");
+    }
+}
+
+/// Connects the emulated gateway to the broker of `settings`, as `<client id>-gateway`.
+pub fn spawn(mut gateway: GatewayEmulator, settings: &MqttSettings) -> Result<MqttGateway, String> {
+    let settings = MqttSettings {
+        will_topic: settings.gateway_status_topic.clone(),
+        ..settings.with_client_id(format!("{}-gateway", settings.client_id))
+    };
+    let (client, rx) = crate::rf::mqtt::connect(&settings)?;
+    client
+        .subscribe(&settings.command_topic, settings.qos)
+        .map_err(|e| format!("Error subscribing to {}: {e}", settings.command_topic))?;
+
+    let stop = Arc::new(AtomicBool::new(false));
+    let thread_stop = stop.clone();
+    let thread = thread::spawn(move || {
+        while !thread_stop.load(Ordering::Relaxed) {
+            match rx.recv_timeout(Duration::from_millis(20)) {
+                Ok(Some(msg)) => match parse_command(&msg.payload_str()) {
+                    Ok((name, command, argument)) => {
+                        match gateway.handle(command, argument.as_deref()) {
+                            Ok(()) => publish(&client, &settings, &AckMessage { command: name }),
+                            Err(message) => publish(
+                                &client,
+                                &settings,
+                                &ErrorMessage {
+                                    message,
+                                    command: Some(name),
+                                },
+                            ),
+                        }
+                    }
+                    Err(message) => publish(
+                        &client,
+                        &settings,
+                        &ErrorMessage {
+                            message,
+                            command: None,
+                        },
+                    ),
+                },
+                Ok(None) => break,
+                Err(e) if e.is_timeout() => {}
+                Err(_) => break,
+            }
+            for (data, rssi) in gateway.take_received() {
+                let message = DataMessage {
+                    data: hex::encode(data),
+                    rssi: Some(rssi),
+                    lqi: None,
+                    gateway: None,
+                };
+                publish(&client, &settings, &message);
+            }
+        }
+        let _ = client.disconnect(None);
+    });
+
+    Ok(MqttGateway {
+        stop,
+        thread: Some(thread),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_command() {
+        assert_eq!(
+            parse_command(r#"{"type":"SET_NETWORK_ID","network_id":"05da2ee2"}"#),
+            Ok((
+                "SET_NETWORK_ID".to_string(),
+                Command::NetworkId,
+                Some("05da2ee2".to_string())
+            ))
+        );
+        assert_eq!(
+            parse_command(r#"{"type":"LISTEN"}"#),
+            Ok(("LISTEN".to_string(), Command::Listen, None))
+        );
+        assert!(parse_command(r#"{"type":"SEND"}"#).is_err());
+        assert!(parse_command(r#"{"type":"REBOOT"}"#).is_err());
+    }
+}
@@ -0,0 +1,134 @@
+use std::io::{ErrorKind, Read, Write};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use serialport::{SerialPort, TTYPort};
+
+use crate::emulator::gateway::GatewayEmulator;
+use crate::rf::serial::protocol::Command;
+
+const COMMANDS: [Command; 4] = [
+    Command::NetworkId,
+    Command::Send,
+    Command::Sleep,
+    Command::Listen,
+];
+
+/// Gateway emulator speaking the serial line protocol on a pseudo-terminal.
+pub struct SerialGateway {
+    /// Path of the pseudo-terminal to open as the serial port.
+    pub path: String,
+    stop: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+    // Kept open, the pseudo-terminal disappears with its last slave.
+    _slave: TTYPort,
+}
+
+impl Drop for SerialGateway {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+/// Splits the commands of a buffer, as the client doesn't terminate them.
+fn split_commands(buffer: &str) -> Vec<(Command, Option<&str>)> {
+    let mut starts: Vec<(usize, Command)> = COMMANDS
+        .iter()
+        .flat_map(|command| {
+            buffer
+                .match_indices(command.prefix())
+                .map(move |(index, _)| (index, *command))
+        })
+        .collect();
+    starts.sort_by_key(|(index, _)| *index);
+
+    starts
+        .iter()
+        .enumerate()
+        .map(|(n, (start, command))| {
+            let end = starts.get(n + 1).map_or(buffer.len(), |(end, _)| *end);
+            let argument = buffer[start + command.prefix().len()..end].trim();
+            (*command, (!argument.is_empty()).then_some(argument))
+        })
+        .collect()
+}
+
+pub fn spawn(mut gateway: GatewayEmulator) -> Result<SerialGateway, String> {
+    let (mut master, slave) = TTYPort::pair().map_err(|e| e.to_string())?;
+    master
+        .set_timeout(Duration::from_millis(20))
+        .map_err(|e| e.to_string())?;
+    let path = slave.name().ok_or("pseudo-terminal without name")?;
+    let stop = Arc::new(AtomicBool::new(false));
+
+    let thread_stop = stop.clone();
+    let thread = thread::spawn(move || {
+        let mut buffer = String::new();
+        let _ = master.write_all(b"Frisquet gateway emulator ready\r\n");
+        while !thread_stop.load(Ordering::Relaxed) {
+            let mut buf = [0; 512];
+            let idle = match master.read(&mut buf) {
+                Ok(read) => {
+                    buffer.push_str(&String::from_utf8_lossy(&buf[..read]));
+                    read == 0
+                }
+                Err(e) if e.kind() == ErrorKind::TimedOut => true,
+                Err(_) => break,
+            };
+
+            // A command is complete once the client stops writing.
+            if idle && !buffer.is_empty() {
+                let mut output = String::new();
+                for (command, argument) in split_commands(&buffer) {
+                    match gateway.handle(command, argument) {
+                        Ok(()) => output.push_str(&format!("OK:{command}\r\n")),
+                        Err(e) => output.push_str(&format!("ERR:{e}\r\n")),
+                    }
+                }
+                buffer.clear();
+                if master.write_all(output.as_bytes()).is_err() {
+                    break;
+                }
+            }
+            for (data, rssi) in gateway.take_received() {
+                let line = format!("{} RSSI:{rssi}\r\n", hex::encode(data));
+                if master.write_all(line.as_bytes()).is_err() {
+                    break;
+                }
+            }
+        }
+    });
+
+    Ok(SerialGateway {
+        path,
+        stop,
+        thread: Some(thread),
+        _slave: slave,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_split_commands() {
+        assert_eq!(
+            split_commands("NID: 05da2ee2LST:"),
+            vec![
+                (Command::NetworkId, Some("05da2ee2")),
+                (Command::Listen, None)
+            ]
+        );
+        assert_eq!(
+            split_commands("CMD: 0680202094824\n"),
+            vec![(Command::Send, Some("0680202094824"))]
+        );
+        assert!(split_commands("garbage").is_empty());
+    }
+}
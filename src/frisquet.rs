@@ -8,6 +8,36 @@ use crate::frisquet::proto::{FrisquetData, FrisquetMetadata};
 
 pub mod proto;
 
+/// Encodes a frame, the metadata length being taken from the message.
+pub fn encode_data<T>(
+    from: u8,
+    to: u8,
+    request_id: u16,
+    req_or_answer: u8,
+    msg_type: u8,
+    message: &T,
+) -> Result<(FrisquetMetadata, Vec<u8>), deku::DekuError>
+where
+    T: for<'a> DekuEnumExt<'a, u8> + DekuWrite<u8>,
+{
+    let length = message.deku_id()?;
+    let mut out = deku::bitvec::BitVec::with_capacity(length as usize);
+    message.write(&mut out, length)?;
+    let mut out = out.into_vec();
+
+    let metadata = FrisquetMetadata {
+        length,
+        to_addr: to,
+        from_addr: from,
+        request_id,
+        req_or_answer,
+        msg_type,
+    };
+    let mut payload = metadata.to_bytes()?;
+    payload.append(&mut out);
+    Ok((metadata, payload))
+}
+
 pub fn parse_data_from_str(
     input: &str,
 ) -> Result<(FrisquetMetadata, FrisquetData), deku::DekuError> {
@@ -30,6 +60,8 @@ pub fn parse_data_from_str(
             let (_, payload) = ChaudierePayload::read(rest, metadata.length)?;
             Ok((metadata, FrisquetData::Chaudiere(payload)))
         }
-        _ => panic!("Unknown"),
+        from => Err(deku::DekuError::Parse(format!(
+            "Unknown sender {from:#04x}"
+        ))),
     }
 }
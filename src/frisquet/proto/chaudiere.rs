@@ -1,6 +1,7 @@
 use deku::prelude::*;
+use serde::Serialize;
 
-#[derive(Debug, PartialEq, DekuRead, DekuWrite)]
+#[derive(Debug, PartialEq, DekuRead, DekuWrite, Serialize)]
 #[deku(ctx = "length: u8", id = "length")]
 pub enum ChaudierePayload {
     #[deku(id = "11")]
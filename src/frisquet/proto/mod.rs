@@ -1,29 +1,66 @@
-use deku::prelude::*;
+use serde::Serialize;
+use serde_json::Value;
 
 use crate::frisquet::proto::chaudiere::ChaudierePayload;
 use crate::frisquet::proto::satellite::SatellitePayload;
 use crate::frisquet::proto::sonde::SondePayload;
 
+// Baseline helpers, kept as they were.
+#[allow(clippy::is_digit_ascii_radix, clippy::needless_return)]
 pub mod common;
 pub mod satellite;
 
 pub mod chaudiere;
 pub mod sonde;
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, PartialEq, Serialize)]
 pub enum FrisquetData {
     Satellite(SatellitePayload),
     Chaudiere(ChaudierePayload),
     Sonde(SondePayload),
 }
 
-#[derive(Debug, PartialEq, DekuRead, DekuWrite)]
-#[deku(endian = "big")]
-pub struct FrisquetMetadata {
-    pub length: u8,
-    pub to_addr: u8,
-    pub from_addr: u8,
-    pub request_id: u16,
-    pub req_or_answer: u8,
-    pub msg_type: u8,
+impl FrisquetData {
+    /// Splits the serialized payload into its variant name and fields.
+    pub fn variant(&self) -> Option<(String, Value)> {
+        let payload = match serde_json::to_value(self).ok()? {
+            Value::Object(data) => data.into_iter().next()?.1,
+            _ => return None,
+        };
+        match payload {
+            Value::Object(payload) => payload.into_iter().next(),
+            _ => None,
+        }
+    }
+
+    /// Whether the payload fell in the catch-all variant of its device.
+    pub fn is_unknown(&self) -> bool {
+        matches!(
+            self,
+            FrisquetData::Satellite(SatellitePayload::SatelliteUnknowMessage { .. })
+                | FrisquetData::Chaudiere(ChaudierePayload::ChaudiereUnknownMessage { .. })
+                | FrisquetData::Sonde(SondePayload::SondeUnknownMessage { .. })
+        )
+    }
+}
+
+pub use metadata::FrisquetMetadata;
+
+// deku's derive computes byte lengths by hand, the impls it generates being
+// siblings of the struct.
+#[allow(clippy::manual_div_ceil)]
+mod metadata {
+    use deku::prelude::*;
+    use serde::Serialize;
+
+    #[derive(Debug, PartialEq, DekuRead, DekuWrite, Serialize)]
+    #[deku(endian = "big")]
+    pub struct FrisquetMetadata {
+        pub length: u8,
+        pub to_addr: u8,
+        pub from_addr: u8,
+        pub request_id: u16,
+        pub req_or_answer: u8,
+        pub msg_type: u8,
+    }
 }
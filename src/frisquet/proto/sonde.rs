@@ -1,6 +1,7 @@
 use deku::prelude::*;
+use serde::Serialize;
 
-#[derive(Debug, PartialEq, DekuRead, DekuWrite)]
+#[derive(Debug, PartialEq, DekuRead, DekuWrite, Serialize)]
 #[deku(ctx = "length: u8", id = "length")]
 pub enum SondePayload {
     #[deku(id = "17")]
@@ -27,6 +28,8 @@ pub enum SondePayload {
         data: Vec<u8>,
     },
 }
+// The tests keep the remainder of each parse without checking it.
+#[allow(unused_variables)]
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -0,0 +1,547 @@
+//! SQLite history of the frames, device states, temperatures and request/answer exchanges.
+
+use std::collections::HashMap;
+use std::time::{Duration, Instant, SystemTime};
+
+use rusqlite::{params, Connection, OptionalExtension};
+use serde_json::json;
+
+use crate::cli::{log, HistoryCommand};
+use crate::frisquet::proto::{FrisquetData, FrisquetMetadata};
+use crate::rf::{unix_seconds, ReceivedFrame};
+use crate::settings::parse_setting;
+use crate::sink::Sink;
+use crate::state::{Device, HeatingState};
+use crate::timeseries;
+
+const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS frames (
+    id INTEGER PRIMARY KEY,
+    timestamp REAL NOT NULL,
+    gateway TEXT NOT NULL,
+    rssi INTEGER,
+    lqi INTEGER,
+    raw TEXT NOT NULL,
+    from_addr INTEGER NOT NULL,
+    to_addr INTEGER NOT NULL,
+    request_id INTEGER NOT NULL,
+    req_or_answer INTEGER NOT NULL,
+    msg_type INTEGER NOT NULL,
+    variant TEXT NOT NULL,
+    unknown INTEGER NOT NULL,
+    payload TEXT NOT NULL
+);
+CREATE INDEX IF NOT EXISTS frames_timestamp ON frames (timestamp);
+CREATE TABLE IF NOT EXISTS devices (
+    name TEXT PRIMARY KEY,
+    first_seen REAL NOT NULL,
+    last_seen REAL NOT NULL,
+    state TEXT NOT NULL
+);
+CREATE TABLE IF NOT EXISTS device_states (
+    id INTEGER PRIMARY KEY,
+    frame_id INTEGER NOT NULL REFERENCES frames (id) ON DELETE CASCADE,
+    timestamp REAL NOT NULL,
+    device TEXT NOT NULL,
+    state TEXT NOT NULL
+);
+CREATE INDEX IF NOT EXISTS device_states_timestamp ON device_states (timestamp);
+CREATE TABLE IF NOT EXISTS temperatures (
+    id INTEGER PRIMARY KEY,
+    frame_id INTEGER NOT NULL REFERENCES frames (id) ON DELETE CASCADE,
+    timestamp REAL NOT NULL,
+    device TEXT NOT NULL,
+    zone INTEGER,
+    temperature REAL,
+    consigne REAL,
+    outdoor_temperature REAL
+);
+CREATE INDEX IF NOT EXISTS temperatures_timestamp ON temperatures (timestamp);
+CREATE TABLE IF NOT EXISTS exchanges (
+    id INTEGER PRIMARY KEY,
+    request_frame_id INTEGER NOT NULL REFERENCES frames (id) ON DELETE CASCADE,
+    answer_frame_id INTEGER NOT NULL REFERENCES frames (id) ON DELETE CASCADE,
+    request_id INTEGER NOT NULL,
+    msg_type INTEGER NOT NULL,
+    latency_ms REAL NOT NULL
+);
+";
+
+/// Answers further than this from their request are not paired with it.
+const EXCHANGE_WINDOW_SECS: f64 = 60.0;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct HistorySettings {
+    pub database: String,
+    /// Rows older than this are pruned, when set.
+    pub retention: Option<Duration>,
+}
+
+impl HistorySettings {
+    /// Reads the `history_*` settings, the history being enabled by `history_database`.
+    pub fn from_settings(
+        settings: &HashMap<String, String>,
+    ) -> Result<Option<HistorySettings>, String> {
+        let Some(database) = settings.get("history_database") else {
+            return Ok(None);
+        };
+        let days: u64 = parse_setting(settings, "history_retention_days")?.unwrap_or(365);
+        Ok(Some(HistorySettings {
+            database: database.clone(),
+            // 0 keeps everything.
+            retention: (days > 0).then(|| Duration::from_secs(days * 86400)),
+        }))
+    }
+}
+
+fn format_timestamp(timestamp: f64) -> String {
+    chrono::DateTime::from_timestamp_millis((timestamp * 1000.0) as i64)
+        .map(|time| {
+            time.with_timezone(&chrono::Local)
+                .format("%Y-%m-%d %H:%M:%S")
+                .to_string()
+        })
+        .unwrap_or_default()
+}
+
+/// Opens the database, creating the schema when needed.
+pub fn open(path: &str) -> Result<Connection, String> {
+    let conn = Connection::open(path).map_err(|e| format!("Error opening {path}: {e}"))?;
+    conn.execute_batch("PRAGMA foreign_keys = ON;")
+        .and_then(|_| conn.execute_batch(SCHEMA))
+        .map_err(|e| format!("Error creating the schema of {path}: {e}"))?;
+    Ok(conn)
+}
+
+pub struct History {
+    conn: Connection,
+    retention: Option<Duration>,
+    last_prune: Option<Instant>,
+}
+
+/// Opens the database when the history is enabled.
+pub fn new(settings: &HashMap<String, String>) -> Result<Option<History>, String> {
+    let Some(settings) = HistorySettings::from_settings(settings)? else {
+        return Ok(None);
+    };
+    Ok(Some(History {
+        conn: open(&settings.database)?,
+        retention: settings.retention,
+        last_prune: None,
+    }))
+}
+
+impl History {
+    /// Stores a frame with the temperatures it carries, the exchange it completes
+    /// and the state of the devices it changed, both the latest and its history.
+    pub fn record(
+        &mut self,
+        frame: &ReceivedFrame,
+        metadata: &FrisquetMetadata,
+        data: &FrisquetData,
+        state: &HeatingState,
+        devices: &[Device],
+    ) -> Result<(), rusqlite::Error> {
+        let timestamp = unix_seconds(frame.timestamp);
+        let (variant, payload) = data.variant().unwrap_or_default();
+        let tx = self.conn.transaction()?;
+        tx.execute(
+            "INSERT INTO frames (timestamp, gateway, rssi, lqi, raw, from_addr, to_addr,
+                request_id, req_or_answer, msg_type, variant, unknown, payload)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13)",
+            params![
+                timestamp,
+                frame.gateway,
+                frame.rssi,
+                frame.lqi,
+                hex::encode(&frame.data),
+                metadata.from_addr,
+                metadata.to_addr,
+                metadata.request_id,
+                metadata.req_or_answer,
+                metadata.msg_type,
+                variant,
+                data.is_unknown(),
+                payload.to_string(),
+            ],
+        )?;
+        let frame_id = tx.last_insert_rowid();
+
+        if let Some(point) = timeseries::point(metadata, data, frame.timestamp) {
+            tx.execute(
+                "INSERT INTO temperatures (frame_id, timestamp, device, zone, temperature,
+                    consigne, outdoor_temperature)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+                params![
+                    frame_id,
+                    timestamp,
+                    point.device.name(),
+                    point.zone,
+                    point.temperature,
+                    point.consigne,
+                    point.outdoor_temperature,
+                ],
+            )?;
+        }
+
+        if metadata.req_or_answer & 0x80 != 0 {
+            let request: Option<(i64, f64)> = tx
+                .query_row(
+                    "SELECT id, timestamp FROM frames
+                     WHERE request_id = ?1 AND from_addr = ?2 AND to_addr = ?3
+                        AND req_or_answer & 128 = 0 AND timestamp BETWEEN ?4 AND ?5
+                     ORDER BY timestamp DESC LIMIT 1",
+                    params![
+                        metadata.request_id,
+                        metadata.to_addr,
+                        metadata.from_addr,
+                        timestamp - EXCHANGE_WINDOW_SECS,
+                        timestamp,
+                    ],
+                    |row| Ok((row.get(0)?, row.get(1)?)),
+                )
+                .optional()?;
+            if let Some((request_frame_id, request_timestamp)) = request {
+                tx.execute(
+                    "INSERT INTO exchanges (request_frame_id, answer_frame_id, request_id,
+                        msg_type, latency_ms)
+                     VALUES (?1, ?2, ?3, ?4, ?5)",
+                    params![
+                        request_frame_id,
+                        frame_id,
+                        metadata.request_id,
+                        metadata.msg_type,
+                        (timestamp - request_timestamp) * 1000.0,
+                    ],
+                )?;
+            }
+        }
+
+        for &device in devices {
+            let device_state = match device {
+                Device::Zone(zone) => json!(state.zones.get(&zone)),
+                Device::Sonde => json!(state.sonde),
+                Device::Boiler => json!(state.boiler),
+            };
+            tx.execute(
+                "INSERT INTO devices (name, first_seen, last_seen, state) VALUES (?1, ?2, ?2, ?3)
+                 ON CONFLICT (name) DO UPDATE SET last_seen = ?2, state = ?3",
+                params![device.name(), timestamp, device_state.to_string()],
+            )?;
+            tx.execute(
+                "INSERT INTO device_states (frame_id, timestamp, device, state)
+                 VALUES (?1, ?2, ?3, ?4)",
+                params![frame_id, timestamp, device.name(), device_state.to_string()],
+            )?;
+        }
+        tx.commit()
+    }
+
+    /// Deletes the frames older than the retention, with their temperatures, exchanges
+    /// and device states.
+    pub fn prune(&self, now: SystemTime) -> Result<usize, rusqlite::Error> {
+        let Some(retention) = self.retention else {
+            return Ok(0);
+        };
+        let cutoff = unix_seconds(now) - retention.as_secs_f64();
+        self.conn
+            .execute("DELETE FROM frames WHERE timestamp < ?1", params![cutoff])
+    }
+}
+
+impl Sink for History {
+    fn name(&self) -> &str {
+        "history"
+    }
+
+    fn frame(
+        &mut self,
+        frame: &ReceivedFrame,
+        metadata: &FrisquetMetadata,
+        data: &FrisquetData,
+        state: &HeatingState,
+        devices: &[Device],
+    ) -> Result<(), String> {
+        self.record(frame, metadata, data, state, devices)
+            .map_err(|e| format!("Error storing frame: {e}"))
+    }
+
+    fn tick(&mut self, _state: &HeatingState, now: SystemTime) -> Result<(), String> {
+        // Hourly is plenty for a retention counted in days.
+        if self
+            .last_prune
+            .is_some_and(|last| last.elapsed() < Duration::from_secs(3600))
+        {
+            return Ok(());
+        }
+        self.last_prune = Some(Instant::now());
+        match self.prune(now) {
+            Ok(0) => Ok(()),
+            Ok(pruned) => {
+                log!(Info, "Pruned {pruned} frames from the history");
+                Ok(())
+            }
+            Err(e) => Err(format!("Error pruning the history: {e}")),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct TemperatureRow {
+    pub timestamp: f64,
+    pub device: String,
+    pub zone: Option<u8>,
+    pub temperature: Option<f64>,
+    pub consigne: Option<f64>,
+    pub outdoor_temperature: Option<f64>,
+}
+
+/// Temperatures since `since`, of a device and/or a zone, oldest first.
+///
+/// A zone matches both its satellite's frames and the boiler's answers to it.
+pub fn temperatures(
+    conn: &Connection,
+    device: Option<&str>,
+    zone: Option<u8>,
+    since: SystemTime,
+) -> Result<Vec<TemperatureRow>, rusqlite::Error> {
+    let mut statement = conn.prepare(
+        "SELECT timestamp, device, zone, temperature, consigne, outdoor_temperature
+         FROM temperatures
+         WHERE timestamp >= ?1 AND (?2 IS NULL OR device = ?2) AND (?3 IS NULL OR zone = ?3)
+         ORDER BY timestamp",
+    )?;
+    let rows = statement.query_map(params![unix_seconds(since), device, zone], |row| {
+        Ok(TemperatureRow {
+            timestamp: row.get(0)?,
+            device: row.get(1)?,
+            zone: row.get(2)?,
+            temperature: row.get(3)?,
+            consigne: row.get(4)?,
+            outdoor_temperature: row.get(5)?,
+        })
+    })?;
+    rows.collect()
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct FrameRow {
+    pub timestamp: f64,
+    pub from_addr: u8,
+    pub to_addr: u8,
+    pub variant: String,
+    pub raw: String,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct FrameFilter {
+    pub since: SystemTime,
+    pub from_addr: Option<u8>,
+    pub unknown: bool,
+    pub limit: u32,
+}
+
+/// Frames matching the filter, oldest first.
+pub fn frames(conn: &Connection, filter: &FrameFilter) -> Result<Vec<FrameRow>, rusqlite::Error> {
+    let mut statement = conn.prepare(
+        "SELECT timestamp, from_addr, to_addr, variant, raw FROM (
+            SELECT * FROM frames
+            WHERE timestamp >= ?1 AND (?2 IS NULL OR from_addr = ?2) AND (NOT ?3 OR unknown)
+            ORDER BY timestamp DESC LIMIT ?4
+         ) ORDER BY timestamp",
+    )?;
+    let rows = statement.query_map(
+        params![
+            unix_seconds(filter.since),
+            filter.from_addr,
+            filter.unknown,
+            filter.limit
+        ],
+        |row| {
+            Ok(FrameRow {
+                timestamp: row.get(0)?,
+                from_addr: row.get(1)?,
+                to_addr: row.get(2)?,
+                variant: row.get(3)?,
+                raw: row.get(4)?,
+            })
+        },
+    )?;
+    rows.collect()
+}
+
+/// Parses an address in decimal or 0x-prefixed hex.
+pub fn parse_addr(value: &str) -> Result<u8, String> {
+    match value.strip_prefix("0x") {
+        Some(hex) => u8::from_str_radix(hex, 16),
+        None => value.parse(),
+    }
+    .map_err(|_| format!("invalid address {value}"))
+}
+
+fn since(days: u32) -> SystemTime {
+    SystemTime::now() - Duration::from_secs(days as u64 * 86400)
+}
+
+/// Runs the `history` subcommand, printing the rows as tab-separated columns.
+pub fn command(settings: &HashMap<String, String>, query: &HistoryCommand) -> Result<(), String> {
+    let database = HistorySettings::from_settings(settings)?
+        .ok_or("history_database is not set")?
+        .database;
+    let conn = open(&database)?;
+    match query {
+        HistoryCommand::Temperatures { device, zone, days } => {
+            let rows = temperatures(&conn, device.as_deref(), *zone, since(*days))
+                .map_err(|e| e.to_string())?;
+            println!("time\tdevice\tzone\ttemperature\tconsigne\toutdoor_temperature");
+            let cell = |v: Option<f64>| v.map(|v| v.to_string()).unwrap_or_default();
+            for row in rows {
+                println!(
+                    "{}\t{}\t{}\t{}\t{}\t{}",
+                    format_timestamp(row.timestamp),
+                    row.device,
+                    row.zone.map(|z| z.to_string()).unwrap_or_default(),
+                    cell(row.temperature),
+                    cell(row.consigne),
+                    cell(row.outdoor_temperature),
+                );
+            }
+        }
+        HistoryCommand::Frames {
+            from,
+            unknown,
+            days,
+            limit,
+        } => {
+            let filter = FrameFilter {
+                since: since(*days),
+                from_addr: *from,
+                unknown: *unknown,
+                limit: *limit,
+            };
+            let rows = frames(&conn, &filter).map_err(|e| e.to_string())?;
+            println!("time\tfrom\tto\tvariant\traw");
+            for row in rows {
+                println!(
+                    "{}\t{:#04x}\t{:#04x}\t{}\t{}",
+                    format_timestamp(row.timestamp),
+                    row.from_addr,
+                    row.to_addr,
+                    row.variant,
+                    row.raw
+                );
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::frisquet;
+
+    fn history() -> History {
+        History {
+            conn: open(":memory:").unwrap(),
+            retention: Some(Duration::from_secs(7 * 86400)),
+            last_prune: None,
+        }
+    }
+
+    fn record(history: &mut History, state: &mut HeatingState, raw: &str, time: SystemTime) {
+        let (metadata, data) = frisquet::parse_data_from_str(raw).unwrap();
+        let mut frame = ReceivedFrame::new(hex::decode(raw).unwrap(), "test");
+        frame.timestamp = time;
+        let devices = state.update(&metadata, &data, time);
+        history
+            .record(&frame, &metadata, &data, state, &devices)
+            .unwrap();
+    }
+
+    #[test]
+    fn test_record_and_query() {
+        let mut history = history();
+        let mut state = HeatingState::default();
+        let now = SystemTime::now();
+        let old = now - Duration::from_secs(10 * 86400);
+        record(
+            &mut history,
+            &mut state,
+            "17800819E40117A0290015A02F00040800B200AA002400C6",
+            old,
+        );
+        // The satellite's request and the boiler's answer.
+        record(
+            &mut history,
+            &mut state,
+            "17800819480117A0290015A02F00040800B200AA002400C6",
+            now,
+        );
+        record(
+            &mut history,
+            &mut state,
+            "310880194881172A050A0000230423171012000000C000BE002500C600C604F6000000000000000004F60000000000000000",
+            now + Duration::from_millis(250),
+        );
+        record(&mut history, &mut state, "0b0080d3c802410405d7199e", now);
+
+        let rows = temperatures(&history.conn, None, Some(1), old).unwrap();
+        assert_eq!(rows.len(), 3);
+        assert_eq!(rows[2].device, "boiler");
+        assert_eq!(rows[2].temperature, Some(19.2));
+        let latency: f64 = history
+            .conn
+            .query_row("SELECT latency_ms FROM exchanges", [], |row| row.get(0))
+            .unwrap();
+        assert!((latency - 250.0).abs() < 1.0);
+        let zone: String = history
+            .conn
+            .query_row(
+                "SELECT state FROM devices WHERE name = 'zone1'",
+                [],
+                |row| row.get(0),
+            )
+            .unwrap();
+        assert!(zone.contains("\"temperature\":19.2"));
+        let count = |history: &History| -> i64 {
+            history
+                .conn
+                .query_row(
+                    "SELECT COUNT(*) FROM device_states WHERE device = 'zone1'",
+                    [],
+                    |row| row.get(0),
+                )
+                .unwrap()
+        };
+        assert_eq!(count(&history), 3);
+
+        assert_eq!(history.prune(now).unwrap(), 1);
+        assert_eq!(count(&history), 2);
+        assert_eq!(
+            temperatures(&history.conn, Some("zone1"), None, old)
+                .unwrap()
+                .len(),
+            1
+        );
+        let filter = FrameFilter {
+            since: old,
+            from_addr: Some(0x80),
+            unknown: false,
+            limit: 10,
+        };
+        assert_eq!(frames(&history.conn, &filter).unwrap().len(), 2);
+        assert_eq!(
+            frames(
+                &history.conn,
+                &FrameFilter {
+                    unknown: true,
+                    ..filter
+                }
+            )
+            .unwrap()
+            .len(),
+            0
+        );
+    }
+}
@@ -0,0 +1,331 @@
+//! Home Assistant MQTT discovery: a climate entity per zone, the outdoor sensor and the boiler sensors.
+
+extern crate paho_mqtt as mqtt;
+
+use std::collections::{BTreeMap, HashMap};
+use std::time::{Duration, SystemTime};
+
+use mqtt::Message;
+use serde_json::{json, Value};
+
+use crate::frisquet::proto::{FrisquetData, FrisquetMetadata};
+use crate::rf::mqtt::MqttSettings;
+use crate::rf::ReceivedFrame;
+use crate::settings::parse_setting;
+use crate::sink::Sink;
+use crate::state::{Device, HeatingState};
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct HomeAssistantSettings {
+    /// Prefix Home Assistant watches for discovery configs.
+    pub discovery_prefix: String,
+    /// Prefix of the state and availability topics.
+    pub state_prefix: String,
+    /// Devices not heard for this long are reported unavailable.
+    pub expire_after: Duration,
+}
+
+impl HomeAssistantSettings {
+    /// Reads the `homeassistant_*` settings, discovery being enabled by `homeassistant_discovery`.
+    pub fn from_settings(
+        settings: &HashMap<String, String>,
+    ) -> Result<Option<HomeAssistantSettings>, String> {
+        if settings.get("homeassistant_discovery").map(|v| v.as_str()) != Some("true") {
+            return Ok(None);
+        }
+        let expire_after =
+            parse_setting(settings, "homeassistant_expire_after_secs")?.unwrap_or(1800);
+        Ok(Some(HomeAssistantSettings {
+            discovery_prefix: settings
+                .get("homeassistant_discovery_prefix")
+                .cloned()
+                .unwrap_or_else(|| "homeassistant".to_string()),
+            state_prefix: settings
+                .get("homeassistant_state_prefix")
+                .cloned()
+                .unwrap_or_else(|| "frisquet".to_string()),
+            expire_after: Duration::from_secs(expire_after),
+        }))
+    }
+
+    fn bridge_availability_topic(&self) -> String {
+        format!("{}/commander/availability", self.state_prefix)
+    }
+
+    pub fn state_topic(&self, device: Device) -> String {
+        format!("{}/{}/state", self.state_prefix, device.name())
+    }
+
+    pub fn availability_topic(&self, device: Device) -> String {
+        format!("{}/{}/availability", self.state_prefix, device.name())
+    }
+}
+
+fn device_info(device: Device) -> Value {
+    let (name, model) = match device {
+        Device::Zone(zone) => (format!("Frisquet zone {zone}"), "Satellite"),
+        Device::Sonde => ("Frisquet sonde".to_string(), "Sonde extérieure"),
+        Device::Boiler => ("Frisquet boiler".to_string(), "Chaudière"),
+    };
+    let mut info = json!({
+        "identifiers": [format!("frisquet_{}", device.name())],
+        "name": name,
+        "manufacturer": "Frisquet",
+        "model": model,
+    });
+    if device != Device::Boiler {
+        info["via_device"] = json!("frisquet_boiler");
+    }
+    info
+}
+
+fn sensor(
+    settings: &HomeAssistantSettings,
+    device: Device,
+    key: &str,
+    name: &str,
+    device_class: Option<&str>,
+) -> (String, Value) {
+    let unique_id = format!("frisquet_{}_{key}", device.name());
+    let mut config = json!({
+        "name": name,
+        "unique_id": unique_id,
+        "state_topic": settings.state_topic(device),
+        "value_template": format!("{{{{ value_json.{key} }}}}"),
+    });
+    if let Some(device_class) = device_class {
+        config["device_class"] = json!(device_class);
+        if device_class == "temperature" {
+            config["unit_of_measurement"] = json!("°C");
+            config["state_class"] = json!("measurement");
+        }
+    }
+    (
+        format!("{}/sensor/{unique_id}/config", settings.discovery_prefix),
+        config,
+    )
+}
+
+/// Returns the discovery configs of a device, by topic.
+pub fn discovery_configs(settings: &HomeAssistantSettings, device: Device) -> Vec<(String, Value)> {
+    let mut configs = match device {
+        Device::Zone(zone) => {
+            let unique_id = format!("frisquet_{}", device.name());
+            let state_topic = settings.state_topic(device);
+            vec![(
+                format!("{}/climate/{unique_id}/config", settings.discovery_prefix),
+                json!({
+                    "name": format!("Zone {zone}"),
+                    "unique_id": unique_id,
+                    "current_temperature_topic": state_topic,
+                    "current_temperature_template": "{{ value_json.temperature }}",
+                    "temperature_state_topic": state_topic,
+                    "temperature_state_template": "{{ value_json.consigne }}",
+                    "mode_state_topic": state_topic,
+                    "mode_state_template": "{{ 'off' if value_json.mode == 'hors_gel' else 'heat' }}",
+                    "modes": ["heat", "off"],
+                    "json_attributes_topic": state_topic,
+                    "temperature_unit": "C",
+                    "temp_step": 0.5,
+                    "min_temp": 5,
+                    "max_temp": 30,
+                }),
+            )]
+        }
+        Device::Sonde => vec![sensor(
+            settings,
+            device,
+            "temperature",
+            "Outdoor temperature",
+            Some("temperature"),
+        )],
+        Device::Boiler => vec![
+            sensor(
+                settings,
+                device,
+                "outdoor_temperature",
+                "Boiler outdoor temperature",
+                Some("temperature"),
+            ),
+            sensor(
+                settings,
+                device,
+                "temperature",
+                "Boiler room temperature",
+                Some("temperature"),
+            ),
+            sensor(
+                settings,
+                device,
+                "consigne",
+                "Boiler target temperature",
+                Some("temperature"),
+            ),
+            sensor(settings, device, "clock", "Boiler clock", None),
+        ],
+    };
+    for (_, config) in configs.iter_mut() {
+        config["device"] = device_info(device);
+        config["availability_mode"] = json!("all");
+        config["availability"] = json!([
+            { "topic": settings.bridge_availability_topic() },
+            { "topic": settings.availability_topic(device) },
+        ]);
+    }
+    configs
+}
+
+/// Returns the state published for a device.
+pub fn state_payload(state: &HeatingState, device: Device) -> Value {
+    match device {
+        Device::Zone(zone) => json!(state.zones.get(&zone).cloned().unwrap_or_default()),
+        Device::Sonde => json!(state.sonde),
+        Device::Boiler => json!(state.boiler),
+    }
+}
+
+/// Publishes the discovery configs, states and availability of the devices heard.
+pub struct HomeAssistant {
+    client: mqtt::Client,
+    settings: HomeAssistantSettings,
+    qos: i32,
+    /// Devices announced to Home Assistant, with their availability.
+    available: BTreeMap<Device, bool>,
+}
+
+/// Connects to the broker when discovery is enabled.
+pub fn new(settings: &HashMap<String, String>) -> Result<Option<HomeAssistant>, String> {
+    let Some(ha_settings) = HomeAssistantSettings::from_settings(settings)? else {
+        return Ok(None);
+    };
+    let mqtt_settings = MqttSettings::from_settings(settings)?;
+    let mqtt_settings = MqttSettings {
+        will_topic: Some(ha_settings.bridge_availability_topic()),
+        ..mqtt_settings.with_client_id(format!("{}-homeassistant", mqtt_settings.client_id))
+    };
+    let (client, _) = crate::rf::mqtt::connect(&mqtt_settings)?;
+    Ok(Some(HomeAssistant {
+        client,
+        settings: ha_settings,
+        qos: mqtt_settings.qos,
+        available: BTreeMap::new(),
+    }))
+}
+
+impl HomeAssistant {
+    fn publish(&self, topic: &str, payload: impl Into<Vec<u8>>) -> Result<(), String> {
+        self.client
+            .publish(Message::new_retained(topic, payload, self.qos))
+            .map_err(|e| format!("Error publishing to {topic}: {e}"))
+    }
+
+    fn set_available(&mut self, device: Device, available: bool) -> Result<(), String> {
+        if self.available.get(&device) != Some(&available) {
+            let payload = if available { "online" } else { "offline" };
+            self.publish(&self.settings.availability_topic(device), payload)?;
+            self.available.insert(device, available);
+        }
+        Ok(())
+    }
+
+    /// Publishes the state of the updated devices, announcing the new ones.
+    pub fn update(&mut self, state: &HeatingState, devices: &[Device]) -> Result<(), String> {
+        for &device in devices {
+            if !self.available.contains_key(&device) {
+                for (topic, config) in discovery_configs(&self.settings, device) {
+                    self.publish(&topic, config.to_string())?;
+                }
+            }
+            self.publish(
+                &self.settings.state_topic(device),
+                state_payload(state, device).to_string(),
+            )?;
+            self.set_available(device, true)?;
+        }
+        Ok(())
+    }
+
+    /// Marks the devices not heard recently as unavailable.
+    pub fn check_availability(
+        &mut self,
+        state: &HeatingState,
+        now: SystemTime,
+    ) -> Result<(), String> {
+        let devices: Vec<Device> = self.available.keys().copied().collect();
+        for device in devices {
+            let fresh = state.is_fresh(device, self.settings.expire_after, now);
+            self.set_available(device, fresh)?;
+        }
+        Ok(())
+    }
+}
+
+impl Sink for HomeAssistant {
+    fn name(&self) -> &str {
+        "Home Assistant"
+    }
+
+    fn frame(
+        &mut self,
+        _frame: &ReceivedFrame,
+        _metadata: &FrisquetMetadata,
+        _data: &FrisquetData,
+        state: &HeatingState,
+        devices: &[Device],
+    ) -> Result<(), String> {
+        self.update(state, devices)
+    }
+
+    fn tick(&mut self, state: &HeatingState, now: SystemTime) -> Result<(), String> {
+        self.check_availability(state, now)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn settings() -> HomeAssistantSettings {
+        HomeAssistantSettings::from_settings(&HashMap::from([(
+            "homeassistant_discovery".to_string(),
+            "true".to_string(),
+        )]))
+        .unwrap()
+        .unwrap()
+    }
+
+    #[test]
+    fn test_zone_discovery() {
+        let configs = discovery_configs(&settings(), Device::Zone(2));
+        assert_eq!(configs.len(), 1);
+        let (topic, config) = &configs[0];
+        assert_eq!(topic, "homeassistant/climate/frisquet_zone2/config");
+        assert_eq!(config["current_temperature_topic"], "frisquet/zone2/state");
+        assert_eq!(config["device"]["via_device"], "frisquet_boiler");
+        assert_eq!(
+            config["availability"][1]["topic"],
+            "frisquet/zone2/availability"
+        );
+    }
+
+    #[test]
+    fn test_boiler_discovery() {
+        let topics: Vec<String> = discovery_configs(&settings(), Device::Boiler)
+            .into_iter()
+            .map(|(topic, _)| topic)
+            .collect();
+        assert_eq!(
+            topics,
+            vec![
+                "homeassistant/sensor/frisquet_boiler_outdoor_temperature/config",
+                "homeassistant/sensor/frisquet_boiler_temperature/config",
+                "homeassistant/sensor/frisquet_boiler_consigne/config",
+                "homeassistant/sensor/frisquet_boiler_clock/config"
+            ]
+        );
+        assert_eq!(
+            HomeAssistantSettings::from_settings(&HashMap::new()),
+            Ok(None)
+        );
+    }
+}
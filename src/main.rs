@@ -1,213 +1,419 @@
+use std::any::Any;
 use std::collections::HashMap;
-use std::fmt::Debug;
+use std::sync::{Arc, Mutex};
 use std::thread::sleep;
 use std::time;
 
-use deku::prelude::*;
-use hex;
-
+use clap::Parser;
 use config::Config;
-use frisquet::proto::FrisquetMetadata;
 
-use crate::frisquet::proto::chaudiere::ChaudierePayload;
-use crate::frisquet::proto::satellite::SatellitePayload;
-use crate::frisquet::proto::sonde::SondePayload;
-use crate::frisquet::proto::FrisquetData;
+use crate::cli::{log, Cli, Command, EncodeCommand, SatelliteArgs};
+use crate::emulator::boiler::SimulatedBoiler;
+use crate::emulator::gateway::GatewayEmulator;
+use crate::rf::request::{self, RequestOptions};
 use crate::rf::RFClient;
+use crate::sink::Sink;
 
 pub mod rf;
 
+pub mod api;
+pub mod cli;
+pub mod commands;
+pub mod decode;
+pub mod emulator;
 pub mod frisquet;
+pub mod history;
+pub mod homeassistant;
+pub mod metrics;
+pub mod pairing;
+pub mod publisher;
+pub mod schedule;
+pub mod settings;
+pub mod sink;
+pub mod sonde;
+pub mod state;
+pub mod temperature;
+pub mod timeseries;
+pub mod watchdog;
+
 fn main() {
-    println!("Hello, world!");
-    let settings = Config::builder()
-        // Add in `./Settings.toml`
-        .add_source(config::File::with_name("config"))
-        // Add in settings from the environment (with a prefix of APP)
+    let cli = Cli::parse();
+    cli::set_log_level(cli.log_level);
+    if let Err(e) = run(&cli) {
+        eprintln!("{e}");
+        std::process::exit(1);
+    }
+}
+
+fn run(cli: &Cli) -> Result<(), String> {
+    let Some(command) = &cli.command else {
+        return bridge(&load_settings(cli)?);
+    };
+    match command {
+        Command::Sniff => {
+            let settings = load_settings(cli)?;
+            let mut client = radio(&settings)?;
+            tune(client.as_mut(), &settings)?;
+            sniff(client.as_mut())
+        }
+        Command::Pair { timeout } => {
+            let settings = load_settings(cli)?;
+            let mut client = radio(&settings)?;
+            let network_id = pairing::pair_sonde(
+                client.as_mut(),
+                time::Duration::from_secs(*timeout),
+                &RequestOptions::from_settings(&settings)?,
+            )?;
+            println!(
+                "Paired on network {0}, set network_id = \"{0}\" in the configuration",
+                hex::encode(network_id)
+            );
+            Ok(())
+        }
+        Command::EmulateSonde { temperature, once } => {
+            let mut settings = load_settings(cli)?;
+            if let Some(temperature) = temperature {
+                settings.insert("outdoor_source".to_string(), "fixed".to_string());
+                settings.insert("outdoor_fixed".to_string(), temperature.to_string());
+            }
+            emulate_sonde(&settings, *once)
+        }
+        Command::EmulateSatellite(satellite) => {
+            let settings = load_settings(cli)?;
+            emulate_satellite(&settings, satellite)
+        }
+        Command::Decode {
+            frames,
+            file,
+            format,
+        } => decode::command(frames, file.as_deref(), *format),
+        Command::Encode { frame } => {
+            let frame = match frame {
+                EncodeCommand::Sonde {
+                    temperature,
+                    request_id,
+                } => commands::sonde_frame(*temperature, *request_id)?,
+                EncodeCommand::Satellite {
+                    satellite,
+                    request_id,
+                } => satellite_frame(satellite, *request_id)?,
+            };
+            println!("{}", hex::encode(frame));
+            Ok(())
+        }
+        Command::Replay {
+            file,
+            original_timing,
+        } => {
+            let mut settings = load_settings(cli)?;
+            settings.insert("transport".to_string(), "replay".to_string());
+            settings.insert("replay_file".to_string(), file.clone());
+            let timing = if *original_timing { "original" } else { "fast" };
+            settings.insert("replay_timing".to_string(), timing.to_string());
+            let mut client = radio(&settings)?;
+            // Without a network id, the frames of all the networks are replayed.
+            if settings.contains_key("network_id") {
+                tune(client.as_mut(), &settings)?;
+            }
+            sniff(client.as_mut())
+        }
+        Command::SimulateBoiler { mqtt, pairing } => {
+            simulate_boiler(&load_settings(cli)?, *mqtt, *pairing)
+        }
+        Command::History { query } => history::command(&load_settings(cli)?, query),
+    }
+}
+
+/// Reads the configuration file and the `APP_` environment variables, then the global options.
+fn load_settings(cli: &Cli) -> Result<HashMap<String, String>, String> {
+    let mut settings = Config::builder()
+        .add_source(config::File::with_name(&cli.config))
         // Eg.. `APP_DEBUG=1 ./target/app` would set the `debug` key
         .add_source(config::Environment::with_prefix("APP"))
         .build()
-        .unwrap()
-        .try_deserialize::<HashMap<String, String>>()
-        .unwrap();
-
-    let mut cli = rf_client(&settings).unwrap();
+        .and_then(|config| config.try_deserialize::<HashMap<String, String>>())
+        .map_err(|e| format!("Invalid configuration {}: {e}", cli.config))?;
+    cli.apply(&mut settings);
+    Ok(settings)
+}
 
-    // start_association(&cli, &rx);
-    // sendTemperatureExt(&cli, &rx, true);
-    let network_id = settings.get("network_id").unwrap().to_string();
+/// The configured radio, recording the frames and limiting the transmissions as configured.
+fn radio(settings: &HashMap<String, String>) -> Result<Box<dyn RFClient>, String> {
+    let client = rf::capture::wrap(rf_client(settings)?, settings)?;
+    rf::dutycycle::wrap(client, settings)
+}
 
-    cli.set_network_id(hex::decode(network_id).expect("network_id should be hex"))
-        .unwrap();
+/// Tunes the radio on the boiler's network.
+fn tune(client: &mut dyn RFClient, settings: &HashMap<String, String>) -> Result<(), String> {
+    let network_id = settings.get("network_id").ok_or("network_id is not set")?;
+    client.set_network_id(
+        hex::decode(network_id).map_err(|e| format!("invalid network_id {network_id}: {e}"))?,
+    )?;
     sleep(time::Duration::from_millis(1000));
+    Ok(())
+}
+
+/// Listens on the boiler's network, feeding the sinks and running the emulated devices.
+fn bridge(settings: &HashMap<String, String>) -> Result<(), String> {
+    let mut cli = radio(settings)?;
+    tune(cli.as_mut(), settings)?;
+
+    let mut state = state::HeatingState::default();
+    let mut sinks: Vec<Box<dyn Sink>> = vec![];
+    if let Some(home_assistant) = homeassistant::new(settings)? {
+        sinks.push(Box::new(home_assistant));
+    }
+    if let Some(publisher) = publisher::new(settings)? {
+        sinks.push(Box::new(publisher));
+    }
+    if let Some(timeseries) = timeseries::new(settings)? {
+        sinks.push(Box::new(timeseries));
+    }
+    if let Some(history) = history::new(settings)? {
+        sinks.push(Box::new(history));
+    }
+    metrics::start(settings)?;
+    let mut commands = commands::new(settings)?;
+    let mut sonde = sonde::new(settings)?;
+    let mut schedule = schedule::new(settings)?;
+    // Only the emulated devices are watched.
+    let mut watchdog = (sonde.is_some() || schedule.is_some())
+        .then(|| watchdog::new(settings))
+        .transpose()?;
+    let mut api = match api::new(settings)? {
+        Some((api, api_sink)) => {
+            sinks.push(Box::new(api_sink));
+            Some(api)
+        }
+        None => None,
+    };
+    let mut last_tick = time::Instant::now();
 
     loop {
-        let msg = cli.receive().unwrap();
-        let (metadata, x) =
-            frisquet::parse_data_from_str(hex::encode(msg.clone()).as_str()).unwrap();
-        // publish(&cli, &Listen {});
-        //
-        // println!("{x:?}");
-        println!("Received: {metadata:?} data: {x:?}");
-        // if (metadata.length == 8 && metadata.to_addr == 32) {
-        //     println!("Send announce message");
-        //     sendData(&cli, 32, 128, metadata.request_id, metadata.req_or_answer + 0x80, metadata.msg_type, SondePayload::SondeAssociationAnnounceMessage { data: vec![] });
-        // }
+        // Listening would wake the radio the watchdog put to sleep.
+        if watchdog.as_ref().is_some_and(|watchdog| watchdog.halted()) {
+            sleep(time::Duration::from_secs(1));
+        } else if let Some(msg) = cli.receive_timeout(time::Duration::from_secs(1))? {
+            handle_frame(&msg, &mut state, &mut sinks);
+        }
+        if let Some(commands) = &mut commands {
+            for command in commands.poll() {
+                if let Some(answer) = commands.execute(cli.as_mut(), &state, &command) {
+                    handle_frame(&answer, &mut state, &mut sinks);
+                }
+            }
+        }
+        if let (Some(sonde), Some(watchdog)) = (&mut sonde, &mut watchdog) {
+            if let Some(answer) = sonde.poll(cli.as_mut(), watchdog) {
+                handle_frame(&answer, &mut state, &mut sinks);
+            }
+        }
+        if let (Some(schedule), Some(watchdog)) = (&mut schedule, &mut watchdog) {
+            let now = chrono::Local::now().naive_local();
+            for answer in schedule.poll(cli.as_mut(), &state, now, watchdog) {
+                handle_frame(&answer, &mut state, &mut sinks);
+            }
+        }
+        if let Some(api) = &mut api {
+            for request in api.poll() {
+                if let Some(answer) = api.execute(cli.as_mut(), &state, request) {
+                    handle_frame(&answer, &mut state, &mut sinks);
+                }
+            }
+        }
+        if let Some(watchdog) = &mut watchdog {
+            watchdog.check();
+        }
+        // Now and then, to notice the devices going silent.
+        if last_tick.elapsed() >= time::Duration::from_secs(10) {
+            last_tick = time::Instant::now();
+            for sink in sinks.iter_mut() {
+                if let Err(e) = sink.tick(&state, time::SystemTime::now()) {
+                    log!(Error, "{} update failed: {e}", sink.name());
+                }
+            }
+        }
     }
 }
 
-fn rf_client(settings: &HashMap<String, String>) -> Result<Box<dyn RFClient>, String> {
-    if settings.get("mqtt_client").is_some() {
-        Ok(Box::new(rf::mqtt::new(&settings)?))
-    } else if settings.get("serial_port").is_some() {
-        Ok(Box::new(rf::serial::new(&settings)?))
-    } else {
-        Err("no client configured".to_string())
+/// Prints the frames heard on the network, until the end of a recorded input.
+fn sniff(client: &mut dyn RFClient) -> Result<(), String> {
+    let mut state = state::HeatingState::default();
+    loop {
+        match client.receive_timeout(time::Duration::from_secs(1)) {
+            Ok(Some(msg)) => handle_frame(&msg, &mut state, &mut []),
+            Ok(None) => {}
+            Err(_) if client.finished() => return Ok(()),
+            Err(e) => return Err(e),
+        }
     }
 }
 
-fn send_data<T>(
-    client: &mut dyn RFClient,
-    from: u8,
-    to: u8,
-    request_id: u16,
-    req_or_answer: u8,
-    msg_type: u8,
-    message: T,
-) where
-    T: for<'a> DekuEnumExt<'a, u8> + DekuWrite<u8> + Debug,
-{
-    let length = message.deku_id().unwrap();
-    let mut out = deku::bitvec::BitVec::with_capacity(length as usize);
-    message.write(&mut out, length).unwrap();
-    let mut out = out.into_vec();
-
-    let metadata = FrisquetMetadata {
-        length,
-        to_addr: to,
-        from_addr: from,
-        request_id: request_id,
-        req_or_answer: req_or_answer,
-        msg_type: msg_type,
-    };
-    let mut payload = metadata.to_bytes().unwrap();
-    payload.append(&mut out);
-    let data = hex::encode(payload.clone());
-    println!("send: {metadata:?}, {message:?}, payload: {data:?}");
+/// Sends the outdoor temperature as the sonde, once or every `sonde_interval_secs`.
+fn emulate_sonde(settings: &HashMap<String, String>, once: bool) -> Result<(), String> {
+    let mut sonde = sonde::new(settings)?.ok_or("outdoor_source is not set, see --temperature")?;
+    let mut watchdog = watchdog::new(settings)?;
+    let mut client = radio(settings)?;
+    tune(client.as_mut(), settings)?;
+    let mut state = state::HeatingState::default();
+    loop {
+        let answer = sonde.poll(client.as_mut(), &mut watchdog);
+        if let Some(answer) = &answer {
+            handle_frame(answer, &mut state, &mut []);
+        }
+        if once {
+            return answer.map(|_| ()).ok_or("Not acknowledged".to_string());
+        }
+        watchdog.check();
+        sleep(time::Duration::from_secs(1));
+    }
+}
 
-    client.send(payload).unwrap();
+fn satellite_frame(satellite: &SatelliteArgs, request_id: u16) -> Result<Vec<u8>, String> {
+    commands::setpoint_frame(
+        satellite.zone,
+        satellite.temperature,
+        satellite.consigne,
+        satellite.mode,
+        satellite.derogation,
+        request_id,
+    )
 }
 
-fn send_temperature_ext(client: &mut dyn RFClient, plug: bool) {
-    let network_id: Vec<u8> = vec![5, 218, 46, 226];
-    client.sleep().unwrap();
-    client.set_network_id(network_id).unwrap();
-    sleep(time::Duration::from_millis(1000));
+/// Sends the setpoint of a zone as its satellite and prints the boiler's answer.
+fn emulate_satellite(
+    settings: &HashMap<String, String>,
+    satellite: &SatelliteArgs,
+) -> Result<(), String> {
+    let frame = satellite_frame(satellite, 0x1c04)?;
+    let mut client = radio(settings)?;
+    tune(client.as_mut(), settings)?;
+    let (_, answer) = request::request(
+        client.as_mut(),
+        &frame,
+        &RequestOptions::from_settings(settings)?,
+    )
+    .map_err(|e| format!("Not acknowledged: {e}"))?;
+    handle_frame(&answer, &mut state::HeatingState::default(), &mut []);
+    Ok(())
+}
 
-    if plug {
-        send_data(
-            client,
-            32,
-            128,
-            33536,
-            1,
-            67,
-            SondePayload::SondeInitMessage { data: vec![0, 0] },
-        );
-        let msg = client.receive().unwrap();
-        let (metadata, x) = frisquet::parse_data_from_str(hex::encode(msg).as_str()).unwrap();
-        println!("Received: {metadata:?} data: {x:?}");
-    }
-    sleep(time::Duration::from_millis(3000));
-
-    send_data(
-        client,
-        32,
-        128,
-        6648,
-        1,
-        23,
-        SondePayload::SondeTemperatureMessage {
-            data: [156, 84, 0, 4, 160, 41, 0, 1, 2],
-            temperature: 190,
-        },
+/// Serves the gateway on a pseudo-terminal.
+#[cfg(unix)]
+fn serial_gateway(gateway: GatewayEmulator) -> Result<Box<dyn Any>, String> {
+    let gateway = emulator::serial::spawn(gateway)?;
+    log!(
+        Info,
+        "Serial gateway on {}, set serial_port to it",
+        gateway.path
     );
+    Ok(Box::new(gateway))
+}
 
-    let msg = client.receive().unwrap();
-    if let (metadata, FrisquetData::Chaudiere(data)) =
-        frisquet::parse_data_from_str(hex::encode(msg).as_str()).unwrap()
-    {
-        println!("Received: {metadata:?} data: {data:?}")
-    }
-    client.sleep().unwrap();
+#[cfg(not(unix))]
+fn serial_gateway(_gateway: GatewayEmulator) -> Result<Box<dyn Any>, String> {
+    Err("The serial gateway needs a pseudo-terminal, use --mqtt".to_string())
 }
 
-fn start_association(client: &mut dyn RFClient) {
-    let network_id: Vec<u8> = vec![0xff, 0xff, 0xff, 0xff];
-    client.set_network_id(network_id).unwrap();
-    println!("Setting network_id to ffffffff");
-
-    println!("Waiting broadcast networkId message");
-    let msg = client.receive().unwrap();
-    if let (metadata, FrisquetData::Chaudiere(data)) =
-        frisquet::parse_data_from_str(hex::encode(msg).as_str()).unwrap()
-    {
-        client.sleep().unwrap();
-        println!("Received a message {data:?}");
-
-        if let ChaudierePayload::ChaudiereAssociationBroadcast {
-            unknown: _,
-            network_id,
-        } = data
-        {
-            println!("This is a ChaudiereAssociationBroadcast message, will announce");
-            send_data(
-                client,
-                32,
-                128,
-                metadata.request_id,
-                metadata.req_or_answer + 0x80,
-                metadata.msg_type,
-                SondePayload::SondeAssociationAnnounceMessage { data: vec![] },
-            );
-            let network_id_str = hex::encode(network_id).to_string();
-            println!("Switch to networkId {network_id_str}");
-
-            client.set_network_id(Vec::from(network_id)).unwrap();
-            sleep(time::Duration::from_millis(200));
-            send_data(
-                client,
-                32,
-                128,
-                33536,
-                1,
-                67,
-                SondePayload::SondeInitMessage { data: vec![0, 0] },
+/// Serves a simulated boiler on `network_id` behind a serial or MQTT gateway, printing what it's told.
+fn simulate_boiler(
+    settings: &HashMap<String, String>,
+    mqtt: bool,
+    pairing: bool,
+) -> Result<(), String> {
+    let network_id = settings.get("network_id").ok_or("network_id is not set")?;
+    let network_id = hex::decode(network_id)
+        .ok()
+        .and_then(|bytes| <[u8; 4]>::try_from(bytes).ok())
+        .ok_or_else(|| format!("invalid network_id {network_id}"))?;
+    let boiler = Arc::new(Mutex::new(SimulatedBoiler::new(network_id)));
+    let gateway = GatewayEmulator::new(boiler.clone());
+    if pairing {
+        let broadcast = boiler.lock().unwrap().start_pairing();
+        gateway.air().lock().unwrap().push_back(broadcast);
+    }
+    // Serving until dropped.
+    let _gateway: Box<dyn Any> = if mqtt {
+        let mqtt_settings = rf::mqtt::MqttSettings::from_settings(settings)?;
+        let gateway = emulator::mqtt::spawn(gateway, &mqtt_settings)?;
+        log!(Info, "Gateway connected to {}", mqtt_settings.broker);
+        Box::new(gateway)
+    } else {
+        serial_gateway(gateway)?
+    };
+
+    let mut last = None;
+    loop {
+        sleep(time::Duration::from_secs(1));
+        let boiler = boiler.lock().unwrap();
+        let current = (boiler.outdoor_temperature, boiler.zones.clone());
+        if last.as_ref() != Some(&current) {
+            log!(
+                Info,
+                "Outdoor temperature: {:?}, zones: {:?}, paired: {:?}",
+                current.0,
+                current.1,
+                boiler.paired
             );
-            loop {
-                sleep(time::Duration::from_millis(1000));
-                send_data(
-                    client,
-                    32,    // from
-                    128,   // to
-                    34692, // request_id présent dans la trame de la chaudière
-                    1,     // +0x80 en cas de réponse
-                    23,    // le type du message en provenance de la chaudière
-                    SondePayload::SondeTemperatureMessage {
-                        data: [156, 84, 0, 4, 160, 41, 0, 1, 2], // des données qui semblent fixes
-                        temperature: 180,                        // la température exterieure, * 10
-                    },
-                );
-
-                let msg = client.receive().unwrap();
-
-                if let (metadata, FrisquetData::Chaudiere(data)) =
-                    frisquet::parse_data_from_str(hex::encode(msg).as_str()).unwrap()
-                {
-                    println!("Received: {metadata:?} data: {data:?}");
-                    return;
+            last = Some(current);
+        }
+    }
+}
+
+fn handle_frame(
+    msg: &rf::ReceivedFrame,
+    state: &mut state::HeatingState,
+    sinks: &mut [Box<dyn Sink>],
+) {
+    match frisquet::parse_data_from_str(hex::encode(&msg.data).as_str()) {
+        Ok((metadata, x)) => {
+            log!(Debug, "Raw: [{msg}] {}", hex::encode(&msg.data));
+            log!(Info, "Received: [{msg}] {metadata:?} data: {x:?}");
+            let devices = state.update(&metadata, &x, msg.timestamp);
+            metrics::record_frame(&metadata, &x, state, &devices, msg.timestamp);
+            for sink in sinks.iter_mut() {
+                if let Err(e) = sink.frame(msg, &metadata, &x, state, &devices) {
+                    log!(Error, "{} update failed: {e}", sink.name());
                 }
             }
         }
+        Err(e) => {
+            metrics::DECODE_ERRORS.inc();
+            log!(
+                Error,
+                "Failed to decode [{msg}] {}: {e}",
+                hex::encode(&msg.data)
+            );
+        }
+    }
+}
+
+fn rf_client(settings: &HashMap<String, String>) -> Result<Box<dyn RFClient>, String> {
+    // The MQTT settings are also used by the bridges, `transport` picks the radio explicitly.
+    if let Some(transport) = settings.get("transport") {
+        return match transport.as_str() {
+            "mqtt" => Ok(Box::new(rf::mqtt::new(settings)?)),
+            "serial" => Ok(Box::new(rf::serial::new(settings)?)),
+            "tcp" => Ok(Box::new(rf::tcp::new(settings)?)),
+            "sdr" => Ok(Box::new(rf::sdr::new(settings)?)),
+            "rtl433" => Ok(Box::new(rf::rtl433::new(settings)?)),
+            "replay" => Ok(Box::new(rf::replay::new(settings)?)),
+            transport => Err(format!("unknown transport {transport}")),
+        };
+    }
+    if settings.get("mqtt_client").is_some() {
+        Ok(Box::new(rf::mqtt::new(settings)?))
+    } else if rf::serial::is_configured(settings) {
+        Ok(Box::new(rf::serial::new(settings)?))
+    } else if settings.get("tcp_address").is_some() {
+        Ok(Box::new(rf::tcp::new(settings)?))
+    } else if settings.get("sdr_file").is_some() {
+        Ok(Box::new(rf::sdr::new(settings)?))
+    } else if settings.get("rtl433_input").is_some() {
+        Ok(Box::new(rf::rtl433::new(settings)?))
+    } else if settings.get("replay_file").is_some() {
+        Ok(Box::new(rf::replay::new(settings)?))
+    } else {
+        Err("no client configured".to_string())
     }
 }
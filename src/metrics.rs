@@ -0,0 +1,291 @@
+//! Metrics of the commander, registered in the default Prometheus registry.
+
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{LazyLock, Mutex};
+use std::thread;
+use std::time::SystemTime;
+
+use prometheus::{
+    register_counter, register_counter_vec, register_gauge, register_gauge_vec, Counter,
+    CounterVec, Encoder, Gauge, GaugeVec, TextEncoder,
+};
+use tiny_http::{Header, Response, Server};
+
+use crate::cli::log;
+use crate::frisquet::proto::{FrisquetData, FrisquetMetadata};
+use crate::state::{device_label, Device, HeatingState};
+
+/// Share of the duty-cycle window spent transmitting, per transport.
+pub static DUTY_CYCLE_RATIO: LazyLock<GaugeVec> = LazyLock::new(|| {
+    register_gauge_vec!(
+        "frisquet_duty_cycle_ratio",
+        "Share of the rolling window spent transmitting",
+        &["transport"]
+    )
+    .unwrap()
+});
+
+pub static AIRTIME_SECONDS: LazyLock<CounterVec> = LazyLock::new(|| {
+    register_counter_vec!(
+        "frisquet_airtime_seconds_total",
+        "Estimated time spent transmitting",
+        &["transport"]
+    )
+    .unwrap()
+});
+
+pub static DUTY_CYCLE_REFUSED: LazyLock<CounterVec> = LazyLock::new(|| {
+    register_counter_vec!(
+        "frisquet_duty_cycle_refused_total",
+        "Transmissions refused for exceeding the duty-cycle budget",
+        &["transport"]
+    )
+    .unwrap()
+});
+
+pub static ZONE_TEMPERATURE: LazyLock<GaugeVec> = LazyLock::new(|| {
+    register_gauge_vec!(
+        "frisquet_zone_temperature_celsius",
+        "Room temperature of the zone",
+        &["zone"]
+    )
+    .unwrap()
+});
+
+pub static ZONE_CONSIGNE: LazyLock<GaugeVec> = LazyLock::new(|| {
+    register_gauge_vec!(
+        "frisquet_zone_consigne_celsius",
+        "Target temperature of the zone",
+        &["zone"]
+    )
+    .unwrap()
+});
+
+/// Outdoor temperature, as sent by the sonde or as used by the boiler.
+pub static OUTDOOR_TEMPERATURE: LazyLock<GaugeVec> = LazyLock::new(|| {
+    register_gauge_vec!(
+        "frisquet_outdoor_temperature_celsius",
+        "Outdoor temperature",
+        &["source"]
+    )
+    .unwrap()
+});
+
+pub static BOILER_TEMPERATURE: LazyLock<Gauge> = LazyLock::new(|| {
+    register_gauge!(
+        "frisquet_boiler_temperature_celsius",
+        "Room temperature of the zone the boiler last answered"
+    )
+    .unwrap()
+});
+
+pub static BOILER_CONSIGNE: LazyLock<Gauge> = LazyLock::new(|| {
+    register_gauge!(
+        "frisquet_boiler_consigne_celsius",
+        "Target temperature of the zone the boiler last answered"
+    )
+    .unwrap()
+});
+
+pub static FRAMES: LazyLock<CounterVec> = LazyLock::new(|| {
+    register_counter_vec!(
+        "frisquet_frames_total",
+        "Decoded frames, per sending device and message variant",
+        &["device", "variant"]
+    )
+    .unwrap()
+});
+
+pub static DECODE_ERRORS: LazyLock<Counter> = LazyLock::new(|| {
+    register_counter!(
+        "frisquet_decode_errors_total",
+        "Frames that could not be decoded"
+    )
+    .unwrap()
+});
+
+pub static UNKNOWN_VARIANTS: LazyLock<CounterVec> = LazyLock::new(|| {
+    register_counter_vec!(
+        "frisquet_unknown_variants_total",
+        "Frames decoded as an unknown message, per sending device",
+        &["device"]
+    )
+    .unwrap()
+});
+
+pub static SEND_FAILURES: LazyLock<CounterVec> = LazyLock::new(|| {
+    register_counter_vec!(
+        "frisquet_send_failures_total",
+        "Frames the transport failed to send",
+        &["transport"]
+    )
+    .unwrap()
+});
+
+pub static REQUEST_TIMEOUTS: LazyLock<Counter> = LazyLock::new(|| {
+    register_counter!(
+        "frisquet_request_timeouts_total",
+        "Requests left unanswered after all their attempts"
+    )
+    .unwrap()
+});
+
+/// Faults of the failsafe watchdog, 1 while active.
+pub static FAILSAFE_ACTIVE: LazyLock<GaugeVec> = LazyLock::new(|| {
+    register_gauge_vec!(
+        "frisquet_failsafe_active",
+        "Whether a failsafe fault is active",
+        &["fault"]
+    )
+    .unwrap()
+});
+
+/// Computed from [`LAST_FRAMES`] when the metrics are rendered.
+static SECONDS_SINCE_LAST_FRAME: LazyLock<GaugeVec> = LazyLock::new(|| {
+    register_gauge_vec!(
+        "frisquet_seconds_since_last_frame",
+        "Time since the device was last heard",
+        &["device"]
+    )
+    .unwrap()
+});
+
+static LAST_FRAMES: LazyLock<Mutex<BTreeMap<Device, SystemTime>>> =
+    LazyLock::new(|| Mutex::new(BTreeMap::new()));
+
+/// Counts a decoded frame and updates the temperatures of the devices it changed.
+pub fn record_frame(
+    metadata: &FrisquetMetadata,
+    data: &FrisquetData,
+    state: &HeatingState,
+    devices: &[Device],
+    time: SystemTime,
+) {
+    let device = device_label(metadata.from_addr);
+    let variant = data
+        .variant()
+        .map(|(variant, _)| variant)
+        .unwrap_or_default();
+    FRAMES.with_label_values(&[&device, &variant]).inc();
+    if data.is_unknown() {
+        UNKNOWN_VARIANTS.with_label_values(&[&device]).inc();
+    }
+    if let Some(sender) = Device::from_addr(metadata.from_addr) {
+        LAST_FRAMES.lock().unwrap().insert(sender, time);
+    }
+
+    let set = |gauge: &Gauge, value: Option<f64>| {
+        if let Some(value) = value {
+            gauge.set(value);
+        }
+    };
+    for device in devices {
+        match device {
+            Device::Zone(zone) => {
+                let Some(zone_state) = state.zones.get(zone) else {
+                    continue;
+                };
+                let zone = zone.to_string();
+                set(
+                    &ZONE_TEMPERATURE.with_label_values(&[&zone]),
+                    zone_state.temperature,
+                );
+                set(
+                    &ZONE_CONSIGNE.with_label_values(&[&zone]),
+                    zone_state.consigne,
+                );
+            }
+            Device::Sonde => set(
+                &OUTDOOR_TEMPERATURE.with_label_values(&["sonde"]),
+                state.sonde.temperature,
+            ),
+            Device::Boiler => {
+                set(
+                    &OUTDOOR_TEMPERATURE.with_label_values(&["boiler"]),
+                    state.boiler.outdoor_temperature,
+                );
+                set(&BOILER_TEMPERATURE, state.boiler.temperature);
+                set(&BOILER_CONSIGNE, state.boiler.consigne);
+            }
+        }
+    }
+}
+
+/// Renders the registry in the Prometheus text format.
+pub fn render(now: SystemTime) -> String {
+    for (device, time) in LAST_FRAMES.lock().unwrap().iter() {
+        let age = now.duration_since(*time).unwrap_or_default();
+        SECONDS_SINCE_LAST_FRAME
+            .with_label_values(&[&device.name()])
+            .set(age.as_secs_f64());
+    }
+    let mut buffer = vec![];
+    if let Err(e) = TextEncoder::new().encode(&prometheus::gather(), &mut buffer) {
+        log!(Error, "Error encoding the metrics: {e}");
+    }
+    String::from_utf8(buffer).unwrap_or_default()
+}
+
+/// Serves `/metrics` on `metrics_listen`, when set.
+pub fn start(settings: &HashMap<String, String>) -> Result<(), String> {
+    let Some(listen) = settings.get("metrics_listen") else {
+        return Ok(());
+    };
+    let server = Server::http(listen).map_err(|e| format!("Error listening on {listen}: {e}"))?;
+    log!(
+        Info,
+        "Metrics exported on http://{}/metrics",
+        server.server_addr()
+    );
+    thread::spawn(move || {
+        for request in server.incoming_requests() {
+            let response = if request.url() == "/metrics" {
+                Response::from_string(render(SystemTime::now())).with_header(content_type())
+            } else {
+                Response::from_string("not found").with_status_code(404)
+            };
+            if let Err(e) = request.respond(response) {
+                log!(Error, "Error answering metrics request: {e}");
+            }
+        }
+    });
+    Ok(())
+}
+
+/// Content type of [`render`]'s output.
+pub fn content_type() -> Header {
+    Header::from_bytes("Content-Type", TextEncoder::new().format_type()).unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::frisquet;
+    use std::time::Duration;
+
+    #[test]
+    fn test_record_frame() {
+        let mut state = HeatingState::default();
+        let time = SystemTime::now();
+        for raw in [
+            "118020ba4001179c540004a029000102005c",
+            "310880194881172A050A0000230423171012000000C000BE002500C600C604F6000000000000000004F60000000000000000",
+        ] {
+            let (metadata, data) = frisquet::parse_data_from_str(raw).unwrap();
+            let devices = state.update(&metadata, &data, time);
+            record_frame(&metadata, &data, &state, &devices, time);
+        }
+        assert_eq!(OUTDOOR_TEMPERATURE.with_label_values(&["sonde"]).get(), 9.2);
+        assert_eq!(ZONE_TEMPERATURE.with_label_values(&["1"]).get(), 19.2);
+        assert!(
+            FRAMES
+                .with_label_values(&["boiler", "ChaudiereSetTemperatureMessageResponse"])
+                .get()
+                >= 1.0
+        );
+
+        let metrics = render(time + Duration::from_secs(30));
+        assert!(metrics.contains("frisquet_seconds_since_last_frame{device=\"sonde\"} 30"));
+        assert!(metrics.contains("frisquet_outdoor_temperature_celsius{source=\"sonde\"} 9.2"));
+    }
+}
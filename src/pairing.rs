@@ -0,0 +1,109 @@
+use std::time::{Duration, Instant};
+
+use crate::cli::log;
+use crate::frisquet;
+use crate::frisquet::proto::chaudiere::ChaudierePayload;
+use crate::frisquet::proto::sonde::SondePayload;
+use crate::frisquet::proto::FrisquetData;
+use crate::rf::request::{self, RequestOptions};
+use crate::rf::RFClient;
+use crate::state::{BOILER_ADDR, SONDE_ADDR};
+
+/// Network the boiler broadcasts its network id on while pairing.
+pub const PAIRING_NETWORK_ID: [u8; 4] = [0xff; 4];
+
+const MSG_TYPE_INIT: u8 = 67;
+
+/// Pairs as the sonde: waits for the boiler's association broadcast, announces
+/// the sonde and initializes it on the boiler's network.
+///
+/// Returns the boiler's network id, the client staying tuned on it. The pairing
+/// must be started on the boiler before `timeout` runs out.
+pub fn pair_sonde(
+    client: &mut dyn RFClient,
+    timeout: Duration,
+    options: &RequestOptions,
+) -> Result<[u8; 4], String> {
+    client.set_network_id(PAIRING_NETWORK_ID.to_vec())?;
+    log!(Info, "Waiting for the boiler's association broadcast");
+
+    let deadline = Instant::now() + timeout;
+    let (metadata, network_id) = loop {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        if remaining.is_zero() {
+            return Err(format!("No association broadcast within {timeout:?}"));
+        }
+        let Some(frame) = client.receive_timeout(remaining)? else {
+            continue;
+        };
+        if let Ok((
+            metadata,
+            FrisquetData::Chaudiere(ChaudierePayload::ChaudiereAssociationBroadcast {
+                network_id,
+                ..
+            }),
+        )) = frisquet::parse_data_from_str(&hex::encode(&frame.data))
+        {
+            break (metadata, network_id);
+        }
+    };
+
+    log!(
+        Info,
+        "Announcing the sonde to network {}",
+        hex::encode(network_id)
+    );
+    let (_, announce) = frisquet::encode_data(
+        SONDE_ADDR,
+        BOILER_ADDR,
+        metadata.request_id,
+        metadata.req_or_answer | 0x80,
+        metadata.msg_type,
+        &SondePayload::SondeAssociationAnnounceMessage { data: vec![] },
+    )
+    .map_err(|e| e.to_string())?;
+    client.send(announce)?;
+
+    client.set_network_id(network_id.to_vec())?;
+    let (_, init) = frisquet::encode_data(
+        SONDE_ADDR,
+        BOILER_ADDR,
+        metadata.request_id.wrapping_add(1),
+        1,
+        MSG_TYPE_INIT,
+        &SondePayload::SondeInitMessage { data: vec![0, 0] },
+    )
+    .map_err(|e| e.to_string())?;
+    request::request(client, &init, options).map_err(|e| format!("Sonde init failed: {e}"))?;
+    Ok(network_id)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rf::testing::FakeClient;
+
+    #[test]
+    fn test_pair_sonde() {
+        // Unrelated traffic, the broadcast, then the boiler's answer to the init.
+        let mut client = FakeClient::with_frames(&[
+            "118020ba4001179c540004a029000102005c",
+            "0b0080d3c802410405d7199e",
+            "062080d3c98143",
+        ]);
+        let options = RequestOptions {
+            timeout: Duration::from_millis(10),
+            retries: 0,
+            backoff: Duration::ZERO,
+        };
+        assert_eq!(
+            pair_sonde(&mut client, Duration::from_secs(1), &options),
+            Ok([5, 215, 25, 158])
+        );
+        assert_eq!(client.sent(), vec!["068020d3c88241", "088020d3c901430000"]);
+        assert_eq!(
+            client.radio.lock().unwrap().network_id,
+            Some(vec![5, 215, 25, 158])
+        );
+    }
+}
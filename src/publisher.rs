@@ -0,0 +1,222 @@
+//! Publishes the decoded frames and the per-field state to MQTT, for flows consuming them directly.
+
+extern crate paho_mqtt as mqtt;
+
+use std::collections::HashMap;
+
+use mqtt::Message;
+use serde_json::{json, Value};
+
+use crate::frisquet::proto::{FrisquetData, FrisquetMetadata};
+use crate::rf::mqtt::MqttSettings;
+use crate::rf::ReceivedFrame;
+use crate::settings::parse_setting;
+use crate::sink::Sink;
+use crate::state::{Device, HeatingState};
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct PublisherSettings {
+    pub prefix: String,
+    pub qos: i32,
+    /// Whether the per-field topics are retained, the decoded frames never are.
+    pub retain: bool,
+}
+
+impl PublisherSettings {
+    /// Reads the `mqtt_publish_*` settings, publishing being enabled by `mqtt_publish`.
+    pub fn from_settings(
+        settings: &HashMap<String, String>,
+    ) -> Result<Option<PublisherSettings>, String> {
+        if settings.get("mqtt_publish").map(|v| v.as_str()) != Some("true") {
+            return Ok(None);
+        }
+        let qos = parse_setting(settings, "mqtt_publish_qos")?.unwrap_or(0);
+        if !(0..=2).contains(&qos) {
+            return Err(format!("invalid mqtt_publish_qos: {qos}"));
+        }
+        Ok(Some(PublisherSettings {
+            prefix: settings
+                .get("mqtt_publish_prefix")
+                .cloned()
+                .unwrap_or_else(|| "frisquet".to_string()),
+            qos,
+            retain: parse_setting(settings, "mqtt_publish_retain")?.unwrap_or(true),
+        }))
+    }
+}
+
+/// Returns the topic and JSON of a decoded frame.
+pub fn decoded_message(
+    settings: &PublisherSettings,
+    frame: &ReceivedFrame,
+    metadata: &FrisquetMetadata,
+    data: &FrisquetData,
+) -> Option<(String, Value)> {
+    let device = Device::from_addr(metadata.from_addr)?;
+    let (variant, fields) = data.variant()?;
+    let timestamp = chrono::DateTime::<chrono::Utc>::from(frame.timestamp);
+    Some((
+        format!("{}/decoded/{}/{variant}", settings.prefix, device.name()),
+        json!({
+            "timestamp": timestamp,
+            "gateway": frame.gateway,
+            "rssi": frame.rssi,
+            "lqi": frame.lqi,
+            "raw": hex::encode(&frame.data),
+            "metadata": metadata,
+            "payload": fields,
+        }),
+    ))
+}
+
+/// Returns the per-field topics of a device and their values, as plain text.
+pub fn field_messages(
+    settings: &PublisherSettings,
+    state: &HeatingState,
+    device: Device,
+) -> Vec<(String, String)> {
+    let value = match device {
+        Device::Zone(zone) => json!(state.zones.get(&zone)),
+        Device::Sonde => json!(state.sonde),
+        Device::Boiler => json!(state.boiler),
+    };
+    let Value::Object(fields) = value else {
+        return vec![];
+    };
+    fields
+        .into_iter()
+        .filter_map(|(field, value)| {
+            let value = match value {
+                Value::Null => return None,
+                Value::String(value) => value,
+                value => value.to_string(),
+            };
+            Some((
+                format!("{}/{}/{field}", settings.prefix, device.name()),
+                value,
+            ))
+        })
+        .collect()
+}
+
+pub struct Publisher {
+    client: mqtt::Client,
+    settings: PublisherSettings,
+}
+
+/// Connects to the broker when publishing is enabled.
+pub fn new(settings: &HashMap<String, String>) -> Result<Option<Publisher>, String> {
+    let Some(publisher_settings) = PublisherSettings::from_settings(settings)? else {
+        return Ok(None);
+    };
+    let mqtt_settings = MqttSettings::from_settings(settings)?;
+    let (client, _) = crate::rf::mqtt::connect(
+        &mqtt_settings.with_client_id(format!("{}-publisher", mqtt_settings.client_id)),
+    )?;
+    Ok(Some(Publisher {
+        client,
+        settings: publisher_settings,
+    }))
+}
+
+impl Publisher {
+    fn publish(
+        &self,
+        topic: String,
+        payload: impl Into<Vec<u8>>,
+        retain: bool,
+    ) -> Result<(), String> {
+        let message = if retain {
+            Message::new_retained(&topic, payload, self.settings.qos)
+        } else {
+            Message::new(&topic, payload, self.settings.qos)
+        };
+        self.client
+            .publish(message)
+            .map_err(|e| format!("Error publishing to {topic}: {e}"))
+    }
+
+    pub fn publish_frame(
+        &self,
+        frame: &ReceivedFrame,
+        metadata: &FrisquetMetadata,
+        data: &FrisquetData,
+    ) -> Result<(), String> {
+        match decoded_message(&self.settings, frame, metadata, data) {
+            Some((topic, message)) => self.publish(topic, message.to_string(), false),
+            None => Ok(()),
+        }
+    }
+
+    /// Publishes the fields of the updated devices.
+    pub fn publish_state(&self, state: &HeatingState, devices: &[Device]) -> Result<(), String> {
+        for &device in devices {
+            for (topic, value) in field_messages(&self.settings, state, device) {
+                self.publish(topic, value, self.settings.retain)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Sink for Publisher {
+    fn name(&self) -> &str {
+        "MQTT publisher"
+    }
+
+    fn frame(
+        &mut self,
+        frame: &ReceivedFrame,
+        metadata: &FrisquetMetadata,
+        data: &FrisquetData,
+        state: &HeatingState,
+        devices: &[Device],
+    ) -> Result<(), String> {
+        self.publish_frame(frame, metadata, data)?;
+        self.publish_state(state, devices)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::frisquet;
+    use std::time::SystemTime;
+
+    fn settings() -> PublisherSettings {
+        PublisherSettings {
+            prefix: "frisquet".to_string(),
+            qos: 0,
+            retain: true,
+        }
+    }
+
+    #[test]
+    fn test_decoded_message() {
+        let raw = "118020ba4001179c540004a029000102005c";
+        let (metadata, data) = frisquet::parse_data_from_str(raw).unwrap();
+        let frame = ReceivedFrame::new(hex::decode(raw).unwrap(), "test");
+        let (topic, message) = decoded_message(&settings(), &frame, &metadata, &data).unwrap();
+        assert_eq!(topic, "frisquet/decoded/sonde/SondeTemperatureMessage");
+        assert_eq!(message["payload"]["temperature"], 92);
+        assert_eq!(message["metadata"]["request_id"], 47680);
+        assert_eq!(message["raw"], raw);
+    }
+
+    #[test]
+    fn test_field_messages() {
+        let mut state = HeatingState::default();
+        let (metadata, data) =
+            frisquet::parse_data_from_str("17800819E40117A0290015A02F00040800B200AA002400C6")
+                .unwrap();
+        state.update(&metadata, &data, SystemTime::now());
+        let fields: HashMap<String, String> = field_messages(&settings(), &state, Device::Zone(1))
+            .into_iter()
+            .collect();
+        assert_eq!(fields["frisquet/zone1/temperature"], "17.8");
+        assert_eq!(fields["frisquet/zone1/consigne"], "17.0");
+        assert_eq!(fields["frisquet/zone1/mode"], "reduit");
+        assert_eq!(fields["frisquet/zone1/derogation"], "false");
+        assert!(field_messages(&settings(), &state, Device::Sonde).is_empty());
+    }
+}
@@ -0,0 +1,242 @@
+use std::collections::HashMap;
+use std::fs::OpenOptions;
+use std::io::{BufWriter, Write};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+
+use crate::cli::log;
+use crate::rf::{unix_seconds, GatewayState, RFClient, ReceivedFrame};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Direction {
+    Rx,
+    Tx,
+}
+
+/// One line of a capture file.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct CaptureRecord {
+    /// Seconds since the unix epoch.
+    pub timestamp: f64,
+    pub direction: Direction,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub network_id: Option<String>,
+    pub data: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub gateway: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rssi: Option<i16>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub lqi: Option<u8>,
+}
+
+impl CaptureRecord {
+    pub fn time(&self) -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs_f64(self.timestamp.max(0.0))
+    }
+
+    pub fn to_frame(&self) -> Result<ReceivedFrame, String> {
+        let data = hex::decode(&self.data).map_err(|e| format!("{}: {e}", self.data))?;
+        Ok(ReceivedFrame {
+            data,
+            timestamp: self.time(),
+            rssi: self.rssi,
+            lqi: self.lqi,
+            gateway: self.gateway.clone().unwrap_or_else(|| "replay".to_string()),
+        })
+    }
+}
+
+/// Wraps a client, recording every frame received or sent as a JSON line.
+pub struct RecordingClient {
+    inner: Box<dyn RFClient>,
+    writer: Box<dyn Write>,
+    network_id: Option<Vec<u8>>,
+}
+
+/// Wraps the client in a recorder when `capture_file` is set.
+pub fn wrap(
+    client: Box<dyn RFClient>,
+    settings: &HashMap<String, String>,
+) -> Result<Box<dyn RFClient>, String> {
+    match settings.get("capture_file") {
+        Some(path) => {
+            let file = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)
+                .map_err(|e| format!("Failed to open {path}: {e}"))?;
+            Ok(Box::new(RecordingClient::new(
+                client,
+                Box::new(BufWriter::new(file)),
+            )))
+        }
+        None => Ok(client),
+    }
+}
+
+impl RecordingClient {
+    pub fn new(inner: Box<dyn RFClient>, writer: Box<dyn Write>) -> RecordingClient {
+        RecordingClient {
+            inner,
+            writer,
+            network_id: None,
+        }
+    }
+
+    /// Appends a record, the radio working on when the capture can't be written.
+    fn record(&mut self, record: CaptureRecord) {
+        let result = serde_json::to_vec(&record)
+            .map_err(std::io::Error::from)
+            .and_then(|mut line| {
+                line.push(b'\n');
+                self.writer.write_all(&line)?;
+                self.writer.flush()
+            });
+        if let Err(e) = result {
+            log!(Error, "Failed to write capture: {e}");
+        }
+    }
+
+    fn record_received(&mut self, frame: &ReceivedFrame) {
+        self.record(CaptureRecord {
+            timestamp: unix_seconds(frame.timestamp),
+            direction: Direction::Rx,
+            network_id: self.network_id.as_ref().map(hex::encode),
+            data: hex::encode(&frame.data),
+            gateway: Some(frame.gateway.clone()),
+            rssi: frame.rssi,
+            lqi: frame.lqi,
+        })
+    }
+}
+
+impl RFClient for RecordingClient {
+    fn set_network_id(&mut self, network_id: Vec<u8>) -> Result<(), String> {
+        self.inner.set_network_id(network_id.clone())?;
+        self.network_id = Some(network_id);
+        Ok(())
+    }
+
+    fn receive(&mut self) -> Result<ReceivedFrame, String> {
+        let frame = self.inner.receive()?;
+        self.record_received(&frame);
+        Ok(frame)
+    }
+
+    fn receive_timeout(&mut self, timeout: Duration) -> Result<Option<ReceivedFrame>, String> {
+        let frame = self.inner.receive_timeout(timeout)?;
+        if let Some(frame) = &frame {
+            self.record_received(frame);
+        }
+        Ok(frame)
+    }
+
+    fn send(&mut self, payload: Vec<u8>) -> Result<(), String> {
+        let data = hex::encode(&payload);
+        self.inner.send(payload)?;
+        self.record(CaptureRecord {
+            timestamp: unix_seconds(SystemTime::now()),
+            direction: Direction::Tx,
+            network_id: self.network_id.as_ref().map(hex::encode),
+            data,
+            gateway: None,
+            rssi: None,
+            lqi: None,
+        });
+        Ok(())
+    }
+
+    fn sleep(&mut self) -> Result<(), String> {
+        self.inner.sleep()
+    }
+
+    fn gateway_state(&self) -> GatewayState {
+        self.inner.gateway_state()
+    }
+
+    fn finished(&self) -> bool {
+        self.inner.finished()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{BufReader, Cursor};
+
+    use super::*;
+    use crate::rf::replay::ReplayClient;
+    use crate::rf::testing::FakeClient;
+
+    #[test]
+    fn test_record_and_replay() {
+        let path =
+            std::env::temp_dir().join(format!("frisquet-capture-{}.jsonl", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let settings = HashMap::from([(
+            "capture_file".to_string(),
+            path.to_string_lossy().to_string(),
+        )]);
+
+        let fake = FakeClient::with_frames(&[
+            "118020ba4001179c540004a029000102005c",
+            "0f2080ba408117082304051131172803",
+        ]);
+        let mut client = wrap(Box::new(fake.clone()), &settings).unwrap();
+        client.set_network_id(vec![0x05, 0xda, 0x2e, 0xe2]).unwrap();
+        let first = client.receive().unwrap();
+        client
+            .send(hex::decode("088020830001430000").unwrap())
+            .unwrap();
+        client.receive().unwrap();
+        assert_eq!(fake.sent(), vec!["088020830001430000"]);
+        drop(client);
+
+        let capture = std::fs::read_to_string(&path).unwrap();
+        let records: Vec<CaptureRecord> = capture
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(
+            records.iter().map(|r| r.direction).collect::<Vec<_>>(),
+            vec![Direction::Rx, Direction::Tx, Direction::Rx]
+        );
+        assert_eq!(records[0].network_id.as_deref(), Some("05da2ee2"));
+        assert_eq!(records[1].data, "088020830001430000");
+        assert_eq!(records[0].to_frame().unwrap().data, first.data);
+
+        let mut replay =
+            ReplayClient::from_reader(Box::new(BufReader::new(Cursor::new(capture))), false);
+        replay.set_network_id(vec![0x05, 0xda, 0x2e, 0xe2]).unwrap();
+        assert_eq!(replay.receive().unwrap().data, first.data);
+        assert_eq!(
+            replay.receive().unwrap().data,
+            hex::decode("0f2080ba408117082304051131172803").unwrap()
+        );
+        assert!(replay.receive().is_err());
+    }
+
+    struct FullDisk;
+
+    impl Write for FullDisk {
+        fn write(&mut self, _buf: &[u8]) -> std::io::Result<usize> {
+            Err(std::io::Error::other("No space left on device"))
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_capture_errors_are_not_radio_errors() {
+        let fake = FakeClient::with_frames(&["118020ba4001179c540004a029000102005c"]);
+        let mut client = RecordingClient::new(Box::new(fake.clone()), Box::new(FullDisk));
+        assert!(client.receive().is_ok());
+        assert!(client.send(vec![0x06, 0x80, 0x20]).is_ok());
+        assert_eq!(fake.sent(), vec!["068020"]);
+    }
+}
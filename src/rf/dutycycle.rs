@@ -0,0 +1,281 @@
+use std::collections::{HashMap, VecDeque};
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+
+use crate::cli::log;
+use crate::metrics;
+use crate::rf::{GatewayState, RFClient, ReceivedFrame};
+use crate::settings::parse_setting;
+
+/// Bytes sent around each frame: preamble, sync word (the network id) and CRC.
+const FRAME_OVERHEAD: usize = 4 + 4 + 2;
+
+/// Estimated time on the air of a frame of `length` bytes.
+pub fn airtime(length: usize, bitrate: u32) -> Duration {
+    Duration::from_secs_f64(((length + FRAME_OVERHEAD) * 8) as f64 / bitrate as f64)
+}
+
+/// What to do with a transmission exceeding the budget.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Policy {
+    Refuse,
+    /// Wait for older transmissions to leave the window.
+    Delay,
+}
+
+/// Airtime spent over a rolling window, against a budget.
+#[derive(Debug)]
+pub struct DutyCycle {
+    window: Duration,
+    budget: Duration,
+    transmissions: VecDeque<(Instant, Duration)>,
+}
+
+impl DutyCycle {
+    pub fn new(window: Duration, ratio: f64) -> DutyCycle {
+        DutyCycle {
+            window,
+            budget: window.mul_f64(ratio),
+            transmissions: VecDeque::new(),
+        }
+    }
+
+    fn expire(&mut self, now: Instant) {
+        while let Some((time, _)) = self.transmissions.front() {
+            if now.duration_since(*time) < self.window {
+                break;
+            }
+            self.transmissions.pop_front();
+        }
+    }
+
+    pub fn used(&mut self, now: Instant) -> Duration {
+        self.expire(now);
+        self.transmissions.iter().map(|(_, airtime)| *airtime).sum()
+    }
+
+    /// Share of the window spent transmitting.
+    pub fn ratio(&mut self, now: Instant) -> f64 {
+        self.used(now).as_secs_f64() / self.window.as_secs_f64()
+    }
+
+    /// Returns how long to wait before `airtime` fits in the budget,
+    /// or None when it never will.
+    pub fn wait_for(&mut self, airtime: Duration, now: Instant) -> Option<Duration> {
+        if airtime > self.budget {
+            return None;
+        }
+        let mut used = self.used(now);
+        let mut wait = Duration::ZERO;
+        for (time, spent) in &self.transmissions {
+            if used + airtime <= self.budget {
+                break;
+            }
+            used -= *spent;
+            wait = (*time + self.window).saturating_duration_since(now);
+        }
+        Some(wait)
+    }
+
+    pub fn record(&mut self, airtime: Duration, now: Instant) {
+        self.transmissions.push_back((now, airtime));
+    }
+}
+
+/// Wraps a client, keeping its transmissions within the duty-cycle budget.
+pub struct DutyCycleClient {
+    inner: Box<dyn RFClient>,
+    duty_cycle: DutyCycle,
+    policy: Policy,
+    bitrate: u32,
+    transport: String,
+}
+
+/// Wraps the client with the `duty_cycle_*` settings, 1% of an hour by default.
+pub fn wrap(
+    client: Box<dyn RFClient>,
+    settings: &HashMap<String, String>,
+) -> Result<Box<dyn RFClient>, String> {
+    let setting = |key: &str, default: f64| -> Result<f64, String> {
+        Ok(parse_setting(settings, key)?.unwrap_or(default))
+    };
+    let percent = setting("duty_cycle_percent", 1.0)?;
+    if !(0.0..=100.0).contains(&percent) {
+        return Err(format!("invalid duty_cycle_percent: {percent}"));
+    }
+    let window = setting("duty_cycle_window_secs", 3600.0)?;
+    let window = Duration::try_from_secs_f64(window)
+        .ok()
+        .filter(|window| !window.is_zero())
+        .ok_or_else(|| format!("invalid duty_cycle_window_secs: {window}, must be positive"))?;
+    let bitrate = match setting(
+        "duty_cycle_bitrate",
+        crate::rf::sdr::FRISQUET_BITRATE as f64,
+    )? {
+        bitrate if bitrate >= 1.0 && bitrate <= u32::MAX as f64 => bitrate as u32,
+        bitrate => {
+            return Err(format!(
+                "invalid duty_cycle_bitrate: {bitrate}, must be positive"
+            ))
+        }
+    };
+    let policy = match settings.get("duty_cycle_policy").map(|p| p.as_str()) {
+        None | Some("delay") => Policy::Delay,
+        Some("refuse") => Policy::Refuse,
+        Some(policy) => return Err(format!("invalid duty_cycle_policy: {policy}")),
+    };
+    Ok(Box::new(DutyCycleClient {
+        inner: client,
+        duty_cycle: DutyCycle::new(window, percent / 100.0),
+        policy,
+        bitrate,
+        transport: settings
+            .get("gateway_id")
+            .cloned()
+            .unwrap_or_else(|| "default".to_string()),
+    }))
+}
+
+impl RFClient for DutyCycleClient {
+    fn set_network_id(&mut self, network_id: Vec<u8>) -> Result<(), String> {
+        self.inner.set_network_id(network_id)
+    }
+
+    fn receive(&mut self) -> Result<ReceivedFrame, String> {
+        self.inner.receive()
+    }
+
+    fn receive_timeout(&mut self, timeout: Duration) -> Result<Option<ReceivedFrame>, String> {
+        self.inner.receive_timeout(timeout)
+    }
+
+    fn send(&mut self, payload: Vec<u8>) -> Result<(), String> {
+        let airtime = airtime(payload.len(), self.bitrate);
+        match self.duty_cycle.wait_for(airtime, Instant::now()) {
+            Some(wait) if wait.is_zero() => {}
+            Some(wait) if self.policy == Policy::Delay => {
+                log!(
+                    Info,
+                    "Duty cycle budget used, delaying transmission by {wait:?}"
+                );
+                sleep(wait);
+            }
+            _ => {
+                metrics::DUTY_CYCLE_REFUSED
+                    .with_label_values(&[&self.transport])
+                    .inc();
+                return Err(format!(
+                    "Duty cycle budget exceeded: {:.3}% used",
+                    self.duty_cycle.ratio(Instant::now()) * 100.0
+                ));
+            }
+        }
+
+        if let Err(e) = self.inner.send(payload) {
+            metrics::SEND_FAILURES
+                .with_label_values(&[&self.transport])
+                .inc();
+            return Err(e);
+        }
+        let now = Instant::now();
+        self.duty_cycle.record(airtime, now);
+        metrics::AIRTIME_SECONDS
+            .with_label_values(&[&self.transport])
+            .inc_by(airtime.as_secs_f64());
+        metrics::DUTY_CYCLE_RATIO
+            .with_label_values(&[&self.transport])
+            .set(self.duty_cycle.ratio(now));
+        Ok(())
+    }
+
+    fn sleep(&mut self) -> Result<(), String> {
+        self.inner.sleep()
+    }
+
+    fn gateway_state(&self) -> GatewayState {
+        self.inner.gateway_state()
+    }
+
+    fn finished(&self) -> bool {
+        self.inner.finished()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rf::testing::FakeClient;
+
+    #[test]
+    fn test_airtime() {
+        // A sonde temperature frame, 18 bytes at 25 kbps.
+        assert_eq!(airtime(18, 25000), Duration::from_micros(8960));
+    }
+
+    #[test]
+    fn test_rolling_window() {
+        let start = Instant::now();
+        let mut duty_cycle = DutyCycle::new(Duration::from_secs(100), 0.01);
+        let airtime = Duration::from_millis(400);
+        assert_eq!(duty_cycle.wait_for(Duration::from_secs(2), start), None);
+
+        duty_cycle.record(airtime, start);
+        duty_cycle.record(airtime, start + Duration::from_secs(10));
+        assert_eq!(
+            duty_cycle.wait_for(airtime, start + Duration::from_secs(20)),
+            Some(Duration::from_secs(80))
+        );
+        assert!((duty_cycle.ratio(start + Duration::from_secs(20)) - 0.008).abs() < 1e-9);
+
+        // The first transmission left the window.
+        let later = start + Duration::from_secs(100);
+        assert_eq!(duty_cycle.wait_for(airtime, later), Some(Duration::ZERO));
+        assert_eq!(duty_cycle.used(later), airtime);
+    }
+
+    #[test]
+    fn test_refuse() {
+        let fake = FakeClient::default();
+        let settings = HashMap::from([
+            ("duty_cycle_policy".to_string(), "refuse".to_string()),
+            ("duty_cycle_window_secs".to_string(), "10".to_string()),
+            ("gateway_id".to_string(), "test_refuse".to_string()),
+        ]);
+        // 100ms of budget, 8.96ms per frame.
+        let mut client = wrap(Box::new(fake.clone()), &settings).unwrap();
+        for _ in 0..11 {
+            client
+                .send(hex::decode("118020ba4001179c540004a029000102005c").unwrap())
+                .unwrap();
+        }
+        assert!(client.send(vec![0; 18]).is_err());
+        assert_eq!(fake.sent().len(), 11);
+        assert_eq!(
+            metrics::DUTY_CYCLE_REFUSED
+                .with_label_values(&["test_refuse"])
+                .get(),
+            1.0
+        );
+    }
+
+    #[test]
+    fn test_settings_errors() {
+        for (key, value) in [
+            ("duty_cycle_percent", "101"),
+            ("duty_cycle_window_secs", "-1"),
+            ("duty_cycle_window_secs", "0"),
+            ("duty_cycle_window_secs", "inf"),
+            ("duty_cycle_window_secs", "NaN"),
+            ("duty_cycle_bitrate", "0"),
+            ("duty_cycle_bitrate", "-25000"),
+            ("duty_cycle_bitrate", "inf"),
+            ("duty_cycle_policy", "drop"),
+        ] {
+            let settings = HashMap::from([(key.to_string(), value.to_string())]);
+            let error = wrap(Box::new(FakeClient::default()), &settings)
+                .err()
+                .unwrap_or_else(|| panic!("{key} = {value} was accepted"));
+            assert!(error.starts_with(&format!("invalid {key}")), "{error}");
+        }
+    }
+}
@@ -0,0 +1,97 @@
+//! Frame layout on air: preamble, the network id as sync word, then a length
+//! byte followed by that many bytes. The extracted frames start with the
+//! length byte, like the ones reported by the gateways.
+
+/// Shortest frame worth decoding: the length byte must cover the metadata.
+const MIN_FRAME_LENGTH: u8 = 6;
+
+pub fn bytes_to_bits(bytes: &[u8]) -> Vec<bool> {
+    bytes
+        .iter()
+        .flat_map(|byte| (0..8).rev().map(move |bit| byte >> bit & 1 == 1))
+        .collect()
+}
+
+/// Converts a hex string to bits, keeping only the first `len` bits when given.
+pub fn bits_from_hex(data: &str, len: Option<usize>) -> Option<Vec<bool>> {
+    let mut bits = bytes_to_bits(&hex::decode(data).ok()?);
+    if let Some(len) = len {
+        bits.truncate(len);
+    }
+    Some(bits)
+}
+
+fn bits_to_byte(bits: &[bool]) -> u8 {
+    bits.iter().fold(0, |byte, &bit| byte << 1 | bit as u8)
+}
+
+/// Finds every sync word in a bit stream and returns the frames following it.
+///
+/// Both polarities are tried, as the demodulated mark and space frequencies
+/// may be swapped depending on the receiver.
+pub fn extract_frames(bits: &[bool], sync_word: &[u8]) -> Vec<Vec<u8>> {
+    let frames = extract_frames_with_polarity(bits, sync_word);
+    if !frames.is_empty() {
+        return frames;
+    }
+    let inverted: Vec<bool> = bits.iter().map(|bit| !bit).collect();
+    extract_frames_with_polarity(&inverted, sync_word)
+}
+
+fn extract_frames_with_polarity(bits: &[bool], sync_word: &[u8]) -> Vec<Vec<u8>> {
+    let sync = bytes_to_bits(sync_word);
+    let mut frames = vec![];
+    let mut position = 0;
+    while position + sync.len() + 8 <= bits.len() {
+        if bits[position..position + sync.len()] != sync[..] {
+            position += 1;
+            continue;
+        }
+        let start = position + sync.len();
+        let length = bits_to_byte(&bits[start..start + 8]);
+        let end = start + 8 * (length as usize + 1);
+        if length < MIN_FRAME_LENGTH || end > bits.len() {
+            position += 1;
+            continue;
+        }
+        frames.push(bits[start..end].chunks(8).map(bits_to_byte).collect());
+        position = end;
+    }
+    frames
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_extract_frames() {
+        let frame = hex::decode("0b0080d3c802410405d7199e").unwrap();
+        let network_id = [0x05, 0xda, 0x2e, 0xe2];
+
+        // Noise, preamble and a frame shifted by 3 bits.
+        let mut bits = vec![true, false, false];
+        bits.extend(bytes_to_bits(&[0xaa, 0xaa, 0xaa]));
+        bits.extend(bytes_to_bits(&network_id));
+        bits.extend(bytes_to_bits(&frame));
+        bits.extend(bytes_to_bits(&[0x12, 0x34]));
+        assert_eq!(extract_frames(&bits, &network_id), vec![frame.clone()]);
+
+        let inverted: Vec<bool> = bits.iter().map(|bit| !bit).collect();
+        assert_eq!(extract_frames(&inverted, &network_id), vec![frame.clone()]);
+
+        assert!(extract_frames(&bits, &[0xff, 0xff, 0xff, 0xff]).is_empty());
+        // Truncated frame.
+        assert!(extract_frames(&bits[..bits.len() - 40], &network_id).is_empty());
+    }
+
+    #[test]
+    fn test_bits_from_hex() {
+        assert_eq!(
+            bits_from_hex("a5", None),
+            Some(vec![true, false, true, false, false, true, false, true])
+        );
+        assert_eq!(bits_from_hex("a5", Some(3)), Some(vec![true, false, true]));
+        assert_eq!(bits_from_hex("a", None), None);
+    }
+}
@@ -1,9 +1,99 @@
+use std::fmt;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+pub mod capture;
+pub mod dutycycle;
+pub mod framing;
 pub mod mqtt;
+pub mod replay;
+pub mod request;
+pub mod rtl433;
+pub mod scheduler;
+pub mod sdr;
 pub mod serial;
+pub mod tcp;
+#[cfg(test)]
+pub mod testing;
+
+/// A frame heard by a gateway, along with the radio metadata it reported.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ReceivedFrame {
+    pub data: Vec<u8>,
+    pub timestamp: SystemTime,
+    pub rssi: Option<i16>,
+    pub lqi: Option<u8>,
+    pub gateway: String,
+}
+
+impl ReceivedFrame {
+    pub fn new(data: Vec<u8>, gateway: impl Into<String>) -> ReceivedFrame {
+        ReceivedFrame {
+            data,
+            timestamp: SystemTime::now(),
+            rssi: None,
+            lqi: None,
+            gateway: gateway.into(),
+        }
+    }
+}
+
+impl fmt::Display for ReceivedFrame {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let ts = self
+            .timestamp
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        write!(
+            f,
+            "{}.{:03} gateway={}",
+            ts.as_secs(),
+            ts.subsec_millis(),
+            self.gateway
+        )?;
+        if let Some(rssi) = self.rssi {
+            write!(f, " rssi={rssi}")?;
+        }
+        if let Some(lqi) = self.lqi {
+            write!(f, " lqi={lqi}")?;
+        }
+        Ok(())
+    }
+}
+
+/// Seconds since the epoch, as the frames are timestamped in captures and the history.
+pub fn unix_seconds(time: SystemTime) -> f64 {
+    time.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs_f64()
+}
+
+/// Whether the remote gateway is reachable, as far as the transport can tell.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GatewayState {
+    Online,
+    Offline,
+    Unknown,
+}
 
 pub trait RFClient {
     fn set_network_id(&mut self, network_id: Vec<u8>) -> Result<(), String>;
-    fn receive(&mut self) -> Result<Vec<u8>, String>;
+    fn receive(&mut self) -> Result<ReceivedFrame, String>;
+
+    /// Waits at most `timeout` for a frame, `None` meaning that nothing was heard.
+    ///
+    /// Transports which can't time out wait for the next frame.
+    fn receive_timeout(&mut self, _timeout: Duration) -> Result<Option<ReceivedFrame>, String> {
+        self.receive().map(Some)
+    }
     fn send(&mut self, payload: Vec<u8>) -> Result<(), String>;
     fn sleep(&mut self) -> Result<(), String>;
+
+    fn gateway_state(&self) -> GatewayState {
+        GatewayState::Unknown
+    }
+
+    /// Whether a recorded input was read to its end, receiving failing from then on.
+    fn finished(&self) -> bool {
+        false
+    }
 }
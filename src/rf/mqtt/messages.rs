@@ -2,9 +2,94 @@ extern crate serde;
 
 use serde::{Deserialize, Serialize};
 
-#[derive(Serialize, Deserialize)]
+/// Messages published by the gateway on its receive topic.
+#[typetag::serde(tag = "type")]
+pub trait GatewayMessage {
+    fn into_event(self: Box<Self>) -> GatewayEvent;
+}
+
+/// A decoded gateway message.
+#[derive(Debug, PartialEq)]
+pub enum GatewayEvent {
+    Data(DataMessage),
+    Ack(AckMessage),
+    Status(StatusMessage),
+    Error(ErrorMessage),
+}
+
+/// Parses a message from the gateway.
+///
+/// Messages without a `type` are data messages, as sent by older gateways.
+pub fn parse_gateway_message(payload: &str) -> Result<GatewayEvent, String> {
+    let value: serde_json::Value = serde_json::from_str(payload).map_err(|e| e.to_string())?;
+    if value.get("type").is_none() {
+        return serde_json::from_value::<DataMessage>(value)
+            .map(GatewayEvent::Data)
+            .map_err(|e| e.to_string());
+    }
+    serde_json::from_value::<Box<dyn GatewayMessage>>(value)
+        .map(|message| message.into_event())
+        .map_err(|e| e.to_string())
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
 pub struct DataMessage {
     pub data: String,
+    #[serde(default)]
+    pub rssi: Option<i16>,
+    #[serde(default)]
+    pub lqi: Option<u8>,
+    #[serde(default)]
+    pub gateway: Option<String>,
+}
+
+#[typetag::serde(name = "DATA")]
+impl GatewayMessage for DataMessage {
+    fn into_event(self: Box<Self>) -> GatewayEvent {
+        GatewayEvent::Data(*self)
+    }
+}
+
+/// Acknowledges a command, `command` being its type, e.g. `SET_NETWORK_ID`.
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct AckMessage {
+    pub command: String,
+}
+
+#[typetag::serde(name = "ACK")]
+impl GatewayMessage for AckMessage {
+    fn into_event(self: Box<Self>) -> GatewayEvent {
+        GatewayEvent::Ack(*self)
+    }
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct StatusMessage {
+    /// `online` or `offline`.
+    pub state: String,
+    #[serde(default)]
+    pub message: Option<String>,
+}
+
+#[typetag::serde(name = "STATUS")]
+impl GatewayMessage for StatusMessage {
+    fn into_event(self: Box<Self>) -> GatewayEvent {
+        GatewayEvent::Status(*self)
+    }
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct ErrorMessage {
+    pub message: String,
+    #[serde(default)]
+    pub command: Option<String>,
+}
+
+#[typetag::serde(name = "ERROR")]
+impl GatewayMessage for ErrorMessage {
+    fn into_event(self: Box<Self>) -> GatewayEvent {
+        GatewayEvent::Error(*self)
+    }
 }
 
 #[typetag::serde(tag = "type")]
@@ -37,3 +122,58 @@ pub struct SendData {
 
 #[typetag::serde(name = "SEND")]
 impl CommandMessage for SendData {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_gateway_messages() {
+        assert_eq!(
+            parse_gateway_message(r#"{"type":"DATA","data":"06802020948241","rssi":-80}"#),
+            Ok(GatewayEvent::Data(DataMessage {
+                data: "06802020948241".to_string(),
+                rssi: Some(-80),
+                lqi: None,
+                gateway: None,
+            }))
+        );
+        assert_eq!(
+            parse_gateway_message(r#"{"data":"06802020948241"}"#),
+            Ok(GatewayEvent::Data(DataMessage {
+                data: "06802020948241".to_string(),
+                rssi: None,
+                lqi: None,
+                gateway: None,
+            }))
+        );
+        assert_eq!(
+            parse_gateway_message(r#"{"type":"ACK","command":"LISTEN"}"#),
+            Ok(GatewayEvent::Ack(AckMessage {
+                command: "LISTEN".to_string()
+            }))
+        );
+        assert_eq!(
+            parse_gateway_message(r#"{"type":"STATUS","state":"online"}"#),
+            Ok(GatewayEvent::Status(StatusMessage {
+                state: "online".to_string(),
+                message: None
+            }))
+        );
+        assert_eq!(
+            parse_gateway_message(r#"{"type":"ERROR","message":"radio not ready"}"#),
+            Ok(GatewayEvent::Error(ErrorMessage {
+                message: "radio not ready".to_string(),
+                command: None
+            }))
+        );
+    }
+
+    #[test]
+    fn test_parse_invalid_gateway_messages() {
+        assert!(parse_gateway_message("online").is_err());
+        assert!(parse_gateway_message(r#"{"temperature":19.5}"#).is_err());
+        assert!(parse_gateway_message(r#"{"type":"REBOOT","data":"06"}"#).is_err());
+        assert!(parse_gateway_message(r#"{"type":"DATA"}"#).is_err());
+    }
+}
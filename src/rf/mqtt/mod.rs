@@ -2,81 +2,351 @@ extern crate paho_mqtt as mqtt;
 
 use std::collections::HashMap;
 use std::result::Result;
-use std::{env, process, time::Duration};
+use std::thread::sleep;
+use std::time::{Duration, Instant};
 
-use hex;
 use mqtt::{Message, Receiver};
-// use serde_json::Result;
 
-use crate::rf::mqtt::messages::{CommandMessage, Listen, SendData, SetNetworkId, Sleep};
-use crate::rf::RFClient;
+use crate::cli::log;
+use crate::rf::mqtt::messages::{
+    CommandMessage, GatewayEvent, Listen, SendData, SetNetworkId, Sleep,
+};
+use crate::rf::{GatewayState, RFClient, ReceivedFrame};
+use crate::settings::parse_setting;
 pub mod messages;
 
-pub struct MqttClient {
-    client: mqtt::Client,
-    rx: Receiver<Option<Message>>,
+const RECONNECT_INITIAL_DELAY: Duration = Duration::from_millis(500);
+
+/// Broker connection and topics, read from the `mqtt_*` settings.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MqttSettings {
+    pub broker: String,
+    pub client_id: String,
+    /// Topic the gateway publishes received frames to.
+    pub receive_topic: String,
+    /// Topic the gateway listens to for commands.
+    pub command_topic: String,
+    /// Topic where the gateway publishes `online`/`offline`, usually its last will.
+    pub gateway_status_topic: Option<String>,
+    pub qos: i32,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    pub ca_file: Option<String>,
+    pub client_cert: Option<String>,
+    pub client_key: Option<String>,
+    pub client_key_password: Option<String>,
+    pub tls_verify: bool,
+    pub keep_alive: Duration,
+    /// Topic where this client publishes `online`, and `offline` as its last will.
+    pub will_topic: Option<String>,
+    pub reconnect_max_delay: Duration,
 }
 
-pub fn new(settings: &HashMap<String, String>) -> Result<MqttClient, String> {
-    let host = env::args()
-        .nth(1)
-        .unwrap_or_else(|| settings.get("broker").unwrap().to_string());
+impl MqttSettings {
+    pub fn from_settings(settings: &HashMap<String, String>) -> Result<MqttSettings, String> {
+        let required = |key: &str| {
+            settings
+                .get(key)
+                .cloned()
+                .ok_or_else(|| format!("{key} required"))
+        };
+        let qos = parse_setting(settings, "mqtt_qos")?.unwrap_or(0);
+        if !(0..=2).contains(&qos) {
+            return Err(format!("invalid mqtt_qos: {qos}"));
+        }
+
+        Ok(MqttSettings {
+            broker: required("broker")?,
+            client_id: required("mqtt_client")?,
+            receive_topic: required("mqtt_frisquet_topic")?,
+            command_topic: settings
+                .get("mqtt_command_topic")
+                .cloned()
+                .unwrap_or_else(|| "frisquet/command".to_string()),
+            gateway_status_topic: settings.get("mqtt_gateway_status_topic").cloned(),
+            qos,
+            username: settings.get("mqtt_username").cloned(),
+            password: settings.get("mqtt_password").cloned(),
+            ca_file: settings.get("mqtt_ca_file").cloned(),
+            client_cert: settings.get("mqtt_client_cert").cloned(),
+            client_key: settings.get("mqtt_client_key").cloned(),
+            client_key_password: settings.get("mqtt_client_key_password").cloned(),
+            tls_verify: parse_setting(settings, "mqtt_tls_verify")?.unwrap_or(true),
+            keep_alive: Duration::from_secs(
+                parse_setting(settings, "mqtt_keep_alive_secs")?.unwrap_or(20),
+            ),
+            will_topic: settings.get("mqtt_will_topic").cloned(),
+            reconnect_max_delay: Duration::from_secs(
+                parse_setting(settings, "mqtt_reconnect_max_secs")?.unwrap_or(30),
+            ),
+        })
+    }
+
+    /// Returns a copy connecting with another client id, for the other components sharing the broker.
+    pub fn with_client_id(&self, client_id: impl Into<String>) -> MqttSettings {
+        MqttSettings {
+            client_id: client_id.into(),
+            ..self.clone()
+        }
+    }
 
+    fn uses_tls(&self) -> bool {
+        self.broker.starts_with("ssl://")
+            || self.broker.starts_with("mqtts://")
+            || self.ca_file.is_some()
+            || self.client_cert.is_some()
+    }
+
+    fn connect_options(&self) -> Result<mqtt::ConnectOptions, String> {
+        let mut builder = mqtt::ConnectOptionsBuilder::new();
+        builder
+            .keep_alive_interval(self.keep_alive)
+            .clean_session(true);
+
+        if let Some(username) = &self.username {
+            builder.user_name(username);
+        }
+        if let Some(password) = &self.password {
+            builder.password(password);
+        }
+        if let Some(topic) = &self.will_topic {
+            builder.will_message(Message::new_retained(topic, "offline", self.qos));
+        }
+        if self.uses_tls() {
+            let mut ssl = mqtt::SslOptionsBuilder::new();
+            ssl.enable_server_cert_auth(self.tls_verify)
+                .verify(self.tls_verify);
+            if let Some(ca_file) = &self.ca_file {
+                ssl.trust_store(ca_file).map_err(|e| e.to_string())?;
+            }
+            if let Some(cert) = &self.client_cert {
+                ssl.key_store(cert).map_err(|e| e.to_string())?;
+            }
+            if let Some(key) = &self.client_key {
+                ssl.private_key(key).map_err(|e| e.to_string())?;
+            }
+            if let Some(password) = &self.client_key_password {
+                ssl.private_key_password(password);
+            }
+            builder.ssl_options(ssl.finalize());
+        }
+        Ok(builder.finalize())
+    }
+}
+
+/// Connects to the broker and starts consuming, without subscribing to anything yet.
+pub fn connect(
+    settings: &MqttSettings,
+) -> Result<(mqtt::Client, Receiver<Option<Message>>), String> {
     // Define the set of options for the create.
-    // Use an ID for a persistent session.
     let create_opts = mqtt::CreateOptionsBuilder::new()
-        .server_uri(host)
-        .client_id(settings.get("mqtt_client").unwrap().to_string())
+        .server_uri(&settings.broker)
+        .client_id(&settings.client_id)
         .finalize();
 
     // Create a client.
-    let client = mqtt::Client::new(create_opts).unwrap_or_else(|err| {
-        println!("Error creating the client: {:?}", err);
-        process::exit(1);
-    });
+    let client = mqtt::Client::new(create_opts)
+        .map_err(|e| format!("Error creating the client: {:?}", e))?;
     let rx: Receiver<Option<Message>> = client.start_consuming();
-    // Define the set of options for the connection.
-    let conn_opts = mqtt::ConnectOptionsBuilder::new()
-        .keep_alive_interval(Duration::from_secs(20))
-        .clean_session(true)
-        .finalize();
 
     // Connect and wait for it to complete or fail.
-    if let Err(e) = client.connect(conn_opts) {
+    if let Err(e) = client.connect(settings.connect_options()?) {
         return Err(format!("Unable to connect:\n\t{:?}", e));
     }
+    if let Some(topic) = &settings.will_topic {
+        client
+            .publish(Message::new_retained(topic, "online", settings.qos))
+            .map_err(|e| format!("Error publishing online state: {:?}", e))?;
+    }
+    Ok((client, rx))
+}
 
-    if let Err(e) = client.subscribe(settings.get("mqtt_frisquet_topic").unwrap(), 0) {
-        return Err(format!("Error subscribes topics: {:?}", e));
+/// Reconnects with an increasing delay, until the broker accepts the connection.
+pub fn reconnect(client: &mqtt::Client, settings: &MqttSettings) {
+    let mut delay = RECONNECT_INITIAL_DELAY;
+    loop {
+        log!(Info, "MQTT connection lost, reconnecting in {delay:?}");
+        sleep(delay);
+        match client.reconnect() {
+            Ok(_) => {
+                if let Some(topic) = &settings.will_topic {
+                    let _ = client.publish(Message::new_retained(topic, "online", settings.qos));
+                }
+                return;
+            }
+            Err(e) => {
+                log!(Error, "MQTT reconnection failed: {e}");
+                delay = (delay * 2).min(settings.reconnect_max_delay);
+            }
+        }
     }
+}
 
-    Ok(MqttClient {
-        client: client,
-        rx: rx,
-    })
+pub struct MqttClient {
+    client: mqtt::Client,
+    rx: Receiver<Option<Message>>,
+    settings: MqttSettings,
+    gateway: String,
+    gateway_state: GatewayState,
+    // State restored when the gateway comes back online.
+    network_id: Option<Vec<u8>>,
+    listening: bool,
+}
+
+pub fn new(settings: &HashMap<String, String>) -> Result<MqttClient, String> {
+    let mqtt_settings = MqttSettings::from_settings(settings)?;
+    let (client, rx) = connect(&mqtt_settings)?;
+
+    let client = MqttClient {
+        client,
+        rx,
+        gateway: settings
+            .get("gateway_id")
+            .cloned()
+            .unwrap_or_else(|| format!("mqtt:{}", mqtt_settings.receive_topic)),
+        settings: mqtt_settings,
+        gateway_state: GatewayState::Unknown,
+        network_id: None,
+        listening: false,
+    };
+    client.subscribe()?;
+    Ok(client)
 }
 
 impl MqttClient {
-    fn publish(&self, value: &dyn CommandMessage) -> Result<(), String> {
+    fn subscribe(&self) -> Result<(), String> {
+        let mut topics = vec![self.settings.receive_topic.as_str()];
+        if let Some(topic) = &self.settings.gateway_status_topic {
+            topics.push(topic);
+        }
+        for topic in topics {
+            if let Err(e) = self.client.subscribe(topic, self.settings.qos) {
+                return Err(format!("Error subscribes topics: {:?}", e));
+            }
+        }
+        Ok(())
+    }
+
+    /// Publishes a command, reconnecting first when the connection was lost.
+    fn publish(&mut self, value: &dyn CommandMessage) -> Result<(), String> {
+        if !self.client.is_connected() {
+            self.reconnect()?;
+        }
         let json = serde_json::to_vec(value).unwrap();
 
-        if let Err(e) = self
-            .client
-            .publish(Message::new("frisquet/command", json, 0))
-        {
-            return Err(format!("Error subscribes topics: {:?}", e));
+        if let Err(e) = self.client.publish(Message::new(
+            &self.settings.command_topic,
+            json,
+            self.settings.qos,
+        )) {
+            return Err(format!("Error publishing command: {:?}", e));
+        }
+        Ok(())
+    }
+
+    fn reconnect(&mut self) -> Result<(), String> {
+        reconnect(&self.client, &self.settings);
+        self.subscribe()?;
+        if self.listening {
+            self.publish(&Listen {})?;
         }
         Ok(())
     }
 
-    fn await_message(&self) -> String {
+    fn update_gateway_state(&mut self, payload: &str) -> Result<(), String> {
+        let state = match payload.trim().to_ascii_lowercase().as_str() {
+            "online" => GatewayState::Online,
+            "offline" => GatewayState::Offline,
+            _ => {
+                log!(Debug, "Unknown gateway state: {payload}");
+                return Ok(());
+            }
+        };
+        if state == self.gateway_state {
+            return Ok(());
+        }
+        log!(Info, "Gateway {} is {state:?}", self.gateway);
+        let was_offline = self.gateway_state == GatewayState::Offline;
+        self.gateway_state = state;
+
+        // The gateway lost its state while offline.
+        if was_offline && state == GatewayState::Online {
+            if let Some(network_id) = self.network_id.clone() {
+                self.publish(&SetNetworkId {
+                    network_id: hex::encode(network_id),
+                })?;
+            }
+            if self.listening {
+                self.publish(&Listen {})?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Waits for the next frame until the deadline, handling the other gateway messages on the way.
+    fn await_frame(&mut self, deadline: Option<Instant>) -> Result<Option<ReceivedFrame>, String> {
         loop {
-            for msg in self.rx.iter() {
-                if let Some(msg) = msg {
-                    let data: messages::DataMessage =
-                        serde_json::from_str(msg.payload_str().as_ref()).unwrap();
-                    return data.data;
+            let received = match deadline {
+                Some(deadline) => {
+                    match self
+                        .rx
+                        .recv_timeout(deadline.saturating_duration_since(Instant::now()))
+                    {
+                        Err(e) if e.is_timeout() => return Ok(None),
+                        received => received.map_err(|e| e.to_string()),
+                    }
+                }
+                None => self.rx.recv().map_err(|e| e.to_string()),
+            };
+            let msg = match received {
+                Ok(Some(msg)) => msg,
+                // The consumer yields None when the connection is lost.
+                Ok(None) => {
+                    // A command may have reconnected already.
+                    if !self.client.is_connected() {
+                        self.reconnect()?;
+                    }
+                    continue;
                 }
+                Err(e) => return Err(format!("MQTT consumer stopped: {e}")),
+            };
+            if Some(msg.topic()) == self.settings.gateway_status_topic.as_deref() {
+                self.update_gateway_state(&msg.payload_str())?;
+                continue;
+            }
+
+            match messages::parse_gateway_message(&msg.payload_str()) {
+                Ok(GatewayEvent::Data(message)) => match hex::decode(&message.data) {
+                    Ok(data) => {
+                        let mut frame = ReceivedFrame::new(
+                            data,
+                            message.gateway.unwrap_or(self.gateway.clone()),
+                        );
+                        frame.rssi = message.rssi;
+                        frame.lqi = message.lqi;
+                        return Ok(Some(frame));
+                    }
+                    Err(e) => log!(Debug, "Ignoring gateway data {:?}: {e}", message.data),
+                },
+                Ok(GatewayEvent::Ack(ack)) => {
+                    log!(Debug, "Gateway acknowledged {}", ack.command)
+                }
+                Ok(GatewayEvent::Status(status)) => {
+                    if let Some(message) = &status.message {
+                        log!(Info, "Gateway: {message}");
+                    }
+                    self.update_gateway_state(&status.state)?
+                }
+                Ok(GatewayEvent::Error(error)) => match error.command {
+                    Some(command) => log!(Error, "Gateway error on {command}: {}", error.message),
+                    None => log!(Error, "Gateway error: {}", error.message),
+                },
+                Err(e) => log!(
+                    Debug,
+                    "Ignoring message on {}: {e}: {}",
+                    msg.topic(),
+                    msg.payload_str()
+                ),
             }
         }
     }
@@ -85,13 +355,23 @@ impl MqttClient {
 impl RFClient for MqttClient {
     fn set_network_id(&mut self, network_id: Vec<u8>) -> Result<(), String> {
         self.publish(&SetNetworkId {
-            network_id: hex::encode(network_id),
-        })
+            network_id: hex::encode(&network_id),
+        })?;
+        self.network_id = Some(network_id);
+        Ok(())
     }
 
-    fn receive(&mut self) -> Result<Vec<u8>, String> {
+    fn receive(&mut self) -> Result<ReceivedFrame, String> {
         self.publish(&Listen {})?;
-        hex::decode(self.await_message()).map_err(|e| e.to_string())
+        self.listening = true;
+        self.await_frame(None)
+            .map(|frame| frame.expect("listening without deadline"))
+    }
+
+    fn receive_timeout(&mut self, timeout: Duration) -> Result<Option<ReceivedFrame>, String> {
+        self.publish(&Listen {})?;
+        self.listening = true;
+        self.await_frame(Some(Instant::now() + timeout))
     }
 
     fn send(&mut self, payload: Vec<u8>) -> Result<(), String> {
@@ -101,6 +381,107 @@ impl RFClient for MqttClient {
     }
 
     fn sleep(&mut self) -> Result<(), String> {
-        self.publish(&Sleep {})
+        self.publish(&Sleep {})?;
+        self.listening = false;
+        Ok(())
+    }
+
+    fn gateway_state(&self) -> GatewayState {
+        if !self.client.is_connected() {
+            return GatewayState::Offline;
+        }
+        self.gateway_state
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn settings(entries: &[(&str, &str)]) -> HashMap<String, String> {
+        entries
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn test_settings_defaults() {
+        let mqtt_settings = MqttSettings::from_settings(&settings(&[
+            ("broker", "tcp://localhost:1883"),
+            ("mqtt_client", "rust_publish"),
+            ("mqtt_frisquet_topic", "frisquet/receive"),
+        ]))
+        .unwrap();
+        assert_eq!(mqtt_settings.command_topic, "frisquet/command");
+        assert_eq!(mqtt_settings.qos, 0);
+        assert_eq!(mqtt_settings.keep_alive, Duration::from_secs(20));
+        assert!(mqtt_settings.tls_verify);
+        assert!(!mqtt_settings.uses_tls());
+    }
+
+    #[test]
+    fn test_settings_tls() {
+        let mqtt_settings = MqttSettings::from_settings(&settings(&[
+            ("broker", "ssl://broker:8883"),
+            ("mqtt_client", "rust_publish"),
+            ("mqtt_frisquet_topic", "gw/receive"),
+            ("mqtt_command_topic", "gw/command"),
+            ("mqtt_qos", "1"),
+            ("mqtt_username", "frisquet"),
+            ("mqtt_client_cert", "/etc/frisquet/client.crt"),
+        ]))
+        .unwrap();
+        assert_eq!(mqtt_settings.command_topic, "gw/command");
+        assert_eq!(mqtt_settings.qos, 1);
+        assert_eq!(mqtt_settings.username.as_deref(), Some("frisquet"));
+        assert!(mqtt_settings.uses_tls());
+    }
+
+    #[test]
+    fn test_settings_errors() {
+        assert!(MqttSettings::from_settings(&settings(&[("broker", "tcp://localhost")])).is_err());
+        assert!(MqttSettings::from_settings(&settings(&[
+            ("broker", "tcp://localhost:1883"),
+            ("mqtt_client", "rust_publish"),
+            ("mqtt_frisquet_topic", "frisquet/receive"),
+            ("mqtt_qos", "3"),
+        ]))
+        .is_err());
+    }
+
+    #[test]
+    fn test_publish_reconnects() {
+        let broker = crate::emulator::broker::start("127.0.0.1:0").unwrap();
+        let settings = settings(&[
+            ("broker", &format!("tcp://{}", broker.address)),
+            ("mqtt_client", "reconnect-test"),
+            ("mqtt_frisquet_topic", "frisquet/receive"),
+        ]);
+        let mut client = new(&settings).unwrap();
+        client.set_network_id(vec![0x05, 0xda, 0x2e, 0xe2]).unwrap();
+
+        broker.stop();
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while client.client.is_connected() {
+            assert!(Instant::now() < deadline, "still connected");
+            sleep(Duration::from_millis(50));
+        }
+        let broker = crate::emulator::broker::start(&broker.address).unwrap();
+        let (observer, rx) = connect(
+            &MqttSettings::from_settings(&settings)
+                .unwrap()
+                .with_client_id("reconnect-test-observer"),
+        )
+        .unwrap();
+        observer.subscribe("frisquet/command", 0).unwrap();
+
+        client.send(vec![0x08, 0x80]).unwrap();
+        let message = rx
+            .recv_timeout(Duration::from_secs(5))
+            .unwrap()
+            .expect("a command");
+        assert!(message.payload_str().contains("0880"));
+        drop(broker);
     }
 }
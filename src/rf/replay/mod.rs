@@ -0,0 +1,160 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+use std::thread::sleep;
+use std::time::{Instant, SystemTime};
+
+use crate::cli::log;
+use crate::rf::capture::{CaptureRecord, Direction};
+use crate::rf::{RFClient, ReceivedFrame};
+
+/// Plays back the frames received in a capture file.
+pub struct ReplayClient {
+    reader: Box<dyn BufRead>,
+    /// Waits between frames as long as they were apart in the capture.
+    original_timing: bool,
+    /// First frame replayed, in capture time and in real time.
+    start: Option<(SystemTime, Instant)>,
+    network_id: Option<String>,
+    finished: bool,
+}
+
+pub fn new(settings: &HashMap<String, String>) -> Result<ReplayClient, String> {
+    let path = settings.get("replay_file").ok_or("replay_file required")?;
+    let file = File::open(path).map_err(|e| format!("Failed to open {path}: {e}"))?;
+    let original_timing = match settings.get("replay_timing").map(String::as_str) {
+        None | Some("fast") => false,
+        Some("original") => true,
+        Some(timing) => return Err(format!("invalid replay_timing: {timing}")),
+    };
+    Ok(ReplayClient::from_reader(
+        Box::new(BufReader::new(file)),
+        original_timing,
+    ))
+}
+
+impl ReplayClient {
+    pub fn from_reader(reader: Box<dyn BufRead>, original_timing: bool) -> ReplayClient {
+        ReplayClient {
+            reader,
+            original_timing,
+            start: None,
+            network_id: None,
+            finished: false,
+        }
+    }
+
+    fn wait_for(&mut self, time: SystemTime) {
+        let (capture_start, replay_start) = *self.start.get_or_insert((time, Instant::now()));
+        if !self.original_timing {
+            return;
+        }
+        if let Ok(offset) = time.duration_since(capture_start) {
+            if let Some(remaining) = offset.checked_sub(replay_start.elapsed()) {
+                sleep(remaining);
+            }
+        }
+    }
+}
+
+impl RFClient for ReplayClient {
+    /// Only the frames captured on this network are replayed.
+    fn set_network_id(&mut self, network_id: Vec<u8>) -> Result<(), String> {
+        self.network_id = Some(hex::encode(network_id));
+        Ok(())
+    }
+
+    fn receive(&mut self) -> Result<ReceivedFrame, String> {
+        loop {
+            let mut line = String::new();
+            if self
+                .reader
+                .read_line(&mut line)
+                .map_err(|e| e.to_string())?
+                == 0
+            {
+                self.finished = true;
+                return Err("End of capture".to_string());
+            }
+            if line.trim().is_empty() {
+                continue;
+            }
+            let record: CaptureRecord = match serde_json::from_str(&line) {
+                Ok(record) => record,
+                Err(e) => {
+                    log!(Debug, "Ignoring capture line: {e}: {}", line.trim());
+                    continue;
+                }
+            };
+            if record.direction != Direction::Rx {
+                continue;
+            }
+            if let (Some(expected), Some(network_id)) = (&self.network_id, &record.network_id) {
+                if !expected.eq_ignore_ascii_case(network_id) {
+                    continue;
+                }
+            }
+
+            let frame = record.to_frame()?;
+            self.wait_for(frame.timestamp);
+            return Ok(frame);
+        }
+    }
+
+    fn send(&mut self, payload: Vec<u8>) -> Result<(), String> {
+        log!(
+            Debug,
+            "Replay: dropping sent frame {}",
+            hex::encode(payload)
+        );
+        Ok(())
+    }
+
+    fn sleep(&mut self) -> Result<(), String> {
+        Ok(())
+    }
+
+    fn finished(&self) -> bool {
+        self.finished
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+    use std::time::Duration;
+
+    use super::*;
+
+    const CAPTURE: &str = r#"{"timestamp":1680709763.0,"direction":"rx","network_id":"05da2ee2","data":"118020ba4001179c540004a029000102005c","rssi":-70}
+{"timestamp":1680709763.1,"direction":"rx","network_id":"01020304","data":"0b0080d3c802410405d7199e"}
+not json
+{"timestamp":1680709763.2,"direction":"rx","network_id":"05DA2EE2","data":"0f2080ba408117082304051131172803"}
+"#;
+
+    #[test]
+    fn test_network_filter() {
+        let mut replay = ReplayClient::from_reader(Box::new(Cursor::new(CAPTURE)), false);
+        replay.set_network_id(vec![0x05, 0xda, 0x2e, 0xe2]).unwrap();
+        let frame = replay.receive().unwrap();
+        assert_eq!(frame.rssi, Some(-70));
+        assert_eq!(frame.gateway, "replay");
+        assert_eq!(
+            replay.receive().unwrap().data,
+            hex::decode("0f2080ba408117082304051131172803").unwrap()
+        );
+        assert!(!replay.finished());
+        assert!(replay.receive().is_err());
+        assert!(replay.finished());
+    }
+
+    #[test]
+    fn test_original_timing() {
+        let mut replay = ReplayClient::from_reader(Box::new(Cursor::new(CAPTURE)), true);
+        let start = Instant::now();
+        for _ in 0..3 {
+            replay.receive().unwrap();
+        }
+        assert!(start.elapsed() >= Duration::from_millis(190));
+    }
+}
@@ -0,0 +1,194 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+
+use deku::DekuContainerRead;
+
+use crate::cli::log;
+use crate::frisquet::proto::FrisquetMetadata;
+use crate::metrics;
+use crate::rf::{RFClient, ReceivedFrame};
+use crate::settings::parse_setting;
+
+/// How long to wait for an answer and how often to try, read from the `request_*` settings.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RequestOptions {
+    /// Time to wait for the answer after each transmission.
+    pub timeout: Duration,
+    /// Transmissions after the first one.
+    pub retries: u32,
+    /// Delay before the first retry, doubled for each following one.
+    pub backoff: Duration,
+}
+
+impl Default for RequestOptions {
+    fn default() -> Self {
+        RequestOptions {
+            timeout: Duration::from_millis(1000),
+            retries: 2,
+            backoff: Duration::from_millis(500),
+        }
+    }
+}
+
+impl RequestOptions {
+    pub fn from_settings(settings: &HashMap<String, String>) -> Result<RequestOptions, String> {
+        let setting = |key: &str, default: u64| -> Result<u64, String> {
+            Ok(parse_setting(settings, key)?.unwrap_or(default))
+        };
+        let default = RequestOptions::default();
+        Ok(RequestOptions {
+            timeout: Duration::from_millis(setting(
+                "request_timeout_ms",
+                default.timeout.as_millis() as u64,
+            )?),
+            retries: setting("request_retries", default.retries as u64)? as u32,
+            backoff: Duration::from_millis(setting(
+                "request_backoff_ms",
+                default.backoff.as_millis() as u64,
+            )?),
+        })
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum RequestError {
+    /// No answer after all the attempts.
+    Timeout {
+        attempts: u32,
+    },
+    /// The request isn't a valid frame.
+    InvalidRequest(String),
+    Client(String),
+}
+
+impl fmt::Display for RequestError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RequestError::Timeout { attempts } => {
+                write!(f, "No answer after {attempts} attempts")
+            }
+            RequestError::InvalidRequest(message) => write!(f, "Invalid request: {message}"),
+            RequestError::Client(message) => write!(f, "{message}"),
+        }
+    }
+}
+
+/// Returns true when `answer` answers `request`: same request id, answer bit set, sent back by the target.
+pub fn is_answer(request: &FrisquetMetadata, answer: &FrisquetMetadata) -> bool {
+    answer.request_id == request.request_id
+        && answer.req_or_answer & 0x80 != 0
+        && answer.from_addr == request.to_addr
+        && answer.to_addr == request.from_addr
+}
+
+/// Sends a frame and waits for its answer, ignoring the unrelated traffic.
+pub fn request(
+    client: &mut dyn RFClient,
+    frame: &[u8],
+    options: &RequestOptions,
+) -> Result<(FrisquetMetadata, ReceivedFrame), RequestError> {
+    request_with(client, frame, options, &mut || {})
+}
+
+/// Like [`request`], calling `sent` once the frame has been transmitted the first time.
+pub fn request_with(
+    client: &mut dyn RFClient,
+    frame: &[u8],
+    options: &RequestOptions,
+    sent: &mut dyn FnMut(),
+) -> Result<(FrisquetMetadata, ReceivedFrame), RequestError> {
+    let (_, metadata) = FrisquetMetadata::from_bytes((frame, 0))
+        .map_err(|e| RequestError::InvalidRequest(e.to_string()))?;
+
+    let mut backoff = options.backoff;
+    for attempt in 0..=options.retries {
+        if attempt > 0 {
+            log!(
+                Info,
+                "No answer to request {}, retrying in {backoff:?}",
+                metadata.request_id
+            );
+            sleep(backoff);
+            backoff *= 2;
+        }
+        client.send(frame.to_vec()).map_err(RequestError::Client)?;
+        if attempt == 0 {
+            sent();
+        }
+
+        let deadline = Instant::now() + options.timeout;
+        loop {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() {
+                break;
+            }
+            let Some(received) = client
+                .receive_timeout(remaining)
+                .map_err(RequestError::Client)?
+            else {
+                break;
+            };
+            match FrisquetMetadata::from_bytes((&received.data, 0)) {
+                Ok((_, answer)) if is_answer(&metadata, &answer) => return Ok((answer, received)),
+                _ => log!(Debug, "Ignoring frame {}", hex::encode(&received.data)),
+            }
+        }
+    }
+    metrics::REQUEST_TIMEOUTS.inc();
+    Err(RequestError::Timeout {
+        attempts: options.retries + 1,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rf::testing::FakeClient;
+
+    const SONDE: &str = "118020ba4001179c540004a029000102005c";
+
+    fn options() -> RequestOptions {
+        RequestOptions {
+            timeout: Duration::from_millis(10),
+            retries: 2,
+            backoff: Duration::ZERO,
+        }
+    }
+
+    #[test]
+    fn test_skips_unrelated_frames() {
+        let mut client = FakeClient::with_frames(&[
+            // Another request id, then the request itself echoed, then the answer.
+            "0c2080ba41811700000000000000",
+            "118020ba4001179c540004a029000102005c",
+            "0c2080ba40811700000000000000",
+        ]);
+        let (answer, frame) =
+            request(&mut client, &hex::decode(SONDE).unwrap(), &options()).unwrap();
+        assert_eq!(answer.request_id, 0xba40);
+        assert_eq!(hex::encode(frame.data), "0c2080ba40811700000000000000");
+        assert_eq!(client.sent().len(), 1);
+    }
+
+    #[test]
+    fn test_retries_then_times_out() {
+        let mut client = FakeClient::default();
+        assert_eq!(
+            request(&mut client, &hex::decode(SONDE).unwrap(), &options()),
+            Err(RequestError::Timeout { attempts: 3 })
+        );
+        assert_eq!(client.sent(), vec![SONDE; 3]);
+
+        let mut notified = 0;
+        let result = request_with(
+            &mut client,
+            &hex::decode(SONDE).unwrap(),
+            &options(),
+            &mut || notified += 1,
+        );
+        assert!(result.is_err());
+        assert_eq!(notified, 1);
+    }
+}
@@ -0,0 +1,257 @@
+use std::collections::{HashMap, VecDeque};
+use std::fs::File;
+use std::io::{self, BufRead, BufReader};
+use std::net::TcpStream;
+#[cfg(unix)]
+use std::os::unix::net::UnixStream;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use serde_json::Value;
+
+use crate::cli::log;
+use crate::rf::framing::{bits_from_hex, bytes_to_bits, extract_frames};
+use crate::rf::{RFClient, ReceivedFrame};
+use crate::settings::parse_setting;
+
+/// Receive-only client reading the JSON output of rtl_433.
+///
+/// rtl_433 must be set up with a flex decoder for the Frisquet transmissions, e.g.
+/// `rtl_433 -f 868.96M -s 1M -X 'n=Frisquet,m=FSK_PCM,s=40,l=40,r=1000' -F json -M level`.
+pub struct Rtl433Client {
+    reader: Box<dyn BufRead>,
+    gateway: String,
+    /// Only accept events from this decoder, when set.
+    model: Option<String>,
+    /// The rows start after the sync word, as with a flex decoder matching on it.
+    after_sync: bool,
+    network_id: Option<Vec<u8>>,
+    frames: VecDeque<ReceivedFrame>,
+}
+
+/// Opens the input: `-` for stdin, `tcp://host:port`, `unix:/path` or a file path.
+fn open_input(input: &str) -> Result<Box<dyn BufRead>, String> {
+    if input == "-" {
+        return Ok(Box::new(BufReader::new(io::stdin())));
+    }
+    if let Some(address) = input.strip_prefix("tcp://") {
+        let stream =
+            TcpStream::connect(address).map_err(|e| format!("Failed to connect {input}: {e}"))?;
+        return Ok(Box::new(BufReader::new(stream)));
+    }
+    #[cfg(unix)]
+    if let Some(path) = input.strip_prefix("unix:") {
+        let stream =
+            UnixStream::connect(path).map_err(|e| format!("Failed to connect {input}: {e}"))?;
+        return Ok(Box::new(BufReader::new(stream)));
+    }
+    let file = File::open(input).map_err(|e| format!("Failed to open {input}: {e}"))?;
+    Ok(Box::new(BufReader::new(file)))
+}
+
+pub fn new(settings: &HashMap<String, String>) -> Result<Rtl433Client, String> {
+    let input = settings
+        .get("rtl433_input")
+        .ok_or("rtl433_input required")?;
+    let mut client = Rtl433Client::from_reader(open_input(input)?, format!("rtl433:{input}"));
+    client.model = settings.get("rtl433_model").cloned();
+    client.after_sync = parse_setting(settings, "rtl433_after_sync")?.unwrap_or(false);
+    Ok(client)
+}
+
+/// Extracts the bit rows of an event, from `rows` or else from `codes`.
+fn bit_rows(event: &Value) -> Vec<Vec<bool>> {
+    if let Some(rows) = event.get("rows").and_then(Value::as_array) {
+        return rows
+            .iter()
+            .filter_map(|row| {
+                let data = row.get("data")?.as_str()?;
+                let len = row.get("len").and_then(Value::as_u64);
+                bits_from_hex(data, len.map(|len| len as usize))
+            })
+            .collect();
+    }
+    // Codes are written as `{<length in bits>}<hex>`.
+    event
+        .get("codes")
+        .and_then(Value::as_array)
+        .into_iter()
+        .flatten()
+        .filter_map(|code| {
+            let (len, data) = code.as_str()?.strip_prefix('{')?.split_once('}')?;
+            bits_from_hex(data, Some(len.parse().ok()?))
+        })
+        .collect()
+}
+
+fn timestamp(event: &Value) -> SystemTime {
+    // Only unix timestamps (`-M time:unix`) are understood, others are local dates.
+    match event.get("time").and_then(|t| match t {
+        Value::Number(n) => n.as_f64(),
+        Value::String(s) => s.parse().ok(),
+        _ => None,
+    }) {
+        Some(secs) if secs >= 0.0 => UNIX_EPOCH + Duration::from_secs_f64(secs),
+        _ => SystemTime::now(),
+    }
+}
+
+impl Rtl433Client {
+    pub fn from_reader(reader: Box<dyn BufRead>, gateway: String) -> Rtl433Client {
+        Rtl433Client {
+            reader,
+            gateway,
+            model: None,
+            after_sync: false,
+            network_id: None,
+            frames: VecDeque::new(),
+        }
+    }
+
+    /// Queues the frames found in one line of rtl_433 output.
+    fn process_line(&mut self, line: &str) -> Result<(), String> {
+        let line = line.trim();
+        if !line.starts_with('{') {
+            // rtl_433 also writes its own messages on the same output.
+            return Ok(());
+        }
+        let event: Value = match serde_json::from_str(line) {
+            Ok(event) => event,
+            Err(e) => {
+                log!(Debug, "Ignoring rtl_433 line: {e}: {line}");
+                return Ok(());
+            }
+        };
+        if let Some(model) = &self.model {
+            if event.get("model").and_then(Value::as_str) != Some(model) {
+                return Ok(());
+            }
+        }
+
+        let network_id = self
+            .network_id
+            .clone()
+            .ok_or("The network id is needed to find the frames")?;
+        for bits in bit_rows(&event) {
+            let frames = if self.after_sync {
+                let mut bits_with_sync = bytes_to_bits(&network_id);
+                bits_with_sync.extend(bits);
+                extract_frames(&bits_with_sync, &network_id)
+            } else {
+                extract_frames(&bits, &network_id)
+            };
+            for data in frames {
+                let mut frame = ReceivedFrame::new(data, self.gateway.clone());
+                frame.timestamp = timestamp(&event);
+                frame.rssi = event
+                    .get("rssi")
+                    .and_then(Value::as_f64)
+                    .map(|rssi| rssi.round() as i16);
+                self.frames.push_back(frame);
+            }
+        }
+        Ok(())
+    }
+}
+
+impl RFClient for Rtl433Client {
+    fn set_network_id(&mut self, network_id: Vec<u8>) -> Result<(), String> {
+        self.network_id = Some(network_id);
+        Ok(())
+    }
+
+    fn receive(&mut self) -> Result<ReceivedFrame, String> {
+        loop {
+            if let Some(frame) = self.frames.pop_front() {
+                return Ok(frame);
+            }
+            let mut line = String::new();
+            if self
+                .reader
+                .read_line(&mut line)
+                .map_err(|e| e.to_string())?
+                == 0
+            {
+                return Err("End of rtl_433 input".to_string());
+            }
+            self.process_line(&line)?;
+        }
+    }
+
+    fn send(&mut self, _payload: Vec<u8>) -> Result<(), String> {
+        Err("The rtl_433 backend is receive-only".to_string())
+    }
+
+    fn sleep(&mut self) -> Result<(), String> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn client(input: &str) -> Rtl433Client {
+        let mut client = Rtl433Client::from_reader(
+            Box::new(io::Cursor::new(input.to_string())),
+            "rtl433:test".to_string(),
+        );
+        client.set_network_id(vec![0x05, 0xda, 0x2e, 0xe2]).unwrap();
+        client
+    }
+
+    fn client_after_sync(input: &str) -> Rtl433Client {
+        let mut client = client(input);
+        client.after_sync = true;
+        client
+    }
+
+    #[test]
+    fn test_after_sync() {
+        let mut client = client(r#"{"rows":[{"len":96,"data":"0b0080d3c802410405d7199e"}]}"#);
+        assert!(client.receive().is_err());
+
+        let mut client =
+            client_after_sync(r#"{"rows":[{"len":96,"data":"0b0080d3c802410405d7199e"}]}"#);
+        assert_eq!(
+            client.receive().unwrap().data,
+            hex::decode("0b0080d3c802410405d7199e").unwrap()
+        );
+    }
+
+    #[test]
+    fn test_model_filter() {
+        let mut client = client(
+            r#"{"model":"Acurite-Tower","codes":["{144}aaaa05da2ee20b0080d3c802410405d7199e"]}"#,
+        );
+        client.model = Some("Frisquet".to_string());
+        assert!(client.receive().is_err());
+    }
+
+    #[test]
+    fn test_rows() {
+        // A row of preamble, sync word and frame, shifted by 2 bits, then a log line.
+        let mut client = client(concat!(
+            r#"{"time":"1680709763","model":"Frisquet","rssi":-12.3,"rows":[{"len":194,"data":"aaaa81768bb88460082e900045e7150001280a400040801700"}]}"#,
+            "\n",
+            "Tuned to 868.960MHz.\n",
+            r#"{"time":"2023-04-05 17:49:23","model":"Frisquet","codes":["{144}aaaa05da2ee20b0080d3c802410405d7199e"]}"#,
+            "\n"
+        ));
+
+        let frame = client.receive().unwrap();
+        assert_eq!(
+            frame.data,
+            hex::decode("118020ba4001179c540004a029000102005c").unwrap()
+        );
+        assert_eq!(frame.rssi, Some(-12));
+        assert_eq!(
+            frame.timestamp,
+            UNIX_EPOCH + Duration::from_secs(1680709763)
+        );
+        assert_eq!(
+            client.receive().unwrap().data,
+            hex::decode("0b0080d3c802410405d7199e").unwrap()
+        );
+        assert!(client.receive().is_err());
+    }
+}
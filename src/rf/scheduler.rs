@@ -0,0 +1,303 @@
+use std::cell::RefCell;
+use std::collections::{HashMap, VecDeque};
+use std::rc::Rc;
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+
+use crate::cli::log;
+use crate::rf::{GatewayState, RFClient, ReceivedFrame};
+use crate::settings::parse_setting;
+
+/// Frames sent to this address are delivered to every device listening on the network.
+const BROADCAST_ADDR: u8 = 0;
+
+/// Shares one radio between several emulated devices, e.g. the sonde and the satellites.
+///
+/// Each device gets its own [`RFClient`], with its own network id and sleep state.
+/// The radio is retuned for each transmission, keeps listening as long as a device
+/// listens, and received frames are routed to the devices by their `to_addr`.
+/// Scheduling is cooperative: a device waiting for a frame holds the radio,
+/// queuing the frames heard for the other devices.
+pub struct RadioScheduler {
+    radio: Rc<RefCell<Radio>>,
+}
+
+struct Radio {
+    client: Box<dyn RFClient>,
+    network_id: Option<Vec<u8>>,
+    asleep: bool,
+    /// Minimum delay between two transmissions, leaving time for the answer.
+    tx_gap: Duration,
+    last_tx: Option<Instant>,
+    devices: Vec<Device>,
+}
+
+struct Device {
+    addresses: Vec<u8>,
+    network_id: Option<Vec<u8>>,
+    listening: bool,
+    queue: VecDeque<ReceivedFrame>,
+}
+
+/// One device's view of the shared radio.
+pub struct LogicalRadio {
+    id: usize,
+    radio: Rc<RefCell<Radio>>,
+}
+
+impl RadioScheduler {
+    pub fn new(
+        client: Box<dyn RFClient>,
+        settings: &HashMap<String, String>,
+    ) -> Result<Self, String> {
+        let tx_gap = parse_setting(settings, "radio_tx_gap_ms")?.unwrap_or(200);
+        Ok(RadioScheduler {
+            radio: Rc::new(RefCell::new(Radio {
+                client,
+                network_id: None,
+                asleep: true,
+                tx_gap: Duration::from_millis(tx_gap),
+                last_tx: None,
+                devices: vec![],
+            })),
+        })
+    }
+
+    /// Adds a device receiving the frames sent to `addresses`.
+    pub fn device(&self, addresses: &[u8]) -> LogicalRadio {
+        let mut radio = self.radio.borrow_mut();
+        radio.devices.push(Device {
+            addresses: addresses.to_vec(),
+            network_id: None,
+            listening: false,
+            queue: VecDeque::new(),
+        });
+        LogicalRadio {
+            id: radio.devices.len() - 1,
+            radio: self.radio.clone(),
+        }
+    }
+}
+
+impl Radio {
+    fn tune(&mut self, network_id: &[u8]) -> Result<(), String> {
+        if self.network_id.as_deref() != Some(network_id) {
+            self.client.set_network_id(network_id.to_vec())?;
+            self.network_id = Some(network_id.to_vec());
+        }
+        Ok(())
+    }
+
+    /// Returns the network of a listening device, preferring the current one.
+    fn listened_network(&self) -> Option<Vec<u8>> {
+        let mut networks = self
+            .devices
+            .iter()
+            .filter(|device| device.listening)
+            .filter_map(|device| device.network_id.clone());
+        let first = networks.next()?;
+        if self.network_id.as_ref() == Some(&first)
+            || networks.any(|n| Some(&n) == self.network_id.as_ref())
+        {
+            return self.network_id.clone();
+        }
+        Some(first)
+    }
+
+    /// Puts the radio to sleep, or back on a listened network, after a device changed its state.
+    fn settle(&mut self) -> Result<(), String> {
+        match self.listened_network() {
+            Some(network_id) => {
+                self.tune(&network_id)?;
+                self.asleep = false;
+            }
+            None if !self.asleep => {
+                self.client.sleep()?;
+                self.asleep = true;
+            }
+            None => {}
+        }
+        Ok(())
+    }
+
+    /// Queues a frame for the devices it is sent to, returning false when no device wants it.
+    fn route(&mut self, frame: ReceivedFrame) -> bool {
+        // Frames shorter than the header have no destination.
+        let Some(&to_addr) = frame.data.get(1) else {
+            return false;
+        };
+        let network_id = self.network_id.clone();
+        let mut routed = false;
+        for device in self.devices.iter_mut() {
+            let addressed = to_addr == BROADCAST_ADDR || device.addresses.contains(&to_addr);
+            if addressed && device.listening && device.network_id == network_id {
+                device.queue.push_back(frame.clone());
+                routed = true;
+            }
+        }
+        routed
+    }
+}
+
+impl LogicalRadio {
+    /// Receives for the device until the deadline, queuing the frames for the others.
+    fn listen(&mut self, deadline: Option<Instant>) -> Result<Option<ReceivedFrame>, String> {
+        let mut radio = self.radio.borrow_mut();
+        let network_id = radio.devices[self.id]
+            .network_id
+            .clone()
+            .ok_or("receiving without network id")?;
+        radio.devices[self.id].listening = true;
+        loop {
+            if let Some(frame) = radio.devices[self.id].queue.pop_front() {
+                return Ok(Some(frame));
+            }
+            radio.tune(&network_id)?;
+            radio.asleep = false;
+            let frame = match deadline {
+                Some(deadline) => {
+                    let remaining = deadline.saturating_duration_since(Instant::now());
+                    match radio.client.receive_timeout(remaining)? {
+                        Some(frame) => frame,
+                        None => return Ok(None),
+                    }
+                }
+                None => radio.client.receive()?,
+            };
+            if !radio.route(frame.clone()) {
+                log!(Debug, "No device for frame {}", hex::encode(&frame.data));
+            }
+        }
+    }
+}
+
+impl RFClient for LogicalRadio {
+    fn set_network_id(&mut self, network_id: Vec<u8>) -> Result<(), String> {
+        let mut radio = self.radio.borrow_mut();
+        radio.devices[self.id].network_id = Some(network_id);
+        radio.settle()
+    }
+
+    fn receive(&mut self) -> Result<ReceivedFrame, String> {
+        self.listen(None)
+            .map(|frame| frame.expect("listening without deadline"))
+    }
+
+    fn receive_timeout(&mut self, timeout: Duration) -> Result<Option<ReceivedFrame>, String> {
+        self.listen(Some(Instant::now() + timeout))
+    }
+
+    fn send(&mut self, payload: Vec<u8>) -> Result<(), String> {
+        let mut radio = self.radio.borrow_mut();
+        let network_id = radio.devices[self.id]
+            .network_id
+            .clone()
+            .ok_or("sending without network id")?;
+        if let Some(elapsed) = radio.last_tx.map(|t| t.elapsed()) {
+            if elapsed < radio.tx_gap {
+                sleep(radio.tx_gap - elapsed);
+            }
+        }
+        radio.tune(&network_id)?;
+        radio.client.send(payload)?;
+        radio.last_tx = Some(Instant::now());
+        // The device expects the answer on the network it transmitted on.
+        radio.devices[self.id].listening = true;
+        radio.asleep = false;
+        Ok(())
+    }
+
+    fn sleep(&mut self) -> Result<(), String> {
+        let mut radio = self.radio.borrow_mut();
+        let device = &mut radio.devices[self.id];
+        device.listening = false;
+        device.queue.clear();
+        radio.settle()
+    }
+
+    fn gateway_state(&self) -> GatewayState {
+        self.radio.borrow().client.gateway_state()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rf::testing::FakeClient;
+
+    const NETWORK: [u8; 4] = [5, 218, 46, 226];
+
+    fn scheduler(client: &FakeClient) -> RadioScheduler {
+        let settings = HashMap::from([("radio_tx_gap_ms".to_string(), "0".to_string())]);
+        RadioScheduler::new(Box::new(client.clone()), &settings).unwrap()
+    }
+
+    #[test]
+    fn test_routing() {
+        // Boiler answers to the satellite 0x08, then to the sonde 0x20.
+        let client =
+            FakeClient::with_frames(&["0708800001811700", "0720800002811700", "0730800003811700"]);
+        let scheduler = scheduler(&client);
+        let mut satellite = scheduler.device(&[0x08]);
+        let mut sonde = scheduler.device(&[0x20]);
+        satellite.set_network_id(NETWORK.to_vec()).unwrap();
+        sonde.set_network_id(NETWORK.to_vec()).unwrap();
+        sonde
+            .send(hex::decode("0680200002011700").unwrap())
+            .unwrap();
+        assert_eq!(client.sent(), vec!["0680200002011700"]);
+
+        // The satellite isn't listening yet, its frame is dropped.
+        assert_eq!(sonde.receive().unwrap().data[1], 0x20);
+        satellite.receive().unwrap_err();
+    }
+
+    #[test]
+    fn test_queueing_and_sleep() {
+        let client = FakeClient::with_frames(&["0720800002811700", "0708800001811700"]);
+        let scheduler = scheduler(&client);
+        let mut satellite = scheduler.device(&[0x08]);
+        let mut sonde = scheduler.device(&[0x20]);
+        satellite.set_network_id(NETWORK.to_vec()).unwrap();
+        sonde.set_network_id(NETWORK.to_vec()).unwrap();
+        sonde
+            .send(hex::decode("0680200002011700").unwrap())
+            .unwrap();
+        satellite
+            .send(hex::decode("0680080001011700").unwrap())
+            .unwrap();
+
+        // The sonde's answer heard while the satellite waits is kept for the sonde.
+        assert_eq!(satellite.receive().unwrap().data[1], 0x08);
+        assert_eq!(sonde.receive().unwrap().data[1], 0x20);
+
+        // The radio stays awake until the last listening device sleeps.
+        sonde.sleep().unwrap();
+        assert!(!client.radio.lock().unwrap().sleeping);
+        satellite.sleep().unwrap();
+        assert!(client.radio.lock().unwrap().sleeping);
+    }
+
+    #[test]
+    fn test_network_switch() {
+        let client = FakeClient::default();
+        let scheduler = scheduler(&client);
+        let mut satellite = scheduler.device(&[0x08]);
+        let mut pairing = scheduler.device(&[0x08]);
+        satellite.set_network_id(NETWORK.to_vec()).unwrap();
+        pairing.set_network_id(vec![0xff; 4]).unwrap();
+        pairing.receive().unwrap_err();
+        assert_eq!(client.radio.lock().unwrap().network_id, Some(vec![0xff; 4]));
+        satellite
+            .send(hex::decode("0680080001011700").unwrap())
+            .unwrap();
+        assert_eq!(
+            client.radio.lock().unwrap().network_id,
+            Some(NETWORK.to_vec())
+        );
+
+        // Once the satellite sleeps, the radio moves back to the network still listened to.
+        satellite.sleep().unwrap();
+        assert_eq!(client.radio.lock().unwrap().network_id, Some(vec![0xff; 4]));
+    }
+}
@@ -0,0 +1,166 @@
+use std::f32::consts::PI;
+
+/// Power above the noise floor needed to consider a transmission started (10 dB).
+const SQUELCH_RATIO: f32 = 10.0;
+/// Bits longer than this are not data, the run is truncated.
+const MAX_RUN_BITS: usize = 32;
+/// Bits of the recording used to estimate the noise floor before looking for transmissions.
+const WARMUP_BITS: f32 = 16.0;
+
+/// A transmission, demodulated to bits.
+#[derive(Debug)]
+pub struct Burst {
+    pub bits: Vec<bool>,
+    /// Index of the first sample of the burst in the recording.
+    pub start: u64,
+    /// Mean power of the burst, in dB relative to full scale.
+    pub power_db: f32,
+}
+
+/// Streaming 2-FSK demodulator: squelch, FM discriminator, low-pass filter,
+/// then a slicer re-synchronising its bit clock on every transition.
+pub struct Demodulator {
+    samples_per_bit: f32,
+    position: u64,
+    previous: (f32, f32),
+    power: f32,
+    noise_floor: Option<f32>,
+    frequency: f32,
+    dc: f32,
+    burst: Option<BurstState>,
+}
+
+struct BurstState {
+    start: u64,
+    bits: Vec<bool>,
+    level: bool,
+    run: usize,
+    quiet: usize,
+    energy: f64,
+    samples: usize,
+}
+
+impl Demodulator {
+    pub fn new(sample_rate: u32, bitrate: u32) -> Demodulator {
+        Demodulator {
+            samples_per_bit: sample_rate as f32 / bitrate as f32,
+            position: 0,
+            previous: (0.0, 0.0),
+            power: 0.0,
+            noise_floor: None,
+            frequency: 0.0,
+            dc: 0.0,
+            burst: None,
+        }
+    }
+
+    /// Demodulates the next samples, returning the bursts which ended in them.
+    pub fn push(&mut self, samples: &[(f32, f32)]) -> Vec<Burst> {
+        let mut bursts = vec![];
+        let power_alpha = 1.0 / self.samples_per_bit;
+        let frequency_alpha = 4.0 / self.samples_per_bit;
+        let dc_alpha = 1.0 / (self.samples_per_bit * 16.0);
+
+        for &(i, q) in samples {
+            let (pi, pq) = self.previous;
+            self.previous = (i, q);
+            self.position += 1;
+
+            let p = i * i + q * q;
+            self.power += (p - self.power) * power_alpha;
+            // Instantaneous frequency, as the phase difference between two samples.
+            let d = (q * pi - i * pq).atan2(i * pi + q * pq);
+            self.frequency += (d - self.frequency) * frequency_alpha.min(1.0);
+
+            if (self.position as f32) < WARMUP_BITS * self.samples_per_bit {
+                self.noise_floor = Some(self.power.max(1e-9));
+                continue;
+            }
+            let noise_floor = self.noise_floor.unwrap_or(1e-9);
+            let above = self.power > noise_floor * SQUELCH_RATIO;
+
+            match &mut self.burst {
+                None => {
+                    if above {
+                        self.dc = 0.0;
+                        self.burst = Some(BurstState {
+                            start: self.position - 1,
+                            bits: vec![],
+                            level: self.frequency > 0.0,
+                            run: 0,
+                            quiet: 0,
+                            energy: 0.0,
+                            samples: 0,
+                        });
+                    } else {
+                        let floor = noise_floor + (self.power - noise_floor) * 0.001;
+                        self.noise_floor = Some(floor.max(1e-9));
+                    }
+                }
+                Some(burst) => {
+                    burst.energy += p as f64;
+                    burst.samples += 1;
+                    burst.quiet = if above { 0 } else { burst.quiet + 1 };
+
+                    self.dc += (self.frequency - self.dc) * dc_alpha;
+                    let level = self.frequency > self.dc;
+                    if level == burst.level {
+                        burst.run += 1;
+                    } else {
+                        push_run(
+                            &mut burst.bits,
+                            burst.level,
+                            burst.run,
+                            self.samples_per_bit,
+                        );
+                        burst.level = level;
+                        burst.run = 1;
+                    }
+
+                    if burst.quiet as f32 > 2.0 * self.samples_per_bit {
+                        let mut burst = self.burst.take().unwrap();
+                        push_run(
+                            &mut burst.bits,
+                            burst.level,
+                            burst.run,
+                            self.samples_per_bit,
+                        );
+                        bursts.push(Burst {
+                            bits: burst.bits,
+                            start: burst.start,
+                            power_db: 10.0 * (burst.energy / burst.samples as f64).log10() as f32,
+                        });
+                    }
+                }
+            }
+        }
+        bursts
+    }
+}
+
+/// Appends the bits corresponding to a run of samples at the same level.
+fn push_run(bits: &mut Vec<bool>, level: bool, run: usize, samples_per_bit: f32) {
+    let count = (run as f32 / samples_per_bit).round() as usize;
+    bits.extend(std::iter::repeat_n(level, count.min(MAX_RUN_BITS)));
+}
+
+/// Generates the samples of a 2-FSK transmission, for tests and fixtures.
+pub fn modulate(
+    bits: &[bool],
+    sample_rate: u32,
+    bitrate: u32,
+    deviation: f32,
+    amplitude: f32,
+) -> Vec<(f32, f32)> {
+    let samples_per_bit = sample_rate as f32 / bitrate as f32;
+    let step = 2.0 * PI * deviation / sample_rate as f32;
+    let mut phase = 0.0_f32;
+    let count = (bits.len() as f32 * samples_per_bit) as usize;
+    (0..count)
+        .map(|n| {
+            let bit = bits[((n as f32 / samples_per_bit) as usize).min(bits.len() - 1)];
+            phase += if bit { step } else { -step };
+            (amplitude * phase.cos(), amplitude * phase.sin())
+        })
+        .collect()
+}
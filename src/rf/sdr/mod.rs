@@ -0,0 +1,329 @@
+use std::collections::{HashMap, VecDeque};
+use std::f32::consts::PI;
+use std::fs::File;
+use std::io::{BufReader, Read};
+use std::path::Path;
+use std::time::{Duration, SystemTime};
+
+use crate::rf::framing::extract_frames;
+use crate::rf::sdr::demod::Demodulator;
+use crate::rf::{RFClient, ReceivedFrame};
+use crate::settings::parse_setting;
+
+pub mod demod;
+
+/// Bitrate used by the Frisquet devices.
+pub const FRISQUET_BITRATE: u32 = 25_000;
+
+const CHUNK_SAMPLES: usize = 64 * 1024;
+
+/// Sample formats written by rtl_sdr and similar tools.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SampleFormat {
+    /// Interleaved unsigned 8 bits I/Q, as written by `rtl_sdr`.
+    Cu8,
+    /// Interleaved signed 16 bits little endian I/Q.
+    Cs16,
+}
+
+impl SampleFormat {
+    pub fn from_name(name: &str) -> Result<SampleFormat, String> {
+        match name.to_ascii_lowercase().as_str() {
+            "cu8" => Ok(SampleFormat::Cu8),
+            "cs16" => Ok(SampleFormat::Cs16),
+            _ => Err(format!(
+                "Unknown sample format {name}, expected cu8 or cs16"
+            )),
+        }
+    }
+
+    fn sample_size(&self) -> usize {
+        match self {
+            SampleFormat::Cu8 => 2,
+            SampleFormat::Cs16 => 4,
+        }
+    }
+
+    fn decode(&self, bytes: &[u8]) -> (f32, f32) {
+        match self {
+            SampleFormat::Cu8 => (
+                (bytes[0] as f32 - 127.5) / 127.5,
+                (bytes[1] as f32 - 127.5) / 127.5,
+            ),
+            SampleFormat::Cs16 => (
+                i16::from_le_bytes([bytes[0], bytes[1]]) as f32 / 32768.0,
+                i16::from_le_bytes([bytes[2], bytes[3]]) as f32 / 32768.0,
+            ),
+        }
+    }
+}
+
+/// Receive-only client demodulating an IQ recording.
+pub struct SdrClient {
+    reader: Box<dyn Read>,
+    format: SampleFormat,
+    sample_rate: u32,
+    /// Frequency of the signal relative to the center of the recording, in Hz.
+    frequency_offset: f32,
+    mixer_phase: f32,
+    demodulator: Demodulator,
+    recording_start: SystemTime,
+    gateway: String,
+    network_id: Option<Vec<u8>>,
+    frames: VecDeque<ReceivedFrame>,
+    end_of_recording: bool,
+}
+
+pub fn new(settings: &HashMap<String, String>) -> Result<SdrClient, String> {
+    let path = settings.get("sdr_file").ok_or("sdr_file required")?;
+    let format = match settings.get("sdr_format") {
+        Some(format) => SampleFormat::from_name(format)?,
+        None => SampleFormat::from_name(
+            Path::new(path)
+                .extension()
+                .and_then(|e| e.to_str())
+                .unwrap_or_default(),
+        )?,
+    };
+    let parse = |key: &str, default: f64| -> Result<f64, String> {
+        Ok(parse_setting(settings, key)?.unwrap_or(default))
+    };
+    let positive = |key: &str, default: f64| -> Result<u32, String> {
+        match parse(key, default)? {
+            value if value >= 1.0 => Ok(value as u32),
+            value => Err(format!("invalid {key}: {value}, must be positive")),
+        }
+    };
+    let sample_rate = positive("sdr_sample_rate", 1_000_000.0)?;
+    let bitrate = positive("sdr_bitrate", FRISQUET_BITRATE as f64)?;
+    let frequency_offset = parse("sdr_frequency_offset", 0.0)? as f32;
+
+    let file = File::open(path).map_err(|e| format!("Failed to open {path}: {e}"))?;
+    let metadata = file.metadata().map_err(|e| e.to_string())?;
+    // rtl_sdr writes the file as it records, its modification time is the end of the capture.
+    let duration = metadata.len() as f64 / format.sample_size() as f64 / sample_rate as f64;
+    let recording_start = metadata
+        .modified()
+        .map(|end| end - Duration::from_secs_f64(duration))
+        .unwrap_or(SystemTime::UNIX_EPOCH);
+
+    let mut client = SdrClient::from_reader(
+        Box::new(BufReader::new(file)),
+        format,
+        sample_rate,
+        bitrate,
+        format!("sdr:{path}"),
+    );
+    client.frequency_offset = frequency_offset;
+    client.recording_start = recording_start;
+    Ok(client)
+}
+
+impl SdrClient {
+    pub fn from_reader(
+        reader: Box<dyn Read>,
+        format: SampleFormat,
+        sample_rate: u32,
+        bitrate: u32,
+        gateway: String,
+    ) -> SdrClient {
+        SdrClient {
+            reader,
+            format,
+            sample_rate,
+            frequency_offset: 0.0,
+            mixer_phase: 0.0,
+            demodulator: Demodulator::new(sample_rate, bitrate),
+            recording_start: SystemTime::UNIX_EPOCH,
+            gateway,
+            network_id: None,
+            frames: VecDeque::new(),
+            end_of_recording: false,
+        }
+    }
+
+    /// Reads and demodulates the next chunk of the recording.
+    fn process_chunk(&mut self) -> Result<(), String> {
+        // Checked first, the chunk would be lost otherwise.
+        let network_id = self
+            .network_id
+            .clone()
+            .ok_or("The network id is needed to find the frames")?;
+        let mut bytes = vec![0; CHUNK_SAMPLES * self.format.sample_size()];
+        let mut read = 0;
+        while read < bytes.len() {
+            match self.reader.read(&mut bytes[read..]) {
+                Ok(0) => break,
+                Ok(n) => read += n,
+                Err(e) => return Err(e.to_string()),
+            }
+        }
+        if read < bytes.len() {
+            self.end_of_recording = true;
+        }
+
+        let step = -2.0 * PI * self.frequency_offset / self.sample_rate as f32;
+        let mut samples = Vec::with_capacity(read / self.format.sample_size());
+        for bytes in bytes[..read].chunks_exact(self.format.sample_size()) {
+            let (i, q) = self.format.decode(bytes);
+            if self.frequency_offset == 0.0 {
+                samples.push((i, q));
+            } else {
+                // Bring the signal back to the center of the band.
+                let (sin, cos) = self.mixer_phase.sin_cos();
+                samples.push((i * cos - q * sin, i * sin + q * cos));
+                self.mixer_phase = (self.mixer_phase + step) % (2.0 * PI);
+            }
+        }
+
+        for burst in self.demodulator.push(&samples) {
+            for data in extract_frames(&burst.bits, &network_id) {
+                let mut frame = ReceivedFrame::new(data, self.gateway.clone());
+                frame.timestamp = self.recording_start
+                    + Duration::from_secs_f64(burst.start as f64 / self.sample_rate as f64);
+                frame.rssi = Some(burst.power_db.round() as i16);
+                self.frames.push_back(frame);
+            }
+        }
+        Ok(())
+    }
+}
+
+impl RFClient for SdrClient {
+    fn set_network_id(&mut self, network_id: Vec<u8>) -> Result<(), String> {
+        self.network_id = Some(network_id);
+        Ok(())
+    }
+
+    fn receive(&mut self) -> Result<ReceivedFrame, String> {
+        loop {
+            if let Some(frame) = self.frames.pop_front() {
+                return Ok(frame);
+            }
+            if self.end_of_recording {
+                return Err("End of recording".to_string());
+            }
+            self.process_chunk()?;
+        }
+    }
+
+    fn send(&mut self, _payload: Vec<u8>) -> Result<(), String> {
+        Err("The SDR backend is receive-only".to_string())
+    }
+
+    fn sleep(&mut self) -> Result<(), String> {
+        Ok(())
+    }
+
+    fn finished(&self) -> bool {
+        self.end_of_recording && self.frames.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rf::framing::bytes_to_bits;
+    use crate::rf::sdr::demod::modulate;
+
+    const SAMPLE_RATE: u32 = 1_000_000;
+
+    /// Builds a cu8 recording with a few transmissions separated by noise.
+    fn recording(frames: &[Vec<u8>], network_id: &[u8]) -> Vec<u8> {
+        let mut seed = 42_u32;
+        let mut noise = move || {
+            seed = seed.wrapping_mul(1_103_515_245).wrapping_add(12_345);
+            (seed >> 16) as f32 / 65536.0 * 0.04 - 0.02
+        };
+        let mut samples = vec![];
+        for frame in frames {
+            samples.extend((0..5000).map(|_| (0.0, 0.0)));
+            let mut bits = bytes_to_bits(&[0xaa; 6]);
+            bits.extend(bytes_to_bits(network_id));
+            bits.extend(bytes_to_bits(frame));
+            bits.extend(bytes_to_bits(&[0xaa]));
+            samples.extend(modulate(
+                &bits,
+                SAMPLE_RATE,
+                FRISQUET_BITRATE,
+                50_000.0,
+                0.8,
+            ));
+        }
+        samples.extend((0..5000).map(|_| (0.0, 0.0)));
+
+        samples
+            .into_iter()
+            .flat_map(|(i, q)| {
+                let i = ((i + noise()) * 127.5 + 127.5).round() as u8;
+                let q = ((q + noise()) * 127.5 + 127.5).round() as u8;
+                [i, q]
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_demodulate_recording() {
+        let network_id = vec![0x05, 0xda, 0x2e, 0xe2];
+        let frames = vec![
+            hex::decode("118020ba4001179c540004a029000102005c").unwrap(),
+            hex::decode("0f2080ba408117082304051131172803").unwrap(),
+        ];
+        let mut client = SdrClient::from_reader(
+            Box::new(std::io::Cursor::new(recording(&frames, &network_id))),
+            SampleFormat::Cu8,
+            SAMPLE_RATE,
+            FRISQUET_BITRATE,
+            "sdr:test".to_string(),
+        );
+        assert!(client.receive().is_err());
+        assert!(!client.finished());
+        client.set_network_id(network_id).unwrap();
+
+        let first = client.receive().unwrap();
+        assert_eq!(first.data, frames[0]);
+        assert_eq!(first.gateway, "sdr:test");
+        assert!(first.rssi.is_some());
+        assert_eq!(client.receive().unwrap().data, frames[1]);
+        assert!(client.receive().is_err());
+        assert!(client.finished());
+        assert!(client.send(vec![]).is_err());
+    }
+
+    #[test]
+    fn test_other_network_is_ignored() {
+        let frames = vec![hex::decode("0b0080d3c802410405d7199e").unwrap()];
+        let mut client = SdrClient::from_reader(
+            Box::new(std::io::Cursor::new(recording(&frames, &[1, 2, 3, 4]))),
+            SampleFormat::Cu8,
+            SAMPLE_RATE,
+            FRISQUET_BITRATE,
+            "sdr:test".to_string(),
+        );
+        client.set_network_id(vec![0x05, 0xda, 0x2e, 0xe2]).unwrap();
+        assert!(client.receive().is_err());
+    }
+
+    #[test]
+    fn test_invalid_sample_rate() {
+        for rate in ["0", "-1000000"] {
+            let settings = HashMap::from([
+                ("sdr_file".to_string(), "capture.cu8".to_string()),
+                ("sdr_sample_rate".to_string(), rate.to_string()),
+            ]);
+            let error = new(&settings).err().unwrap();
+            assert!(error.starts_with("invalid sdr_sample_rate"), "{error}");
+        }
+    }
+
+    #[test]
+    fn test_sample_formats() {
+        assert_eq!(SampleFormat::from_name("CU8"), Ok(SampleFormat::Cu8));
+        assert!(SampleFormat::from_name("wav").is_err());
+        assert_eq!(SampleFormat::Cu8.decode(&[255, 0]), (1.0, -1.0));
+        assert_eq!(
+            SampleFormat::Cs16.decode(&[0x00, 0x40, 0x00, 0xc0]),
+            (0.5, -0.5)
+        );
+    }
+}
@@ -2,110 +2,545 @@ extern crate serialport;
 
 use std::collections::HashMap;
 use std::collections::VecDeque;
+use std::io::{ErrorKind, Read, Write};
 use std::result::Result;
-use std::time::Duration;
+use std::thread::sleep;
+use std::time::{Duration, Instant};
 
-use hex;
+use serialport::SerialPortType;
 
-use crate::rf::RFClient;
+use crate::cli::log;
+use crate::rf::serial::protocol::{Command, SerialEvent};
+use crate::rf::{RFClient, ReceivedFrame};
+use crate::settings::parse_setting;
 
+pub mod protocol;
+
+const RECONNECT_INITIAL_DELAY: Duration = Duration::from_millis(500);
+const RECONNECT_MAX_DELAY: Duration = Duration::from_secs(30);
+
+/// Selects the dongle among the connected USB serial devices.
+#[derive(Debug, Default, PartialEq)]
+pub struct UsbFilter {
+    pub vid: Option<u16>,
+    pub pid: Option<u16>,
+    pub serial_number: Option<String>,
+}
+
+impl UsbFilter {
+    fn from_settings(settings: &HashMap<String, String>) -> Result<UsbFilter, String> {
+        Ok(UsbFilter {
+            vid: settings
+                .get("serial_vid")
+                .map(|v| parse_usb_id(v))
+                .transpose()?,
+            pid: settings
+                .get("serial_pid")
+                .map(|v| parse_usb_id(v))
+                .transpose()?,
+            serial_number: settings.get("serial_serial_number").cloned(),
+        })
+    }
+
+    fn is_empty(&self) -> bool {
+        *self == UsbFilter::default()
+    }
+
+    pub fn matches(&self, vid: u16, pid: u16, serial_number: Option<&str>) -> bool {
+        self.vid.is_none_or(|v| v == vid)
+            && self.pid.is_none_or(|p| p == pid)
+            && self
+                .serial_number
+                .as_deref()
+                .is_none_or(|s| Some(s) == serial_number)
+    }
+
+    /// Looks for a connected device matching the filter and returns its path.
+    pub fn find_port(&self) -> Result<String, String> {
+        let ports = serialport::available_ports().map_err(|e| e.to_string())?;
+        ports
+            .into_iter()
+            .find(|port| match &port.port_type {
+                SerialPortType::UsbPort(info) => {
+                    self.matches(info.vid, info.pid, info.serial_number.as_deref())
+                }
+                _ => false,
+            })
+            .map(|port| port.port_name)
+            .ok_or_else(|| format!("No serial port matching {self:?}"))
+    }
+}
+
+/// Parses a USB vendor or product id, written in hex with or without `0x`.
+fn parse_usb_id(value: &str) -> Result<u16, String> {
+    let value = value.trim();
+    let digits = value
+        .strip_prefix("0x")
+        .or_else(|| value.strip_prefix("0X"))
+        .unwrap_or(value);
+    u16::from_str_radix(digits, 16).map_err(|e| format!("invalid USB id {value}: {e}"))
+}
+
+/// Returns true when the settings select the serial backend.
+pub fn is_configured(settings: &HashMap<String, String>) -> bool {
+    [
+        "serial_port",
+        "serial_vid",
+        "serial_pid",
+        "serial_serial_number",
+    ]
+    .iter()
+    .any(|key| settings.contains_key(*key))
+}
+
+enum SerialError {
+    /// The link to the dongle is broken, reopening the port may fix it.
+    Io(String),
+    /// The dongle answered, but with an error.
+    Gateway(String),
+}
+
+/// Byte stream to the dongle.
+pub trait Stream: Read + Write + Send {}
+
+impl<T: Read + Write + Send> Stream for T {}
+
+/// Opens the byte stream to the dongle, again after each disconnection.
+pub trait Connector: Send {
+    /// Returns the stream and a name describing where it is connected.
+    fn connect(&mut self) -> Result<(Box<dyn Stream>, String), String>;
+}
+
+/// Opens the configured port, or the first USB device matching the filter.
+pub struct SerialConnector {
+    path: Option<String>,
+    usb_filter: UsbFilter,
+    speed: u32,
+}
+
+impl Connector for SerialConnector {
+    fn connect(&mut self) -> Result<(Box<dyn Stream>, String), String> {
+        let port_name = match &self.path {
+            Some(path) => path.to_string(),
+            None => self.usb_filter.find_port()?,
+        };
+        let port = serialport::new(&port_name, self.speed)
+            .timeout(Duration::from_millis(100))
+            .open()
+            .map_err(|e| format!("Failed to open port {port_name}: {e}"))?;
+        Ok((Box::new(port), format!("serial:{port_name}")))
+    }
+}
+
+/// Client for the dongle line protocol, over a serial port or any other byte stream.
 pub struct SerialClient {
-    port: Box<dyn serialport::SerialPort>,
+    connector: Box<dyn Connector>,
+    port: Box<dyn Stream>,
+    gateway: String,
+    ack_timeout: Duration,
+    /// Reopening attempts before giving up on a broken link.
+    reconnect_attempts: u32,
     buffer: Vec<u8>,
-    data_packets: VecDeque<Vec<u8>>,
+    events: VecDeque<SerialEvent>,
+    data_packets: VecDeque<ReceivedFrame>,
+    // State restored after a reconnection.
+    network_id: Option<Vec<u8>>,
+    listening: bool,
 }
 
 pub fn new(settings: &HashMap<String, String>) -> Result<SerialClient, String> {
-    let port = serialport::new(
-        settings.get("serial_port").unwrap(),
-        settings.get("serial_speed").unwrap().parse().unwrap(),
+    let path = settings.get("serial_port").cloned();
+    let usb_filter = UsbFilter::from_settings(settings)?;
+    if path.is_none() && usb_filter.is_empty() {
+        return Err(
+            "serial_port or serial_vid/serial_pid/serial_serial_number required".to_string(),
+        );
+    }
+    let speed = parse_setting(settings, "serial_speed")?.ok_or("serial_speed required")?;
+
+    SerialClient::connect(
+        Box::new(SerialConnector {
+            path,
+            usb_filter,
+            speed,
+        }),
+        settings,
     )
-    .timeout(Duration::from_millis(5000))
-    .open()
-    .expect("Failed to open port");
-
-    Ok(SerialClient {
-        port: port,
-        buffer: vec![],
-        data_packets: VecDeque::new(),
-    })
 }
 
-impl RFClient for SerialClient {
-    fn set_network_id(&mut self, network_id: Vec<u8>) -> Result<(), String> {
-        let cmd = format!("NID: {}", hex::encode(network_id));
+impl SerialClient {
+    /// Connects to the dongle, with the settings common to all the streams.
+    pub fn connect(
+        mut connector: Box<dyn Connector>,
+        settings: &HashMap<String, String>,
+    ) -> Result<SerialClient, String> {
+        // Firmwares acknowledging their commands opt in, the others never answer them.
+        let ack_timeout = parse_setting(settings, "serial_ack_timeout_ms")?.unwrap_or(0);
+        let reconnect_attempts =
+            parse_setting(settings, "serial_reconnect_attempts")?.unwrap_or(10);
+        if reconnect_attempts == 0 {
+            return Err("invalid serial_reconnect_attempts: 0".to_string());
+        }
+        let (port, name) = connector.connect()?;
+
+        Ok(SerialClient {
+            connector,
+            port,
+            gateway: settings.get("gateway_id").cloned().unwrap_or(name),
+            ack_timeout: Duration::from_millis(ack_timeout),
+            reconnect_attempts,
+            buffer: vec![],
+            events: VecDeque::new(),
+            data_packets: VecDeque::new(),
+            network_id: None,
+            listening: false,
+        })
+    }
+
+    /// Runs an operation, reopening the port and retrying it while the link is broken,
+    /// until the port can't be reopened.
+    fn with_reconnect<T>(
+        &mut self,
+        mut operation: impl FnMut(&mut SerialClient) -> Result<T, SerialError>,
+    ) -> Result<T, String> {
+        loop {
+            match operation(self) {
+                Ok(v) => return Ok(v),
+                Err(SerialError::Gateway(message)) => return Err(message),
+                Err(SerialError::Io(message)) => {
+                    log!(Error, "Gateway {} disconnected: {message}", self.gateway);
+                    self.reconnect()?;
+                }
+            }
+        }
+    }
+
+    fn reconnect(&mut self) -> Result<(), String> {
+        let mut delay = RECONNECT_INITIAL_DELAY;
+        for attempt in 1..=self.reconnect_attempts {
+            sleep(delay);
+            match self.reopen() {
+                Ok(()) => {
+                    log!(Info, "Gateway {} reconnected", self.gateway);
+                    return Ok(());
+                }
+                Err(SerialError::Io(message)) | Err(SerialError::Gateway(message)) => {
+                    if attempt == self.reconnect_attempts {
+                        return Err(format!(
+                            "Gateway {} unreachable after {attempt} attempts: {message}",
+                            self.gateway
+                        ));
+                    }
+                    delay = (delay * 2).min(RECONNECT_MAX_DELAY);
+                    log!(
+                        Error,
+                        "Gateway reconnection failed, retrying in {delay:?}: {message}"
+                    );
+                }
+            }
+        }
+        unreachable!("at least one attempt")
+    }
+
+    /// Reopens the port, then restores the network id and listen mode.
+    fn reopen(&mut self) -> Result<(), SerialError> {
+        let (port, _) = self.connector.connect().map_err(SerialError::Io)?;
+        self.port = port;
+        self.buffer.clear();
+        self.events.clear();
+
+        if let Some(network_id) = self.network_id.clone() {
+            self.command(Command::NetworkId, Some(&hex::encode(network_id)))?;
+        }
+        if self.listening {
+            self.command(Command::Listen, None)?;
+        }
+        Ok(())
+    }
+
+    fn write_command(
+        &mut self,
+        command: Command,
+        argument: Option<&str>,
+    ) -> Result<(), SerialError> {
         self.port
-            .write_all(cmd.as_bytes())
-            .map(|_x| ())
-            .map_err(|e| e.to_string())?;
-        self.port.flush().map_err(|e| e.to_string())
+            .write_all(command.line(argument).as_bytes())
+            .map_err(|e| SerialError::Io(e.to_string()))?;
+        self.port
+            .flush()
+            .map_err(|e| SerialError::Io(e.to_string()))
+    }
+
+    /// Sends a command and waits for the dongle to acknowledge it.
+    fn command(&mut self, command: Command, argument: Option<&str>) -> Result<(), SerialError> {
+        self.write_command(command, argument)?;
+        if self.ack_timeout.is_zero() {
+            return Ok(());
+        }
+
+        let deadline = Instant::now() + self.ack_timeout;
+        while Instant::now() < deadline {
+            match self.next_event()? {
+                Some(SerialEvent::Ack(acked)) if acked == command => return Ok(()),
+                Some(SerialEvent::Error(message)) => {
+                    return Err(SerialError::Gateway(format!(
+                        "Gateway refused {command}: {message}"
+                    )))
+                }
+                Some(event) => self.handle_unsolicited(event),
+                None => {}
+            }
+        }
+        Err(SerialError::Gateway(format!(
+            "No acknowledgement from gateway for {command}"
+        )))
+    }
+
+    /// Handles an event which isn't the answer to a pending command.
+    fn handle_unsolicited(&mut self, event: SerialEvent) {
+        match event {
+            SerialEvent::Data { data, rssi, lqi } => {
+                let mut frame = ReceivedFrame::new(data, self.gateway.clone());
+                frame.rssi = rssi;
+                frame.lqi = lqi;
+                self.data_packets.push_back(frame)
+            }
+            SerialEvent::Ack(command) => {
+                log!(Debug, "Gateway: unexpected acknowledgement of {command}")
+            }
+            SerialEvent::Error(message) => log!(Error, "Gateway error: {message}"),
+            SerialEvent::Info(message) => log!(Info, "Gateway: {message}"),
+        }
     }
 
-    fn receive(&mut self) -> Result<Vec<u8>, String> {
+    /// Listens until a frame arrives, or until the deadline.
+    fn listen(&mut self, deadline: Option<Instant>) -> Result<Option<ReceivedFrame>, String> {
         if let Some(data) = self.data_packets.pop_front() {
-            return Ok(data);
+            return Ok(Some(data));
         }
 
-        let cmd = format!("LST:");
-        self.port
-            .write_all(cmd.as_bytes())
-            .map(|_x| ())
-            .map_err(|e| e.to_string())?;
-        self.port.flush().map_err(|e| e.to_string())?;
+        self.with_reconnect(|client| {
+            client.command(Command::Listen, None)?;
+            client.listening = true;
 
-        loop {
-            let mut buf = [0; 512];
-            let read = match self.port.read(&mut buf) {
-                Ok(v) => Ok(v),
-                Err(e) => match e.kind() {
-                    std::io::ErrorKind::TimedOut => Ok(0 as usize),
-                    error => Err(error.to_string()),
-                },
-            }?;
-
-            for n in 0..read {
-                if buf[n] == 0xd {
-                    // \r
-                    continue;
+            loop {
+                if let Some(data) = client.data_packets.pop_front() {
+                    return Ok(Some(data));
                 }
-                if buf[n] == 0xA {
-                    // \r
-                    let data = hex::decode(&self.buffer)
-                        .map_err(|e| {
-                            // println!("{}: {}", e, String::from_utf8(self.buffer.clone()).unwrap())
-                        })
-                        .unwrap_or(vec![]);
-
-                    if !data.is_empty() {
-                        self.data_packets.push_back(data)
-                    }
-                    self.buffer.clear();
-                } else {
-                    self.buffer.push(buf[n]);
+                if deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+                    return Ok(None);
+                }
+                if let Some(event) = client.next_event()? {
+                    client.handle_unsolicited(event);
                 }
             }
+        })
+    }
+
+    /// Returns the next line sent by the dongle, or `None` if nothing arrived before the port timeout.
+    fn next_event(&mut self) -> Result<Option<SerialEvent>, SerialError> {
+        if let Some(event) = self.events.pop_front() {
+            return Ok(Some(event));
+        }
+
+        let mut buf = [0; 512];
+        let read = match self.port.read(&mut buf) {
+            Ok(0) => Err(SerialError::Io("end of stream".to_string())),
+            Ok(v) => Ok(v),
+            Err(e) => match e.kind() {
+                // Sockets report their read timeout as WouldBlock.
+                ErrorKind::TimedOut | ErrorKind::WouldBlock | ErrorKind::Interrupted => Ok(0_usize),
+                _ => Err(SerialError::Io(e.to_string())),
+            },
+        }?;
 
-            if let Some(data) = self.data_packets.pop_front() {
-                return Ok(data);
+        for &byte in &buf[..read] {
+            if byte == 0xd {
+                // \r
+                continue;
+            }
+            if byte == 0xA {
+                // \n
+                if let Some(event) = SerialEvent::parse(&String::from_utf8_lossy(&self.buffer)) {
+                    self.events.push_back(event);
+                }
+                self.buffer.clear();
+            } else {
+                self.buffer.push(byte);
             }
         }
+
+        Ok(self.events.pop_front())
+    }
+}
+
+impl RFClient for SerialClient {
+    fn set_network_id(&mut self, network_id: Vec<u8>) -> Result<(), String> {
+        let argument = hex::encode(&network_id);
+        self.with_reconnect(|client| client.command(Command::NetworkId, Some(&argument)))?;
+        self.network_id = Some(network_id);
+        Ok(())
+    }
+
+    fn receive(&mut self) -> Result<ReceivedFrame, String> {
+        self.listen(None)
+            .map(|frame| frame.expect("listening without deadline"))
+    }
+
+    fn receive_timeout(&mut self, timeout: Duration) -> Result<Option<ReceivedFrame>, String> {
+        self.listen(Some(Instant::now() + timeout))
     }
 
     fn send(&mut self, payload: Vec<u8>) -> Result<(), String> {
-        let cmd = format!("CMD: {}", hex::encode(payload));
-        self.port
-            .write_all(cmd.as_bytes())
-            .map(|_x| ())
-            .map_err(|e| e.to_string())?;
-        self.port.flush().map_err(|e| e.to_string())
+        let argument = hex::encode(payload);
+        self.with_reconnect(|client| client.command(Command::Send, Some(&argument)))
     }
 
     fn sleep(&mut self) -> Result<(), String> {
-        let cmd = format!("SLP:");
-        self.port
-            .write_all(cmd.as_bytes())
-            .map(|_x| ())
-            .map_err(|e| e.to_string())?;
-        self.port.flush().map_err(|e| e.to_string())
+        self.with_reconnect(|client| client.command(Command::Sleep, None))?;
+        self.listening = false;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+    use std::sync::{Arc, Mutex};
+
+    use super::*;
+
+    /// A dongle answering with scripted lines, and recording what it was sent.
+    #[derive(Clone, Default)]
+    struct FakeDongle {
+        input: Arc<Mutex<VecDeque<u8>>>,
+        written: Arc<Mutex<String>>,
+        broken: Arc<Mutex<bool>>,
+    }
+
+    impl FakeDongle {
+        fn written(&self) -> String {
+            self.written.lock().unwrap().clone()
+        }
+    }
+
+    impl Read for FakeDongle {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            if *self.broken.lock().unwrap() {
+                return Err(io::Error::from(ErrorKind::BrokenPipe));
+            }
+            let mut input = self.input.lock().unwrap();
+            if input.is_empty() {
+                sleep(Duration::from_millis(10));
+                return Err(io::Error::from(ErrorKind::TimedOut));
+            }
+            let read = input.len().min(buf.len());
+            for (byte, input) in buf.iter_mut().zip(input.drain(..read)) {
+                *byte = input;
+            }
+            Ok(read)
+        }
+    }
+
+    impl Write for FakeDongle {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            if *self.broken.lock().unwrap() {
+                return Err(io::Error::from(ErrorKind::BrokenPipe));
+            }
+            self.written
+                .lock()
+                .unwrap()
+                .push_str(&String::from_utf8_lossy(buf));
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    /// Hands out the dongles in turn, failing when there's none left.
+    struct FakeConnector(VecDeque<Result<FakeDongle, String>>);
+
+    impl Connector for FakeConnector {
+        fn connect(&mut self) -> Result<(Box<dyn Stream>, String), String> {
+            let dongle = self.0.pop_front().unwrap_or(Err("unplugged".to_string()))?;
+            Ok((Box::new(dongle), "fake".to_string()))
+        }
+    }
+
+    #[test]
+    fn test_without_acknowledgements() {
+        let dongle = FakeDongle::default();
+        let mut client = SerialClient::connect(
+            Box::new(FakeConnector(VecDeque::from([Ok(dongle.clone())]))),
+            &HashMap::new(),
+        )
+        .unwrap();
+        client.set_network_id(vec![0x05, 0xda, 0x2e, 0xe2]).unwrap();
+        client.send(vec![0x08, 0x80]).unwrap();
+        client.sleep().unwrap();
+        assert_eq!(dongle.written(), "NID: 05da2ee2CMD: 0880SLP:");
+    }
+
+    #[test]
+    fn test_reconnect() {
+        let (first, second) = (FakeDongle::default(), FakeDongle::default());
+        first
+            .input
+            .lock()
+            .unwrap()
+            .extend(b"0b0080d3c802410405d7199e\n");
+        let connector = FakeConnector(VecDeque::from([
+            Ok(first.clone()),
+            Err("busy".to_string()),
+            Ok(second.clone()),
+        ]));
+        let settings = HashMap::from([("serial_reconnect_attempts".to_string(), "2".to_string())]);
+        let mut client = SerialClient::connect(Box::new(connector), &settings).unwrap();
+        client.set_network_id(vec![0x05, 0xda, 0x2e, 0xe2]).unwrap();
+        assert!(client
+            .receive_timeout(Duration::from_millis(100))
+            .unwrap()
+            .is_some());
+
+        // Unplugged, then failing to reopen once.
+        *first.broken.lock().unwrap() = true;
+        client.send(vec![0x08, 0x80]).unwrap();
+        assert_eq!(second.written(), "NID: 05da2ee2LST:CMD: 0880");
+
+        // Giving up when it can't be reopened.
+        *second.broken.lock().unwrap() = true;
+        assert_eq!(
+            client.send(vec![0x08, 0x80]),
+            Err("Gateway fake unreachable after 2 attempts: unplugged".to_string())
+        );
+    }
+
+    #[test]
+    fn test_usb_filter() {
+        let settings = HashMap::from([
+            ("serial_vid".to_string(), "0x10c4".to_string()),
+            ("serial_pid".to_string(), "EA60".to_string()),
+        ]);
+        let filter = UsbFilter::from_settings(&settings).unwrap();
+        assert_eq!(filter.vid, Some(0x10c4));
+        assert_eq!(filter.pid, Some(0xea60));
+        assert!(filter.matches(0x10c4, 0xea60, Some("0001")));
+        assert!(!filter.matches(0x10c4, 0x6001, None));
+
+        let filter = UsbFilter {
+            serial_number: Some("A50285BI".to_string()),
+            ..UsbFilter::default()
+        };
+        assert!(filter.matches(0x0403, 0x6001, Some("A50285BI")));
+        assert!(!filter.matches(0x0403, 0x6001, Some("A50285BJ")));
+        assert!(!filter.matches(0x0403, 0x6001, None));
+
+        assert!(UsbFilter::from_settings(&HashMap::new())
+            .unwrap()
+            .is_empty());
+        assert!(parse_usb_id("zz").is_err());
+        assert_eq!(parse_usb_id("0X0403"), Ok(0x0403));
     }
 }
@@ -0,0 +1,182 @@
+use std::fmt;
+
+/// Commands understood by the dongle firmware, written as `<PREFIX> <argument>`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Command {
+    NetworkId,
+    Send,
+    Sleep,
+    Listen,
+}
+
+impl Command {
+    pub fn prefix(&self) -> &'static str {
+        match self {
+            Command::NetworkId => "NID:",
+            Command::Send => "CMD:",
+            Command::Sleep => "SLP:",
+            Command::Listen => "LST:",
+        }
+    }
+
+    fn from_name(name: &str) -> Option<Command> {
+        match name
+            .trim()
+            .trim_end_matches(':')
+            .to_ascii_uppercase()
+            .as_str()
+        {
+            "NID" => Some(Command::NetworkId),
+            "CMD" => Some(Command::Send),
+            "SLP" => Some(Command::Sleep),
+            "LST" => Some(Command::Listen),
+            _ => None,
+        }
+    }
+
+    /// Formats the line sent to the dongle for this command.
+    pub fn line(&self, argument: Option<&str>) -> String {
+        match argument {
+            Some(argument) => format!("{} {}", self.prefix(), argument),
+            None => self.prefix().to_string(),
+        }
+    }
+}
+
+impl fmt::Display for Command {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.prefix().trim_end_matches(':'))
+    }
+}
+
+/// A line received from the dongle.
+///
+/// - a hex string, optionally followed by radio metadata (`RSSI:-72 LQI:40`), is a frame
+/// - `OK:<CMD>` acknowledges a command, e.g. `OK:NID`
+/// - `ERR:<message>` reports a failure
+/// - anything else (boot banner, debug output) is informational
+#[derive(Debug, Clone, PartialEq)]
+pub enum SerialEvent {
+    Data {
+        data: Vec<u8>,
+        rssi: Option<i16>,
+        lqi: Option<u8>,
+    },
+    Ack(Command),
+    Error(String),
+    Info(String),
+}
+
+impl SerialEvent {
+    pub fn parse(line: &str) -> Option<SerialEvent> {
+        let line = line.trim();
+        if line.is_empty() {
+            return None;
+        }
+        if let Some(name) = strip_prefix_ignore_case(line, "OK:") {
+            if let Some(command) = Command::from_name(name) {
+                return Some(SerialEvent::Ack(command));
+            }
+        }
+        if let Some(message) = strip_prefix_ignore_case(line, "ERR:") {
+            return Some(SerialEvent::Error(message.trim().to_string()));
+        }
+        if let Some((data, rssi, lqi)) = parse_data_line(line) {
+            return Some(SerialEvent::Data { data, rssi, lqi });
+        }
+        Some(SerialEvent::Info(line.to_string()))
+    }
+}
+
+fn strip_prefix_ignore_case<'a>(line: &'a str, prefix: &str) -> Option<&'a str> {
+    match line.get(..prefix.len()) {
+        Some(start) if start.eq_ignore_ascii_case(prefix) => Some(&line[prefix.len()..]),
+        _ => None,
+    }
+}
+
+/// Parses a data line sent by the dongle: the hex encoded frame, optionally
+/// followed by radio metadata such as `RSSI:-72 LQI:40`.
+fn parse_data_line(line: &str) -> Option<(Vec<u8>, Option<i16>, Option<u8>)> {
+    let mut fields = line.split(|c: char| c.is_whitespace() || c == ';' || c == ',');
+    let data = hex::decode(fields.next()?).ok()?;
+    if data.is_empty() {
+        return None;
+    }
+
+    let mut rssi = None;
+    let mut lqi = None;
+    for field in fields {
+        if let Some((key, value)) = field.split_once([':', '=']) {
+            match key.to_ascii_lowercase().as_str() {
+                "rssi" => rssi = value.trim().parse().ok(),
+                "lqi" => lqi = value.trim().parse().ok(),
+                _ => {}
+            }
+        }
+    }
+    Some((data, rssi, lqi))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_data_line() {
+        assert_eq!(
+            parse_data_line("0b0080d3c802410405d7199e"),
+            Some((hex::decode("0b0080d3c802410405d7199e").unwrap(), None, None))
+        );
+        assert_eq!(
+            parse_data_line("06802020948241 RSSI:-72 LQI:40"),
+            Some((hex::decode("06802020948241").unwrap(), Some(-72), Some(40)))
+        );
+        assert_eq!(
+            parse_data_line("06802020948241;rssi=-101"),
+            Some((hex::decode("06802020948241").unwrap(), Some(-101), None))
+        );
+        assert_eq!(parse_data_line("Frisquet gateway ready"), None);
+        assert_eq!(parse_data_line(""), None);
+    }
+
+    #[test]
+    fn test_parse_events() {
+        assert_eq!(
+            SerialEvent::parse("06802020948241 RSSI:-72\r"),
+            Some(SerialEvent::Data {
+                data: hex::decode("06802020948241").unwrap(),
+                rssi: Some(-72),
+                lqi: None
+            })
+        );
+        assert_eq!(
+            SerialEvent::parse("OK:NID"),
+            Some(SerialEvent::Ack(Command::NetworkId))
+        );
+        assert_eq!(
+            SerialEvent::parse("ok:lst"),
+            Some(SerialEvent::Ack(Command::Listen))
+        );
+        assert_eq!(
+            SerialEvent::parse("ERR: invalid network id"),
+            Some(SerialEvent::Error("invalid network id".to_string()))
+        );
+        assert_eq!(
+            SerialEvent::parse("OK:FOO"),
+            Some(SerialEvent::Info("OK:FOO".to_string()))
+        );
+        assert_eq!(
+            SerialEvent::parse("Frisquet gateway v1.2 ready"),
+            Some(SerialEvent::Info("Frisquet gateway v1.2 ready".to_string()))
+        );
+        assert_eq!(SerialEvent::parse("  "), None);
+    }
+
+    #[test]
+    fn test_command_line() {
+        assert_eq!(Command::NetworkId.line(Some("05da2ee2")), "NID: 05da2ee2");
+        assert_eq!(Command::Listen.line(None), "LST:");
+        assert_eq!(Command::Send.to_string(), "CMD");
+    }
+}
@@ -0,0 +1,132 @@
+use std::collections::HashMap;
+use std::net::{TcpStream, ToSocketAddrs};
+use std::time::Duration;
+
+use crate::rf::serial::{Connector, SerialClient, Stream};
+use crate::settings::parse_setting;
+
+/// Connects to a dongle exposed on the network, by ser2net or a Wi-Fi bridge.
+pub struct TcpConnector {
+    address: String,
+    connect_timeout: Duration,
+}
+
+impl Connector for TcpConnector {
+    fn connect(&mut self) -> Result<(Box<dyn Stream>, String), String> {
+        let addresses = self
+            .address
+            .to_socket_addrs()
+            .map_err(|e| format!("Failed to resolve {}: {e}", self.address))?;
+
+        let mut error = format!("No address for {}", self.address);
+        for address in addresses {
+            match TcpStream::connect_timeout(&address, self.connect_timeout) {
+                Ok(stream) => {
+                    stream
+                        .set_read_timeout(Some(Duration::from_millis(100)))
+                        .map_err(|e| e.to_string())?;
+                    stream.set_nodelay(true).map_err(|e| e.to_string())?;
+                    return Ok((Box::new(stream), format!("tcp:{}", self.address)));
+                }
+                Err(e) => error = format!("Failed to connect {address}: {e}"),
+            }
+        }
+        Err(error)
+    }
+}
+
+/// Returns a client speaking the serial line protocol over TCP.
+pub fn new(settings: &HashMap<String, String>) -> Result<SerialClient, String> {
+    let address = settings.get("tcp_address").ok_or("tcp_address required")?;
+    let connect_timeout = parse_setting(settings, "tcp_connect_timeout_ms")?.unwrap_or(5000);
+
+    SerialClient::connect(
+        Box::new(TcpConnector {
+            address: address.to_string(),
+            connect_timeout: Duration::from_millis(connect_timeout),
+        }),
+        settings,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::net::TcpListener;
+    use std::thread;
+
+    use super::*;
+    use crate::rf::RFClient;
+
+    /// Reads one command, which is not terminated by a newline.
+    fn read_command(stream: &mut TcpStream) -> String {
+        let mut buf = [0; 64];
+        let read = stream.read(&mut buf).unwrap();
+        String::from_utf8_lossy(&buf[..read]).to_string()
+    }
+
+    #[test]
+    fn test_tcp_gateway() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+
+        let gateway = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            stream.write_all(b"Frisquet gateway ready\n").unwrap();
+            assert_eq!(read_command(&mut stream), "NID: 05da2ee2");
+            stream.write_all(b"OK:NID\n").unwrap();
+            assert_eq!(read_command(&mut stream), "LST:");
+            stream
+                .write_all(b"OK:LST\r\n0b0080d3c802410405d7199e RSSI:-70\r\n")
+                .unwrap();
+            drop(stream);
+
+            // The client restores its state after reconnecting.
+            let (mut stream, _) = listener.accept().unwrap();
+            assert_eq!(read_command(&mut stream), "NID: 05da2ee2");
+            stream.write_all(b"OK:NID\n").unwrap();
+            assert_eq!(read_command(&mut stream), "LST:");
+            stream.write_all(b"OK:LST\n").unwrap();
+            assert_eq!(read_command(&mut stream), "LST:");
+            stream.write_all(b"OK:LST\n06802020948241\n").unwrap();
+            assert_eq!(read_command(&mut stream), "CMD: 088020830001430000");
+            stream.write_all(b"ERR:radio busy\n").unwrap();
+            BufReader::new(stream).lines().count()
+        });
+
+        let settings = HashMap::from([
+            ("tcp_address".to_string(), address.clone()),
+            ("serial_ack_timeout_ms".to_string(), "2000".to_string()),
+        ]);
+        let mut client = new(&settings).unwrap();
+        client.set_network_id(vec![0x05, 0xda, 0x2e, 0xe2]).unwrap();
+
+        let frame = client.receive().unwrap();
+        assert_eq!(frame.data, hex::decode("0b0080d3c802410405d7199e").unwrap());
+        assert_eq!(frame.rssi, Some(-70));
+        assert_eq!(frame.gateway, format!("tcp:{address}"));
+
+        let frame = client.receive().unwrap();
+        assert_eq!(frame.data, hex::decode("06802020948241").unwrap());
+
+        assert_eq!(
+            client.send(hex::decode("088020830001430000").unwrap()),
+            Err("Gateway refused CMD: radio busy".to_string())
+        );
+        drop(client);
+        gateway.join().unwrap();
+    }
+
+    #[test]
+    fn test_connect_failure() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        drop(listener);
+
+        let settings = HashMap::from([
+            ("tcp_address".to_string(), address),
+            ("tcp_connect_timeout_ms".to_string(), "200".to_string()),
+        ]);
+        assert!(new(&settings).is_err());
+    }
+}
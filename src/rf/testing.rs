@@ -0,0 +1,80 @@
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use crate::rf::{RFClient, ReceivedFrame};
+
+/// What a [`FakeClient`] was asked to do, shared with the test.
+#[derive(Debug, Default)]
+pub struct FakeRadio {
+    pub incoming: VecDeque<ReceivedFrame>,
+    pub sent: Vec<Vec<u8>>,
+    pub network_id: Option<Vec<u8>>,
+    pub sleeping: bool,
+}
+
+/// In-memory client for the tests.
+#[derive(Clone, Default)]
+pub struct FakeClient {
+    pub radio: Arc<Mutex<FakeRadio>>,
+}
+
+impl FakeClient {
+    pub fn with_frames(frames: &[&str]) -> FakeClient {
+        let client = FakeClient::default();
+        for frame in frames {
+            client.push(frame);
+        }
+        client
+    }
+
+    pub fn push(&self, frame: &str) {
+        self.radio
+            .lock()
+            .unwrap()
+            .incoming
+            .push_back(ReceivedFrame::new(hex::decode(frame).unwrap(), "fake"));
+    }
+
+    pub fn sent(&self) -> Vec<String> {
+        self.radio
+            .lock()
+            .unwrap()
+            .sent
+            .iter()
+            .map(hex::encode)
+            .collect()
+    }
+}
+
+impl RFClient for FakeClient {
+    fn set_network_id(&mut self, network_id: Vec<u8>) -> Result<(), String> {
+        self.radio.lock().unwrap().network_id = Some(network_id);
+        Ok(())
+    }
+
+    fn receive(&mut self) -> Result<ReceivedFrame, String> {
+        let mut radio = self.radio.lock().unwrap();
+        radio.sleeping = false;
+        radio
+            .incoming
+            .pop_front()
+            .ok_or_else(|| "No more frames".to_string())
+    }
+
+    fn receive_timeout(&mut self, _timeout: Duration) -> Result<Option<ReceivedFrame>, String> {
+        let mut radio = self.radio.lock().unwrap();
+        radio.sleeping = false;
+        Ok(radio.incoming.pop_front())
+    }
+
+    fn send(&mut self, payload: Vec<u8>) -> Result<(), String> {
+        self.radio.lock().unwrap().sent.push(payload);
+        Ok(())
+    }
+
+    fn sleep(&mut self) -> Result<(), String> {
+        self.radio.lock().unwrap().sleeping = true;
+        Ok(())
+    }
+}
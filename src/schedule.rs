@@ -0,0 +1,475 @@
+//! Weekly heating programmes driving the emulated satellites, with holidays and one-off overrides.
+//!
+//! The programme of a zone is a list of periods, e.g.
+//! `schedule_zone1 = "mon-fri 06:30-08:00 confort; sat,sun 08:00-23:00 confort"`,
+//! the zone being in reduced mode outside of them.
+
+use std::collections::{BTreeMap, HashMap};
+use std::time::{Duration, Instant};
+
+use chrono::{Datelike, NaiveDate, NaiveDateTime, NaiveTime, Weekday};
+
+use crate::cli::log;
+use crate::commands;
+use crate::rf::request::{self, RequestOptions};
+use crate::rf::{RFClient, ReceivedFrame};
+use crate::settings::parse_setting;
+use crate::state::{satellite_addr, HeatingState, ZoneMode};
+use crate::watchdog::Watchdog;
+
+/// A time range of a weekly programme, ending the next day when it ends before it starts.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Period {
+    pub days: Vec<Weekday>,
+    pub start: NaiveTime,
+    /// `None` for a period ending at midnight, written `24:00`.
+    pub end: Option<NaiveTime>,
+    pub mode: ZoneMode,
+}
+
+impl Period {
+    fn contains(&self, now: NaiveDateTime) -> bool {
+        let (day, time) = (now.weekday(), now.time());
+        match self.end {
+            None => self.days.contains(&day) && self.start <= time,
+            Some(end) if self.start <= end => {
+                self.days.contains(&day) && self.start <= time && time < end
+            }
+            Some(end) => {
+                (self.days.contains(&day) && self.start <= time)
+                    || (self.days.contains(&day.pred()) && time < end)
+            }
+        }
+    }
+}
+
+/// A one-off mode between two local times, sent as a derogation.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Override {
+    pub start: NaiveDateTime,
+    pub end: NaiveDateTime,
+    pub mode: ZoneMode,
+}
+
+/// Target temperatures of each mode, in °C.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Setpoints {
+    pub confort: f64,
+    pub reduit: f64,
+    pub hors_gel: f64,
+}
+
+impl Setpoints {
+    pub fn consigne(&self, mode: ZoneMode) -> f64 {
+        match mode {
+            ZoneMode::Confort => self.confort,
+            ZoneMode::Reduit => self.reduit,
+            ZoneMode::HorsGel => self.hors_gel,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ZoneSchedule {
+    pub periods: Vec<Period>,
+    pub overrides: Vec<Override>,
+    pub setpoints: Setpoints,
+}
+
+/// What a zone's satellite should send.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Target {
+    pub mode: ZoneMode,
+    pub consigne: f64,
+    pub derogation: bool,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Schedule {
+    pub zones: BTreeMap<u8, ZoneSchedule>,
+    /// Inclusive date ranges the zones are kept frost-free.
+    pub holidays: Vec<(NaiveDate, NaiveDate)>,
+}
+
+fn parse_weekday(day: &str) -> Result<Weekday, String> {
+    day.parse().map_err(|_| format!("invalid day {day}"))
+}
+
+/// Parses days like `mon-fri`, `sat,sun` or `wed`.
+fn parse_days(days: &str) -> Result<Vec<Weekday>, String> {
+    let mut parsed = vec![];
+    for part in days.split(',') {
+        match part.split_once('-') {
+            Some((first, last)) => {
+                let (mut day, last) = (parse_weekday(first)?, parse_weekday(last)?);
+                parsed.push(day);
+                while day != last {
+                    day = day.succ();
+                    parsed.push(day);
+                }
+            }
+            None => parsed.push(parse_weekday(part)?),
+        }
+    }
+    Ok(parsed)
+}
+
+fn parse_time(time: &str) -> Result<NaiveTime, String> {
+    NaiveTime::parse_from_str(time, "%H:%M").map_err(|_| format!("invalid time {time}"))
+}
+
+/// Splits a `;`-separated setting, skipping the empty entries.
+fn entries(value: &str) -> impl Iterator<Item = &str> {
+    value
+        .split(';')
+        .map(str::trim)
+        .filter(|entry| !entry.is_empty())
+}
+
+/// Parses a programme like `mon-fri 06:30-08:00 confort; sat,sun 08:00-23:00 confort`.
+pub fn parse_periods(value: &str) -> Result<Vec<Period>, String> {
+    entries(value)
+        .map(|entry| {
+            let [days, times, mode] = entry.split_whitespace().collect::<Vec<_>>()[..] else {
+                return Err(format!(
+                    "invalid period {entry}, expected <days> <HH:MM>-<HH:MM> <mode>"
+                ));
+            };
+            let (start, end) = times
+                .split_once('-')
+                .ok_or_else(|| format!("invalid times {times}"))?;
+            Ok(Period {
+                days: parse_days(days)?,
+                start: parse_time(start)?,
+                end: match end {
+                    "24:00" => None,
+                    end => Some(parse_time(end)?),
+                },
+                mode: mode.parse()?,
+            })
+        })
+        .collect()
+}
+
+/// Parses overrides like `2026-10-20T18:00..2026-10-20T23:00 confort`.
+pub fn parse_overrides(value: &str) -> Result<Vec<Override>, String> {
+    let parse = |time: &str| {
+        NaiveDateTime::parse_from_str(time, "%Y-%m-%dT%H:%M")
+            .map_err(|_| format!("invalid time {time}"))
+    };
+    entries(value)
+        .map(|entry| {
+            let (range, mode) = entry
+                .split_once(' ')
+                .ok_or_else(|| format!("invalid override {entry}"))?;
+            let (start, end) = range
+                .split_once("..")
+                .ok_or_else(|| format!("invalid range {range}"))?;
+            Ok(Override {
+                start: parse(start)?,
+                end: parse(end)?,
+                mode: mode.trim().parse()?,
+            })
+        })
+        .collect()
+}
+
+/// Parses holidays like `2026-12-20..2027-01-03`.
+pub fn parse_holidays(value: &str) -> Result<Vec<(NaiveDate, NaiveDate)>, String> {
+    let parse = |date: &str| {
+        NaiveDate::parse_from_str(date, "%Y-%m-%d").map_err(|_| format!("invalid date {date}"))
+    };
+    entries(value)
+        .map(|entry| match entry.split_once("..") {
+            Some((first, last)) => Ok((parse(first)?, parse(last)?)),
+            None => parse(entry).map(|date| (date, date)),
+        })
+        .collect()
+}
+
+impl Schedule {
+    /// Reads the `schedule_zone<n>*` and `schedule_holidays` settings, when a zone has a programme.
+    pub fn from_settings(settings: &HashMap<String, String>) -> Result<Option<Schedule>, String> {
+        let range = commands::ConsigneRange::from_settings(settings)?;
+        let setting = |key: &str, default: f64| -> Result<f64, String> {
+            range
+                .validate(parse_setting(settings, key)?.unwrap_or(default))
+                .map_err(|e| format!("invalid {key}: {e}"))
+        };
+        let mut zones = BTreeMap::new();
+        for zone in (1..).take_while(|zone| satellite_addr(*zone).is_some()) {
+            let prefix = format!("schedule_zone{zone}");
+            let Some(periods) = settings.get(&prefix) else {
+                continue;
+            };
+            zones.insert(
+                zone,
+                ZoneSchedule {
+                    periods: parse_periods(periods)?,
+                    overrides: parse_overrides(
+                        settings
+                            .get(&format!("{prefix}_overrides"))
+                            .map_or("", |v| v.as_str()),
+                    )?,
+                    setpoints: Setpoints {
+                        confort: setting(&format!("{prefix}_confort"), 20.0)?,
+                        reduit: setting(&format!("{prefix}_reduit"), 17.0)?,
+                        hors_gel: setting(&format!("{prefix}_hors_gel"), 8.0)?,
+                    },
+                },
+            );
+        }
+        if zones.is_empty() {
+            return Ok(None);
+        }
+        Ok(Some(Schedule {
+            zones,
+            holidays: parse_holidays(settings.get("schedule_holidays").map_or("", |v| v.as_str()))?,
+        }))
+    }
+
+    /// The target of a zone at a local time: an override, else frost protection
+    /// during the holidays, else the weekly programme.
+    pub fn target(&self, zone: u8, now: NaiveDateTime) -> Option<Target> {
+        let schedule = self.zones.get(&zone)?;
+        let (mode, derogation) = if let Some(o) = schedule
+            .overrides
+            .iter()
+            .find(|o| o.start <= now && now < o.end)
+        {
+            (o.mode, true)
+        } else if self
+            .holidays
+            .iter()
+            .any(|(first, last)| (*first..=*last).contains(&now.date()))
+        {
+            (ZoneMode::HorsGel, false)
+        } else {
+            let mode = schedule
+                .periods
+                .iter()
+                .find(|period| period.contains(now))
+                .map_or(ZoneMode::Reduit, |period| period.mode);
+            (mode, false)
+        };
+        Some(Target {
+            mode,
+            consigne: schedule.setpoints.consigne(mode),
+            derogation,
+        })
+    }
+}
+
+/// Delay before sending an unacknowledged target again.
+const RETRY_DELAY: Duration = Duration::from_secs(60);
+
+/// Sends each scheduled zone's target from its emulated satellite, when it changes
+/// and every `schedule_interval_secs` like the real satellites.
+pub struct ScheduleRunner {
+    schedule: Schedule,
+    interval: Duration,
+    retry_delay: Duration,
+    /// The last target sent to each zone, and when to send it again.
+    sent: BTreeMap<u8, (Instant, Target)>,
+    request_id: u16,
+    request_options: RequestOptions,
+}
+
+/// Loads the schedule when a zone has a programme.
+pub fn new(settings: &HashMap<String, String>) -> Result<Option<ScheduleRunner>, String> {
+    let Some(schedule) = Schedule::from_settings(settings)? else {
+        return Ok(None);
+    };
+    let interval = parse_setting(settings, "schedule_interval_secs")?.unwrap_or(600);
+    Ok(Some(ScheduleRunner {
+        schedule,
+        interval: Duration::from_secs(interval),
+        retry_delay: RETRY_DELAY,
+        sent: BTreeMap::new(),
+        request_id: 0x1b00,
+        request_options: RequestOptions::from_settings(settings)?,
+    }))
+}
+
+impl ScheduleRunner {
+    /// Sends the targets due at a local time, returning the boiler's answers.
+    ///
+    /// The satellite frames carry the room temperature, so a zone is only driven
+    /// once its temperature is known. The zones are kept frost-free while the
+    /// watchdog reports a fault, and left alone while it halts the emulation.
+    pub fn poll(
+        &mut self,
+        client: &mut dyn RFClient,
+        state: &HeatingState,
+        now: NaiveDateTime,
+        watchdog: &mut Watchdog,
+    ) -> Vec<ReceivedFrame> {
+        if watchdog.halted() {
+            return vec![];
+        }
+        let mut answers = vec![];
+        let zones: Vec<u8> = self.schedule.zones.keys().copied().collect();
+        for zone in zones {
+            let Some(mut target) = self.schedule.target(zone, now) else {
+                continue;
+            };
+            if watchdog.frost_protection() {
+                target = Target {
+                    mode: ZoneMode::HorsGel,
+                    consigne: self.schedule.zones[&zone].setpoints.hors_gel,
+                    derogation: false,
+                };
+            }
+            let due = match self.sent.get(&zone) {
+                Some((next, sent)) => *sent != target || Instant::now() >= *next,
+                None => true,
+            };
+            let Some(temperature) = state.zones.get(&zone).and_then(|z| z.temperature) else {
+                continue;
+            };
+            if !due {
+                continue;
+            }
+            self.request_id = self.request_id.wrapping_add(4);
+            let result = commands::setpoint_frame(
+                zone,
+                temperature,
+                target.consigne,
+                target.mode,
+                target.derogation,
+                self.request_id,
+            )
+            .and_then(|frame| {
+                request::request(client, &frame, &self.request_options).map_err(|e| e.to_string())
+            });
+            watchdog.sent(result.is_ok());
+            let delay = match result {
+                Ok(_) => self.interval,
+                Err(_) => self.retry_delay,
+            };
+            self.sent.insert(zone, (Instant::now() + delay, target));
+            match result {
+                Ok((_, answer)) => answers.push(answer),
+                Err(e) => log!(
+                    Error,
+                    "Scheduled setpoint of zone {zone} not acknowledged: {e}"
+                ),
+            }
+        }
+        answers
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rf::testing::FakeClient;
+    use crate::watchdog::WatchdogSettings;
+
+    fn settings() -> HashMap<String, String> {
+        HashMap::from([
+            (
+                "schedule_zone1".to_string(),
+                "mon-fri 06:30-08:00 confort; sat,sun 08:00-23:00 confort; fri 23:00-02:00 confort"
+                    .to_string(),
+            ),
+            ("schedule_zone1_confort".to_string(), "21".to_string()),
+            (
+                "schedule_zone1_overrides".to_string(),
+                "2026-10-21T12:00..2026-10-21T14:00 confort".to_string(),
+            ),
+            (
+                "schedule_holidays".to_string(),
+                "2026-12-20..2027-01-03".to_string(),
+            ),
+            ("request_timeout_ms".to_string(), "10".to_string()),
+            ("request_retries".to_string(), "0".to_string()),
+        ])
+    }
+
+    fn at(time: &str) -> NaiveDateTime {
+        NaiveDateTime::parse_from_str(time, "%Y-%m-%d %H:%M").unwrap()
+    }
+
+    #[test]
+    fn test_target() {
+        let schedule = Schedule::from_settings(&settings()).unwrap().unwrap();
+        let target = |time| schedule.target(1, at(time)).unwrap();
+        // Monday 19 October 2026.
+        assert_eq!(
+            target("2026-10-19 07:00"),
+            Target {
+                mode: ZoneMode::Confort,
+                consigne: 21.0,
+                derogation: false
+            }
+        );
+        assert_eq!(target("2026-10-19 08:00").mode, ZoneMode::Reduit);
+        assert_eq!(target("2026-10-19 08:00").consigne, 17.0);
+        // Friday night, until Saturday 02:00.
+        assert_eq!(target("2026-10-24 01:00").mode, ZoneMode::Confort);
+        assert_eq!(target("2026-10-24 03:00").mode, ZoneMode::Reduit);
+        assert!(target("2026-10-21 13:00").derogation);
+        assert_eq!(target("2026-12-25 07:00").mode, ZoneMode::HorsGel);
+        assert_eq!(schedule.target(2, at("2026-10-19 07:00")), None);
+
+        assert!(parse_periods("mon-fri 06:30 confort").is_err());
+        assert!(parse_periods("mon-fri 06:30-08:00 turbo").is_err());
+
+        let mut settings = settings();
+        settings.insert("schedule_zone1_confort".to_string(), "35".to_string());
+        assert_eq!(
+            Schedule::from_settings(&settings),
+            Err("invalid schedule_zone1_confort: consigne 35 out of range 5..28".to_string())
+        );
+    }
+
+    #[test]
+    fn test_end_of_day() {
+        let periods = parse_periods("sat 00:00-24:00 confort; mon 20:00-24:00 confort").unwrap();
+        assert_eq!(periods[0].end, None);
+        let contains = |time| periods.iter().any(|p| p.contains(at(time)));
+        assert!(contains("2026-10-24 00:00"));
+        assert!(contains("2026-10-24 23:59"));
+        assert!(!contains("2026-10-25 00:00"));
+        assert!(contains("2026-10-19 23:30"));
+        assert!(!contains("2026-10-20 00:30"));
+    }
+
+    #[test]
+    fn test_poll() {
+        let mut runner = new(&settings()).unwrap().unwrap();
+        runner.retry_delay = Duration::from_millis(50);
+        let mut state = HeatingState::default();
+        let client = FakeClient::default();
+        let mut watchdog = crate::watchdog::for_tests(WatchdogSettings {
+            safe_outdoor_temperature: None,
+            answer_timeout: Duration::ZERO,
+            stall_timeout: None,
+            alert_command: None,
+        });
+        let mut poll = |state: &HeatingState, time: &str| {
+            runner.poll(&mut client.clone(), state, at(time), &mut watchdog);
+            watchdog.check();
+        };
+        // The room temperature is unknown yet.
+        poll(&state, "2026-10-19 07:00");
+        assert!(client.sent().is_empty());
+
+        state.zones.entry(1).or_default().temperature = Some(19.5);
+        poll(&state, "2026-10-19 07:00");
+        poll(&state, "2026-10-19 07:00");
+        let (_, confort) = crate::frisquet::parse_data_from_str(&client.sent()[0]).unwrap();
+        assert!(format!("{confort:?}").contains("consigne: 210"));
+        // Unanswered: the boiler is silent, the zone goes frost-free.
+        poll(&state, "2026-10-19 07:30");
+        let sent = client.sent();
+        assert_eq!(sent.len(), 2);
+        let (_, frost) = crate::frisquet::parse_data_from_str(&sent[1]).unwrap();
+        assert!(format!("{frost:?}").contains("hors_gel: true"));
+        // Unanswered again, sent after the retry delay only.
+        std::thread::sleep(Duration::from_millis(60));
+        poll(&state, "2026-10-19 07:30");
+        assert_eq!(client.sent().len(), 3);
+    }
+}
@@ -0,0 +1,37 @@
+//! Typed values of the settings.
+
+use std::collections::HashMap;
+use std::str::FromStr;
+
+/// Parses a setting, `None` when it isn't set.
+pub fn parse_setting<T: FromStr>(
+    settings: &HashMap<String, String>,
+    key: &str,
+) -> Result<Option<T>, String> {
+    settings
+        .get(key)
+        .map(|v| v.parse().map_err(|_| format!("invalid {key}: {v}")))
+        .transpose()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_setting() {
+        let settings = HashMap::from([
+            ("request_retries".to_string(), "3".to_string()),
+            ("request_timeout_ms".to_string(), "soon".to_string()),
+        ]);
+        assert_eq!(parse_setting(&settings, "request_retries"), Ok(Some(3)));
+        assert_eq!(
+            parse_setting::<u32>(&settings, "request_backoff_ms"),
+            Ok(None)
+        );
+        assert_eq!(
+            parse_setting::<u64>(&settings, "request_timeout_ms"),
+            Err("invalid request_timeout_ms: soon".to_string())
+        );
+    }
+}
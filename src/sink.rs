@@ -0,0 +1,25 @@
+use std::time::SystemTime;
+
+use crate::frisquet::proto::{FrisquetData, FrisquetMetadata};
+use crate::rf::ReceivedFrame;
+use crate::state::{Device, HeatingState};
+
+/// Consumer of the decoded frames and of the state they update, e.g. an MQTT bridge.
+pub trait Sink {
+    fn name(&self) -> &str;
+
+    /// Handles a decoded frame, `state` being already updated with it.
+    fn frame(
+        &mut self,
+        frame: &ReceivedFrame,
+        metadata: &FrisquetMetadata,
+        data: &FrisquetData,
+        state: &HeatingState,
+        devices: &[Device],
+    ) -> Result<(), String>;
+
+    /// Called now and then, even when nothing is heard.
+    fn tick(&mut self, _state: &HeatingState, _now: SystemTime) -> Result<(), String> {
+        Ok(())
+    }
+}
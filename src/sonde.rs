@@ -0,0 +1,113 @@
+//! Emulated sonde, sending the outdoor temperature to the boiler now and then.
+
+use std::collections::HashMap;
+use std::time::{Duration, Instant, SystemTime};
+
+use crate::cli::log;
+use crate::commands;
+use crate::rf::request::{self, RequestOptions};
+use crate::rf::{RFClient, ReceivedFrame};
+use crate::settings::parse_setting;
+use crate::temperature::{self, Outdoor};
+use crate::watchdog::Watchdog;
+
+pub struct Sonde {
+    outdoor: Outdoor,
+    interval: Duration,
+    last_sent: Option<Instant>,
+    request_id: u16,
+    request_options: RequestOptions,
+}
+
+/// Emulates the sonde when an outdoor temperature source is configured.
+pub fn new(settings: &HashMap<String, String>) -> Result<Option<Sonde>, String> {
+    let Some(outdoor) = temperature::new(settings)? else {
+        return Ok(None);
+    };
+    let interval = parse_setting(settings, "sonde_interval_secs")?.unwrap_or(600);
+    Ok(Some(Sonde {
+        outdoor,
+        interval: Duration::from_secs(interval),
+        last_sent: None,
+        request_id: 0x1a00,
+        request_options: RequestOptions::from_settings(settings)?,
+    }))
+}
+
+impl Sonde {
+    /// Sends the temperature when due, returning the boiler's answer.
+    ///
+    /// The watchdog supplies a safe temperature when the source is lost, or halts the sonde.
+    pub fn poll(
+        &mut self,
+        client: &mut dyn RFClient,
+        watchdog: &mut Watchdog,
+    ) -> Option<ReceivedFrame> {
+        if self
+            .last_sent
+            .is_some_and(|last_sent| last_sent.elapsed() < self.interval)
+        {
+            return None;
+        }
+        self.last_sent = Some(Instant::now());
+        let temperature = match self.outdoor.temperature(SystemTime::now()) {
+            Ok(temperature) => {
+                watchdog.outdoor_ok();
+                temperature
+            }
+            Err(e) => watchdog.outdoor_lost(client, &e, self.outdoor.fallback)?,
+        };
+        let answer = self.send(client, temperature);
+        watchdog.sent(answer.is_some());
+        answer
+    }
+
+    /// Sends a temperature as the sonde, returning the boiler's answer.
+    pub fn send(&mut self, client: &mut dyn RFClient, temperature: f64) -> Option<ReceivedFrame> {
+        self.request_id = self.request_id.wrapping_add(4);
+        let result = commands::sonde_frame(temperature, self.request_id).and_then(|frame| {
+            request::request(client, &frame, &self.request_options).map_err(|e| e.to_string())
+        });
+        match result {
+            Ok((_, answer)) => Some(answer),
+            Err(e) => {
+                log!(
+                    Error,
+                    "Outdoor temperature {temperature} not acknowledged: {e}"
+                );
+                None
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rf::testing::FakeClient;
+    use crate::watchdog::WatchdogSettings;
+
+    #[test]
+    fn test_poll() {
+        let mut sonde = new(&HashMap::from([
+            ("outdoor_source".to_string(), "fixed".to_string()),
+            ("outdoor_fixed".to_string(), "9.2".to_string()),
+            ("request_timeout_ms".to_string(), "10".to_string()),
+            ("request_retries".to_string(), "0".to_string()),
+        ]))
+        .unwrap()
+        .unwrap();
+        let mut watchdog = crate::watchdog::for_tests(WatchdogSettings {
+            safe_outdoor_temperature: None,
+            answer_timeout: Duration::from_secs(60),
+            stall_timeout: None,
+            alert_command: None,
+        });
+        let mut client = FakeClient::with_frames(&["0f20801a048117082304051131172803"]);
+        assert!(sonde.poll(&mut client, &mut watchdog).is_some());
+        assert_eq!(client.sent(), vec!["1180201a0401179c540004a029000102005c"]);
+        // Not due yet.
+        assert!(sonde.poll(&mut client, &mut watchdog).is_none());
+        assert_eq!(client.sent().len(), 1);
+    }
+}
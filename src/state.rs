@@ -0,0 +1,302 @@
+//! Heating state gathered from the decoded frames, for the bridges to publish.
+
+use std::collections::BTreeMap;
+use std::time::{Duration, SystemTime};
+
+use chrono::{DateTime, NaiveDate, NaiveDateTime, Utc};
+use serde::Serialize;
+
+use crate::frisquet::proto::chaudiere::ChaudierePayload;
+use crate::frisquet::proto::satellite::SatellitePayload;
+use crate::frisquet::proto::sonde::SondePayload;
+use crate::frisquet::proto::{FrisquetData, FrisquetMetadata};
+
+pub const BOILER_ADDR: u8 = 0x80;
+pub const SONDE_ADDR: u8 = 0x20;
+
+/// Zone of a satellite address, satellites 0x08 to 0x0a driving zones 1 to 3.
+pub fn zone_number(addr: u8) -> Option<u8> {
+    (0x08..=0x0a).contains(&addr).then(|| addr - 7)
+}
+
+/// Address of the satellite driving a zone.
+pub fn satellite_addr(zone: u8) -> Option<u8> {
+    (1..=3).contains(&zone).then(|| zone + 7)
+}
+
+/// The devices of the installation, as the state is updated per device.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Device {
+    Zone(u8),
+    Sonde,
+    Boiler,
+}
+
+impl Device {
+    /// The device sending from an address.
+    pub fn from_addr(addr: u8) -> Option<Device> {
+        match addr {
+            SONDE_ADDR => Some(Device::Sonde),
+            BOILER_ADDR => Some(Device::Boiler),
+            addr => zone_number(addr).map(Device::Zone),
+        }
+    }
+
+    /// Name of the device in the topics, e.g. `zone1`.
+    pub fn name(&self) -> String {
+        match self {
+            Device::Zone(zone) => format!("zone{zone}"),
+            Device::Sonde => "sonde".to_string(),
+            Device::Boiler => "boiler".to_string(),
+        }
+    }
+}
+
+/// Name of the device at an address, or the address in hex.
+pub fn device_label(addr: u8) -> String {
+    Device::from_addr(addr).map_or_else(|| format!("{addr:#04x}"), |device| device.name())
+}
+
+/// Mode shown by a satellite.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ZoneMode {
+    /// Comfort temperature, the sun on the satellite.
+    Confort,
+    /// Reduced temperature, the moon on the satellite.
+    Reduit,
+    HorsGel,
+}
+
+impl std::str::FromStr for ZoneMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<ZoneMode, String> {
+        match s {
+            "confort" => Ok(ZoneMode::Confort),
+            "reduit" => Ok(ZoneMode::Reduit),
+            "hors_gel" => Ok(ZoneMode::HorsGel),
+            _ => Err(format!("invalid mode {s}")),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct ZoneState {
+    /// Room temperature in °C.
+    pub temperature: Option<f64>,
+    /// Target temperature in °C.
+    pub consigne: Option<f64>,
+    pub mode: Option<ZoneMode>,
+    /// Whether the mode is temporarily overridden until the next schedule change.
+    pub derogation: bool,
+    pub last_seen: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct SondeState {
+    /// Outdoor temperature in °C.
+    pub temperature: Option<f64>,
+    pub last_seen: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct BoilerState {
+    /// Local time of the boiler clock.
+    pub clock: Option<NaiveDateTime>,
+    /// Outdoor temperature used by the boiler, in °C.
+    pub outdoor_temperature: Option<f64>,
+    /// Room temperature of the zone the boiler last answered, in °C.
+    pub temperature: Option<f64>,
+    /// Target temperature of the zone the boiler last answered, in °C.
+    pub consigne: Option<f64>,
+    pub last_seen: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct HeatingState {
+    pub zones: BTreeMap<u8, ZoneState>,
+    pub sonde: SondeState,
+    pub boiler: BoilerState,
+}
+
+fn from_bcd(value: u8) -> u32 {
+    (value >> 4) as u32 * 10 + (value & 0xf) as u32
+}
+
+/// Decodes the BCD clock sent by the boiler.
+fn boiler_clock(
+    year: u8,
+    month: u8,
+    day: u8,
+    hour: u8,
+    minute: u8,
+    second: u8,
+) -> Option<NaiveDateTime> {
+    NaiveDate::from_ymd_opt(2000 + from_bcd(year) as i32, from_bcd(month), from_bcd(day))?
+        .and_hms_opt(from_bcd(hour), from_bcd(minute), from_bcd(second))
+}
+
+/// Converts the tenths of °C carried by the frames.
+pub fn tenths(value: i16) -> f64 {
+    value as f64 / 10.0
+}
+
+impl HeatingState {
+    /// Updates the state with a decoded frame, returning the devices it changed.
+    pub fn update(
+        &mut self,
+        metadata: &FrisquetMetadata,
+        data: &FrisquetData,
+        time: SystemTime,
+    ) -> Vec<Device> {
+        let time = DateTime::<Utc>::from(time);
+        match data {
+            FrisquetData::Satellite(SatellitePayload::SatelliteSetTemperatureMessage {
+                temperature,
+                consigne,
+                hors_gel,
+                derogation,
+                soleil,
+                ..
+            }) => {
+                let Some(zone) = zone_number(metadata.from_addr) else {
+                    return vec![];
+                };
+                let state = self.zones.entry(zone).or_default();
+                state.temperature = Some(tenths(*temperature));
+                state.consigne = Some(tenths(*consigne));
+                state.mode = Some(match (hors_gel, soleil) {
+                    (true, _) => ZoneMode::HorsGel,
+                    (false, true) => ZoneMode::Confort,
+                    (false, false) => ZoneMode::Reduit,
+                });
+                state.derogation = *derogation;
+                state.last_seen = Some(time);
+                vec![Device::Zone(zone)]
+            }
+            FrisquetData::Sonde(SondePayload::SondeTemperatureMessage { temperature, .. }) => {
+                self.sonde.temperature = Some(tenths(*temperature));
+                self.sonde.last_seen = Some(time);
+                vec![Device::Sonde]
+            }
+            FrisquetData::Chaudiere(ChaudierePayload::ChaudiereSondeResponseMessage {
+                year,
+                month,
+                day,
+                hour,
+                minute,
+                second,
+                ..
+            }) => {
+                self.boiler.clock = boiler_clock(*year, *month, *day, *hour, *minute, *second);
+                self.boiler.last_seen = Some(time);
+                vec![Device::Boiler]
+            }
+            FrisquetData::Chaudiere(ChaudierePayload::ChaudiereSetTemperatureMessageResponse {
+                temperature_exterieure,
+                year,
+                month,
+                day,
+                hour,
+                minute,
+                second,
+                temperature,
+                consigne,
+                ..
+            }) => {
+                self.boiler.clock = boiler_clock(*year, *month, *day, *hour, *minute, *second);
+                self.boiler.outdoor_temperature = Some(tenths(*temperature_exterieure));
+                self.boiler.temperature = Some(tenths(*temperature));
+                self.boiler.consigne = Some(tenths(*consigne));
+                self.boiler.last_seen = Some(time);
+                let mut devices = vec![Device::Boiler];
+                // The boiler echoes the zone it answers to.
+                if let Some(zone) = zone_number(metadata.to_addr) {
+                    let state = self.zones.entry(zone).or_default();
+                    state.temperature = Some(tenths(*temperature));
+                    state.consigne = Some(tenths(*consigne));
+                    devices.push(Device::Zone(zone));
+                }
+                devices
+            }
+            _ => vec![],
+        }
+    }
+
+    pub fn last_seen(&self, device: Device) -> Option<DateTime<Utc>> {
+        match device {
+            Device::Zone(zone) => self.zones.get(&zone).and_then(|z| z.last_seen),
+            Device::Sonde => self.sonde.last_seen,
+            Device::Boiler => self.boiler.last_seen,
+        }
+    }
+
+    /// Whether a device was heard within `max_age` of `now`.
+    pub fn is_fresh(&self, device: Device, max_age: Duration, now: SystemTime) -> bool {
+        self.last_seen(device).is_some_and(|last_seen| {
+            DateTime::<Utc>::from(now)
+                .signed_duration_since(last_seen)
+                .to_std()
+                .map_or(true, |age| age <= max_age)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::frisquet;
+
+    fn update(state: &mut HeatingState, frame: &str) -> Vec<Device> {
+        let (metadata, data) = frisquet::parse_data_from_str(frame).unwrap();
+        state.update(&metadata, &data, SystemTime::now())
+    }
+
+    #[test]
+    fn test_update() {
+        let mut state = HeatingState::default();
+        assert_eq!(
+            update(
+                &mut state,
+                "17800819E40117A0290015A02F00040800B200AA002400C6"
+            ),
+            vec![Device::Zone(1)]
+        );
+        assert_eq!(state.zones[&1].temperature, Some(17.8));
+        assert_eq!(state.zones[&1].consigne, Some(17.0));
+        assert_eq!(state.zones[&1].mode, Some(ZoneMode::Reduit));
+
+        assert_eq!(
+            update(&mut state, "118020ba4001179c540004a029000102005c"),
+            vec![Device::Sonde]
+        );
+        assert_eq!(state.sonde.temperature, Some(9.2));
+
+        assert_eq!(
+            update(&mut state, "310880194881172A050A0000230423171012000000C000BE002500C600C604F6000000000000000004F60000000000000000"),
+            vec![Device::Boiler, Device::Zone(1)]
+        );
+        assert_eq!(
+            state.boiler.clock,
+            NaiveDate::from_ymd_opt(2023, 4, 23)
+                .unwrap()
+                .and_hms_opt(17, 10, 12)
+        );
+        assert_eq!(state.zones[&1].temperature, Some(19.2));
+    }
+
+    #[test]
+    fn test_freshness() {
+        let mut state = HeatingState::default();
+        let now = SystemTime::now();
+        assert!(!state.is_fresh(Device::Sonde, Duration::from_secs(60), now));
+        update(&mut state, "118020ba4001179c540004a029000102005c");
+        assert!(state.is_fresh(Device::Sonde, Duration::from_secs(60), now));
+        assert!(!state.is_fresh(
+            Device::Sonde,
+            Duration::from_secs(60),
+            now + Duration::from_secs(120)
+        ));
+    }
+}
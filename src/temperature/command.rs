@@ -0,0 +1,100 @@
+use std::collections::HashMap;
+use std::process::{Command, Stdio};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+
+use crate::settings::parse_setting;
+use crate::temperature::{Reading, TemperatureSource, ValueParser};
+
+/// A shell command printing the temperature, run on each read.
+pub struct CommandSource {
+    command: String,
+    /// The command is killed when it runs longer.
+    timeout: Duration,
+    parser: ValueParser,
+}
+
+/// Reads `outdoor_command`, with `outdoor_command_timeout_secs`, `outdoor_json_pointer`
+/// and `outdoor_scale`.
+pub fn new(settings: &HashMap<String, String>) -> Result<CommandSource, String> {
+    Ok(CommandSource {
+        command: settings
+            .get("outdoor_command")
+            .cloned()
+            .ok_or("outdoor_command is not set")?,
+        timeout: Duration::from_secs(
+            parse_setting(settings, "outdoor_command_timeout_secs")?.unwrap_or(10),
+        ),
+        parser: ValueParser::from_settings(settings)?,
+    })
+}
+
+impl TemperatureSource for CommandSource {
+    fn name(&self) -> String {
+        format!("command `{}`", self.command)
+    }
+
+    fn read(&mut self) -> Result<Reading, String> {
+        let mut child = Command::new("sh")
+            .arg("-c")
+            .arg(&self.command)
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .map_err(|e| e.to_string())?;
+        let deadline = Instant::now() + self.timeout;
+        while child.try_wait().map_err(|e| e.to_string())?.is_none() {
+            if Instant::now() >= deadline {
+                // Its output isn't read, as what it spawned may still hold the pipes.
+                let _ = child.kill();
+                let _ = child.wait();
+                return Err(format!("timed out after {:?}", self.timeout));
+            }
+            thread::sleep(Duration::from_millis(50));
+        }
+        let output = child.wait_with_output().map_err(|e| e.to_string())?;
+        if !output.status.success() {
+            return Err(format!(
+                "{}: {}",
+                output.status,
+                String::from_utf8_lossy(&output.stderr).trim()
+            ));
+        }
+        Ok(Reading {
+            value: self
+                .parser
+                .parse(&String::from_utf8_lossy(&output.stdout))?,
+            time: SystemTime::now(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_command() {
+        let source = |command: &str| {
+            new(&HashMap::from([(
+                "outdoor_command".to_string(),
+                command.to_string(),
+            )]))
+            .unwrap()
+        };
+        assert_eq!(source("echo 12.5").read().map(|r| r.value), Ok(12.5));
+        assert!(source("echo oops >&2; exit 3").read().is_err());
+
+        let mut slow = new(&HashMap::from([
+            ("outdoor_command".to_string(), "sleep 5".to_string()),
+            ("outdoor_command_timeout_secs".to_string(), "1".to_string()),
+        ]))
+        .unwrap();
+        let start = Instant::now();
+        assert_eq!(
+            slow.read().map(|r| r.value),
+            Err("timed out after 1s".to_string())
+        );
+        assert!(start.elapsed() < Duration::from_secs(3));
+    }
+}
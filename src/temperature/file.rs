@@ -0,0 +1,59 @@
+use std::collections::HashMap;
+use std::fs;
+
+use crate::temperature::{Reading, TemperatureSource, ValueParser};
+
+/// A file holding the temperature, e.g. written by another tool or a 1-Wire sensor in sysfs.
+///
+/// The reading is as old as the file, sysfs files being always fresh.
+pub struct FileSource {
+    path: String,
+    parser: ValueParser,
+}
+
+/// Reads `outdoor_file`, with `outdoor_scale` and `outdoor_json_pointer`.
+pub fn new(settings: &HashMap<String, String>) -> Result<FileSource, String> {
+    Ok(FileSource {
+        path: settings
+            .get("outdoor_file")
+            .cloned()
+            .ok_or("outdoor_file is not set")?,
+        parser: ValueParser::from_settings(settings)?,
+    })
+}
+
+impl TemperatureSource for FileSource {
+    fn name(&self) -> String {
+        format!("file {}", self.path)
+    }
+
+    fn read(&mut self) -> Result<Reading, String> {
+        let time = fs::metadata(&self.path)
+            .and_then(|metadata| metadata.modified())
+            .map_err(|e| e.to_string())?;
+        let text = fs::read_to_string(&self.path).map_err(|e| e.to_string())?;
+        Ok(Reading {
+            value: self.parser.parse(&text)?,
+            time,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sysfs_file() {
+        let path = std::env::temp_dir().join(format!("frisquet-outdoor-{}", std::process::id()));
+        fs::write(&path, "8250\n").unwrap();
+        let mut source = new(&HashMap::from([
+            ("outdoor_file".to_string(), path.display().to_string()),
+            ("outdoor_scale".to_string(), "0.001".to_string()),
+        ]))
+        .unwrap();
+        assert_eq!(source.read().map(|r| r.value), Ok(8.25));
+        fs::remove_file(&path).unwrap();
+        assert!(source.read().is_err());
+    }
+}
@@ -0,0 +1,30 @@
+use std::collections::HashMap;
+use std::time::SystemTime;
+
+use crate::settings::parse_setting;
+use crate::temperature::{Reading, TemperatureSource};
+
+/// Always the same temperature, e.g. while testing the installation.
+pub struct FixedSource {
+    value: f64,
+}
+
+/// Reads `outdoor_fixed`.
+pub fn new(settings: &HashMap<String, String>) -> Result<FixedSource, String> {
+    Ok(FixedSource {
+        value: parse_setting(settings, "outdoor_fixed")?.ok_or("outdoor_fixed is not set")?,
+    })
+}
+
+impl TemperatureSource for FixedSource {
+    fn name(&self) -> String {
+        format!("fixed value {}", self.value)
+    }
+
+    fn read(&mut self) -> Result<Reading, String> {
+        Ok(Reading {
+            value: self.value,
+            time: SystemTime::now(),
+        })
+    }
+}
@@ -0,0 +1,76 @@
+use std::collections::HashMap;
+use std::time::{Duration, SystemTime};
+
+use crate::temperature::{Reading, TemperatureSource, ValueParser};
+
+/// A JSON endpoint, e.g. a local weather station, polled on each read.
+pub struct HttpSource {
+    url: String,
+    agent: ureq::Agent,
+    parser: ValueParser,
+}
+
+/// Reads `outdoor_http_url`, with `outdoor_json_pointer` and `outdoor_scale`.
+pub fn new(settings: &HashMap<String, String>) -> Result<HttpSource, String> {
+    Ok(HttpSource {
+        url: settings
+            .get("outdoor_http_url")
+            .cloned()
+            .ok_or("outdoor_http_url is not set")?,
+        agent: ureq::AgentBuilder::new()
+            .timeout(Duration::from_secs(10))
+            .build(),
+        parser: ValueParser::from_settings(settings)?,
+    })
+}
+
+impl TemperatureSource for HttpSource {
+    fn name(&self) -> String {
+        self.url.clone()
+    }
+
+    fn read(&mut self) -> Result<Reading, String> {
+        let body = self
+            .agent
+            .get(&self.url)
+            .call()
+            .map_err(|e| e.to_string())?
+            .into_string()
+            .map_err(|e| e.to_string())?;
+        Ok(Reading {
+            value: self.parser.parse(&body)?,
+            time: SystemTime::now(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_json_endpoint() {
+        let server = tiny_http::Server::http("127.0.0.1:0").unwrap();
+        let mut source = new(&HashMap::from([
+            (
+                "outdoor_http_url".to_string(),
+                format!("http://{}/weather", server.server_addr()),
+            ),
+            (
+                "outdoor_json_pointer".to_string(),
+                "/outdoor/temperature".to_string(),
+            ),
+        ]))
+        .unwrap();
+        let station = std::thread::spawn(move || {
+            let request = server.recv().unwrap();
+            request
+                .respond(tiny_http::Response::from_string(
+                    r#"{"outdoor": {"temperature": -2.5}}"#,
+                ))
+                .unwrap();
+        });
+        assert_eq!(source.read().map(|r| r.value), Ok(-2.5));
+        station.join().unwrap();
+    }
+}
@@ -0,0 +1,179 @@
+//! Sources of the outdoor temperature sent by the emulated sonde.
+
+use std::collections::HashMap;
+use std::time::{Duration, SystemTime};
+
+use serde_json::Value;
+
+use crate::settings::parse_setting;
+
+pub mod command;
+pub mod file;
+pub mod fixed;
+pub mod http;
+pub mod mqtt;
+
+/// A temperature in °C, with the time it was measured.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Reading {
+    pub value: f64,
+    pub time: SystemTime,
+}
+
+pub trait TemperatureSource {
+    /// Describes the source in the logs.
+    fn name(&self) -> String;
+
+    /// Returns the latest reading, which may be old.
+    fn read(&mut self) -> Result<Reading, String>;
+}
+
+/// How a raw value is turned into °C, from the `outdoor_json_pointer` and `outdoor_scale` settings.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ValueParser {
+    /// JSON pointer of the value, e.g. `/main/temp`; the value is plain text when unset.
+    pub json_pointer: Option<String>,
+    /// Factor applied to the value, e.g. 0.001 for the millidegrees of sysfs.
+    pub scale: f64,
+}
+
+impl ValueParser {
+    pub fn from_settings(settings: &HashMap<String, String>) -> Result<ValueParser, String> {
+        Ok(ValueParser {
+            json_pointer: settings.get("outdoor_json_pointer").cloned(),
+            scale: parse_setting(settings, "outdoor_scale")?.unwrap_or(1.0),
+        })
+    }
+
+    pub fn parse(&self, text: &str) -> Result<f64, String> {
+        let value = match &self.json_pointer {
+            None => text.trim().parse::<f64>().ok(),
+            Some(pointer) => {
+                let json: Value =
+                    serde_json::from_str(text).map_err(|e| format!("invalid JSON: {e}"))?;
+                match json.pointer(pointer) {
+                    // Home Assistant states are strings.
+                    Some(Value::String(value)) => value.trim().parse().ok(),
+                    Some(value) => value.as_f64(),
+                    None => return Err(format!("no value at {pointer}")),
+                }
+            }
+        };
+        let value = value.ok_or_else(|| format!("not a temperature: {}", text.trim()))?;
+        Ok(value * self.scale)
+    }
+}
+
+/// The configured source, with its staleness limit and fallback.
+pub struct Outdoor {
+    source: Box<dyn TemperatureSource>,
+    /// Readings older than this are stale.
+    pub max_age: Duration,
+    /// Sent instead of `failsafe_outdoor_temperature` when the source fails or is stale.
+    pub fallback: Option<f64>,
+}
+
+impl Outdoor {
+    pub fn new(
+        source: Box<dyn TemperatureSource>,
+        max_age: Duration,
+        fallback: Option<f64>,
+    ) -> Outdoor {
+        Outdoor {
+            source,
+            max_age,
+            fallback,
+        }
+    }
+
+    /// The temperature to send, failing when the source fails or is stale.
+    pub fn temperature(&mut self, now: SystemTime) -> Result<f64, String> {
+        self.source
+            .read()
+            .and_then(|reading| {
+                let age = now.duration_since(reading.time).unwrap_or_default();
+                if age > self.max_age {
+                    Err(format!("reading is {}s old", age.as_secs()))
+                } else {
+                    Ok(reading.value)
+                }
+            })
+            .map_err(|e| format!("{}: {e}", self.source.name()))
+    }
+}
+
+/// Builds the source picked by `outdoor_source`, when set.
+pub fn new(settings: &HashMap<String, String>) -> Result<Option<Outdoor>, String> {
+    let Some(kind) = settings.get("outdoor_source") else {
+        return Ok(None);
+    };
+    let source: Box<dyn TemperatureSource> = match kind.as_str() {
+        "fixed" => Box::new(fixed::new(settings)?),
+        "file" => Box::new(file::new(settings)?),
+        "mqtt" => Box::new(mqtt::new(settings)?),
+        "http" => Box::new(http::new(settings)?),
+        "command" => Box::new(command::new(settings)?),
+        kind => return Err(format!("unknown outdoor_source {kind}")),
+    };
+    let max_age = parse_setting(settings, "outdoor_max_age_secs")?.unwrap_or(1800);
+    Ok(Some(Outdoor::new(
+        source,
+        Duration::from_secs(max_age),
+        parse_setting(settings, "outdoor_fallback")?,
+    )))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct Stub(Result<Reading, String>);
+
+    impl TemperatureSource for Stub {
+        fn name(&self) -> String {
+            "stub".to_string()
+        }
+
+        fn read(&mut self) -> Result<Reading, String> {
+            self.0.clone()
+        }
+    }
+
+    #[test]
+    fn test_staleness() {
+        let now = SystemTime::now();
+        let reading = |age| {
+            Ok(Reading {
+                value: 9.5,
+                time: now - Duration::from_secs(age),
+            })
+        };
+        let max_age = Duration::from_secs(600);
+        let mut fresh = Outdoor::new(Box::new(Stub(reading(60))), max_age, Some(5.0));
+        assert_eq!(fresh.temperature(now), Ok(9.5));
+        let mut stale = Outdoor::new(Box::new(Stub(reading(900))), max_age, Some(5.0));
+        assert_eq!(
+            stale.temperature(now),
+            Err("stub: reading is 900s old".to_string())
+        );
+        let mut failing = Outdoor::new(Box::new(Stub(Err("down".to_string()))), max_age, None);
+        assert_eq!(failing.temperature(now), Err("stub: down".to_string()));
+    }
+
+    #[test]
+    fn test_value_parser() {
+        let plain = ValueParser {
+            json_pointer: None,
+            scale: 0.001,
+        };
+        assert_eq!(plain.parse("21500\n"), Ok(21.5));
+        let json = ValueParser {
+            json_pointer: Some("/main/temp".to_string()),
+            scale: 1.0,
+        };
+        assert_eq!(json.parse(r#"{"main": {"temp": 7.25}}"#), Ok(7.25));
+        assert_eq!(json.parse(r#"{"main": {"temp": "7.5"}}"#), Ok(7.5));
+        assert!(json.parse(r#"{"main": {}}"#).is_err());
+        assert!(plain.parse("unavailable").is_err());
+    }
+}
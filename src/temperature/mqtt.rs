@@ -0,0 +1,119 @@
+extern crate paho_mqtt as mqtt;
+
+use std::collections::HashMap;
+use std::time::SystemTime;
+
+use mqtt::{Message, Receiver};
+
+use crate::cli::log;
+use crate::rf::mqtt::MqttSettings;
+use crate::temperature::{Reading, TemperatureSource, ValueParser};
+
+/// An MQTT topic, e.g. the state of a Home Assistant sensor, the last message being kept.
+pub struct MqttSource {
+    client: mqtt::Client,
+    rx: Receiver<Option<Message>>,
+    settings: MqttSettings,
+    topic: String,
+    parser: ValueParser,
+    last: Option<Reading>,
+}
+
+/// Subscribes to `outdoor_mqtt_topic`, with `outdoor_json_pointer` and `outdoor_scale`.
+pub fn new(settings: &HashMap<String, String>) -> Result<MqttSource, String> {
+    let topic = settings
+        .get("outdoor_mqtt_topic")
+        .cloned()
+        .ok_or("outdoor_mqtt_topic is not set")?;
+    let mqtt_settings = MqttSettings::from_settings(settings)?;
+    let mqtt_settings =
+        mqtt_settings.with_client_id(format!("{}-outdoor", mqtt_settings.client_id));
+    let (client, rx) = crate::rf::mqtt::connect(&mqtt_settings)?;
+    client
+        .subscribe(&topic, mqtt_settings.qos)
+        .map_err(|e| format!("Error subscribing to {topic}: {e}"))?;
+    Ok(MqttSource {
+        client,
+        rx,
+        settings: mqtt_settings,
+        topic,
+        parser: ValueParser::from_settings(settings)?,
+        last: None,
+    })
+}
+
+impl TemperatureSource for MqttSource {
+    fn name(&self) -> String {
+        format!("MQTT topic {}", self.topic)
+    }
+
+    fn read(&mut self) -> Result<Reading, String> {
+        while let Ok(received) = self.rx.try_recv() {
+            let Some(msg) = received else {
+                crate::rf::mqtt::reconnect(&self.client, &self.settings);
+                if let Err(e) = self.client.subscribe(&self.topic, self.settings.qos) {
+                    log!(Error, "Error subscribing to {}: {e}", self.topic);
+                }
+                continue;
+            };
+            match self.parser.parse(&msg.payload_str()) {
+                Ok(value) => {
+                    self.last = Some(Reading {
+                        value,
+                        time: SystemTime::now(),
+                    })
+                }
+                Err(e) => log!(Debug, "Ignoring {}: {e}", self.topic),
+            }
+        }
+        self.last.ok_or_else(|| "nothing received yet".to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread::sleep;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn test_retained_state() {
+        let broker = crate::emulator::broker::start("127.0.0.1:0").unwrap();
+        let settings = HashMap::from([
+            ("broker".to_string(), format!("tcp://{}", broker.address)),
+            ("mqtt_client".to_string(), "outdoor-test".to_string()),
+            (
+                "mqtt_frisquet_topic".to_string(),
+                "frisquet/receive".to_string(),
+            ),
+            (
+                "outdoor_mqtt_topic".to_string(),
+                "homeassistant/sensor/outdoor/state".to_string(),
+            ),
+        ]);
+        let (publisher, _) = crate::rf::mqtt::connect(
+            &MqttSettings::from_settings(&settings)
+                .unwrap()
+                .with_client_id("outdoor-test-publisher"),
+        )
+        .unwrap();
+        publisher
+            .publish(Message::new_retained(
+                "homeassistant/sensor/outdoor/state",
+                "6.4",
+                0,
+            ))
+            .unwrap();
+
+        let mut source = new(&settings).unwrap();
+        let deadline = Instant::now() + Duration::from_secs(5);
+        let reading = loop {
+            match source.read() {
+                Ok(reading) => break reading,
+                Err(_) if Instant::now() < deadline => sleep(Duration::from_millis(20)),
+                Err(e) => panic!("{e}"),
+            }
+        };
+        assert_eq!(reading.value, 6.4);
+    }
+}
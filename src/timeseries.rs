@@ -0,0 +1,405 @@
+//! Temperature histories as InfluxDB line protocol or CSV, written to a file, stdout or InfluxDB.
+
+use std::collections::HashMap;
+use std::fs::OpenOptions;
+use std::io::{self, Write};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::frisquet::proto::chaudiere::ChaudierePayload;
+use crate::frisquet::proto::satellite::SatellitePayload;
+use crate::frisquet::proto::sonde::SondePayload;
+use crate::frisquet::proto::{FrisquetData, FrisquetMetadata};
+use crate::rf::ReceivedFrame;
+use crate::sink::Sink;
+use crate::state::{tenths, zone_number, Device, HeatingState};
+
+/// Columns of the CSV output, in order. New columns go at the end.
+pub const CSV_COLUMNS: [&str; 6] = [
+    "timestamp",
+    "device",
+    "zone",
+    "temperature",
+    "consigne",
+    "outdoor_temperature",
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Influx,
+    Csv,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Output {
+    Stdout,
+    File(String),
+    /// InfluxDB write endpoint, e.g. `http://localhost:8086/api/v2/write?org=home&bucket=heating`.
+    Http(String),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct TimeseriesSettings {
+    pub format: Format,
+    pub output: Output,
+    pub measurement: String,
+    /// Sent as `Authorization: Token <token>` to the HTTP endpoint.
+    pub token: Option<String>,
+}
+
+impl TimeseriesSettings {
+    /// Reads the `timeseries_*` settings, the output being enabled by `timeseries_format`.
+    pub fn from_settings(
+        settings: &HashMap<String, String>,
+    ) -> Result<Option<TimeseriesSettings>, String> {
+        let format = match settings.get("timeseries_format").map(|v| v.as_str()) {
+            None => return Ok(None),
+            Some("influx") => Format::Influx,
+            Some("csv") => Format::Csv,
+            Some(format) => return Err(format!("invalid timeseries_format: {format}")),
+        };
+        let output = match settings.get("timeseries_output").map(|v| v.as_str()) {
+            None | Some("-") => Output::Stdout,
+            // ureq is built without TLS.
+            Some(url) if url.starts_with("https://") => {
+                return Err(format!(
+                    "invalid timeseries_output: {url}, https isn't supported"
+                ))
+            }
+            Some(url) if url.starts_with("http://") => Output::Http(url.to_string()),
+            Some(path) => Output::File(path.to_string()),
+        };
+        if format == Format::Csv && matches!(output, Output::Http(_)) {
+            return Err("CSV can only be written to a file or stdout".to_string());
+        }
+        Ok(Some(TimeseriesSettings {
+            format,
+            output,
+            measurement: settings
+                .get("timeseries_measurement")
+                .cloned()
+                .unwrap_or_else(|| "frisquet".to_string()),
+            token: settings.get("timeseries_token").cloned(),
+        }))
+    }
+}
+
+/// Temperatures of one frame, in °C.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Point {
+    pub time: SystemTime,
+    pub device: Device,
+    /// Zone the boiler answered, for its frames.
+    pub zone: Option<u8>,
+    pub temperature: Option<f64>,
+    pub consigne: Option<f64>,
+    pub outdoor_temperature: Option<f64>,
+}
+
+/// Extracts the temperatures of the frames carrying some.
+pub fn point(metadata: &FrisquetMetadata, data: &FrisquetData, time: SystemTime) -> Option<Point> {
+    let device = Device::from_addr(metadata.from_addr)?;
+    let point = Point {
+        time,
+        device,
+        zone: None,
+        temperature: None,
+        consigne: None,
+        outdoor_temperature: None,
+    };
+    match data {
+        FrisquetData::Satellite(SatellitePayload::SatelliteSetTemperatureMessage {
+            temperature,
+            consigne,
+            ..
+        }) => Some(Point {
+            zone: zone_number(metadata.from_addr),
+            temperature: Some(tenths(*temperature)),
+            consigne: Some(tenths(*consigne)),
+            ..point
+        }),
+        FrisquetData::Sonde(SondePayload::SondeTemperatureMessage { temperature, .. }) => {
+            Some(Point {
+                outdoor_temperature: Some(tenths(*temperature)),
+                ..point
+            })
+        }
+        FrisquetData::Chaudiere(ChaudierePayload::ChaudiereSetTemperatureMessageResponse {
+            temperature_exterieure,
+            temperature,
+            consigne,
+            ..
+        }) => Some(Point {
+            zone: zone_number(metadata.to_addr),
+            temperature: Some(tenths(*temperature)),
+            consigne: Some(tenths(*consigne)),
+            outdoor_temperature: Some(tenths(*temperature_exterieure)),
+            ..point
+        }),
+        _ => None,
+    }
+}
+
+fn fields(point: &Point) -> impl Iterator<Item = (&'static str, f64)> {
+    [
+        ("temperature", point.temperature),
+        ("consigne", point.consigne),
+        ("outdoor_temperature", point.outdoor_temperature),
+    ]
+    .into_iter()
+    .filter_map(|(name, value)| Some((name, value?)))
+}
+
+/// Formats a point as a line of InfluxDB line protocol, with a nanosecond timestamp.
+pub fn influx_line(measurement: &str, point: &Point) -> String {
+    let mut line = format!("{measurement},device={}", point.device.name());
+    if let Some(zone) = point.zone {
+        line.push_str(&format!(",zone={zone}"));
+    }
+    let fields: Vec<String> = fields(point)
+        .map(|(name, value)| format!("{name}={value}"))
+        .collect();
+    let nanos = point
+        .time
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos();
+    format!("{line} {} {nanos}", fields.join(","))
+}
+
+/// Formats a point as a CSV row following [`CSV_COLUMNS`].
+pub fn csv_row(point: &Point) -> String {
+    let cell = |value: Option<f64>| value.map(|v| v.to_string()).unwrap_or_default();
+    let timestamp = chrono::DateTime::<chrono::Utc>::from(point.time);
+    [
+        timestamp.to_rfc3339_opts(chrono::SecondsFormat::Millis, true),
+        point.device.name(),
+        point.zone.map(|z| z.to_string()).unwrap_or_default(),
+        cell(point.temperature),
+        cell(point.consigne),
+        cell(point.outdoor_temperature),
+    ]
+    .join(",")
+}
+
+enum Writer {
+    Stream(Box<dyn Write>),
+    Http { agent: ureq::Agent, url: String },
+}
+
+pub struct Timeseries {
+    settings: TimeseriesSettings,
+    writer: Writer,
+}
+
+/// Starts a stream output, with the CSV header when `header` is set.
+fn stream(mut stream: Box<dyn Write>, header: bool) -> Result<Writer, String> {
+    if header {
+        writeln!(stream, "{}", CSV_COLUMNS.join(",")).map_err(|e| e.to_string())?;
+    }
+    Ok(Writer::Stream(stream))
+}
+
+/// Opens the output when the time series are enabled.
+pub fn new(settings: &HashMap<String, String>) -> Result<Option<Timeseries>, String> {
+    let Some(settings) = TimeseriesSettings::from_settings(settings)? else {
+        return Ok(None);
+    };
+    let header = settings.format == Format::Csv;
+    let writer = match &settings.output {
+        Output::Stdout => stream(Box::new(io::stdout()), header)?,
+        Output::File(path) => {
+            let file = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)
+                .map_err(|e| format!("Error opening {path}: {e}"))?;
+            // Appending to an existing history keeps its header.
+            let empty = file.metadata().map_err(|e| e.to_string())?.len() == 0;
+            stream(Box::new(file), header && empty)
+                .map_err(|e| format!("Error writing {path}: {e}"))?
+        }
+        Output::Http(url) => Writer::Http {
+            agent: ureq::AgentBuilder::new()
+                .timeout(Duration::from_secs(10))
+                .build(),
+            url: url.clone(),
+        },
+    };
+    Ok(Some(Timeseries { settings, writer }))
+}
+
+impl Timeseries {
+    pub fn write(&mut self, point: &Point) -> Result<(), String> {
+        let line = match self.settings.format {
+            Format::Influx => influx_line(&self.settings.measurement, point),
+            Format::Csv => csv_row(point),
+        };
+        match &mut self.writer {
+            Writer::Http { agent, url } => {
+                let mut request = agent
+                    .post(url)
+                    .set("Content-Type", "text/plain; charset=utf-8");
+                if let Some(token) = &self.settings.token {
+                    request = request.set("Authorization", &format!("Token {token}"));
+                }
+                request
+                    .send_string(&line)
+                    .map(|_| ())
+                    .map_err(|e| format!("Error writing to {url}: {e}"))
+            }
+            Writer::Stream(stream) => writeln!(stream, "{line}")
+                .and_then(|_| stream.flush())
+                .map_err(|e| format!("Error writing time series: {e}")),
+        }
+    }
+}
+
+impl Sink for Timeseries {
+    fn name(&self) -> &str {
+        "time series"
+    }
+
+    fn frame(
+        &mut self,
+        frame: &ReceivedFrame,
+        metadata: &FrisquetMetadata,
+        data: &FrisquetData,
+        _state: &HeatingState,
+        _devices: &[Device],
+    ) -> Result<(), String> {
+        match point(metadata, data, frame.timestamp) {
+            Some(point) => self.write(&point),
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::frisquet;
+
+    const BOILER: &str = "310880194881172A050A0000230423171012000000C000BE002500C600C604F6000000000000000004F60000000000000000";
+
+    fn parse(raw: &str) -> Point {
+        let (metadata, data) = frisquet::parse_data_from_str(raw).unwrap();
+        point(
+            &metadata,
+            &data,
+            UNIX_EPOCH + Duration::from_secs(1_682_262_612),
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn test_lines() {
+        assert_eq!(
+            influx_line(
+                "frisquet",
+                &parse("17800819E40117A0290015A02F00040800B200AA002400C6")
+            ),
+            "frisquet,device=zone1,zone=1 temperature=17.8,consigne=17 1682262612000000000"
+        );
+        assert_eq!(
+            influx_line("frisquet", &parse("118020ba4001179c540004a029000102005c")),
+            "frisquet,device=sonde outdoor_temperature=9.2 1682262612000000000"
+        );
+        assert_eq!(
+            csv_row(&parse(BOILER)),
+            "2023-04-23T15:10:12.000Z,boiler,1,19.2,19,1"
+        );
+        assert_eq!(
+            csv_row(&parse("118020ba4001179c540004a029000102005c")),
+            "2023-04-23T15:10:12.000Z,sonde,,,,9.2"
+        );
+    }
+
+    #[test]
+    fn test_http_output() {
+        let server = tiny_http::Server::http("127.0.0.1:0").unwrap();
+        let url = format!(
+            "http://{}/api/v2/write?bucket=heating",
+            server.server_addr()
+        );
+        let mut timeseries = new(&HashMap::from([
+            ("timeseries_format".to_string(), "influx".to_string()),
+            ("timeseries_output".to_string(), url),
+            ("timeseries_token".to_string(), "secret".to_string()),
+        ]))
+        .unwrap()
+        .unwrap();
+
+        let received = std::thread::spawn(move || {
+            let mut request = server.recv().unwrap();
+            let mut body = String::new();
+            request.as_reader().read_to_string(&mut body).unwrap();
+            let authorization = request
+                .headers()
+                .iter()
+                .find(|h| h.field.equiv("Authorization"))
+                .map(|h| h.value.to_string());
+            let url = request.url().to_string();
+            request.respond(tiny_http::Response::empty(204)).unwrap();
+            (url, authorization, body)
+        });
+        timeseries.write(&parse(BOILER)).unwrap();
+        let (url, authorization, body) = received.join().unwrap();
+        assert_eq!(url, "/api/v2/write?bucket=heating");
+        assert_eq!(authorization.as_deref(), Some("Token secret"));
+        assert!(body.starts_with("frisquet,device=boiler,zone=1 "));
+    }
+
+    #[test]
+    fn test_invalid_output() {
+        let settings = |output: &str| {
+            let mut settings =
+                HashMap::from([("timeseries_format".to_string(), "influx".to_string())]);
+            if !output.is_empty() {
+                settings.insert("timeseries_output".to_string(), output.to_string());
+            }
+            TimeseriesSettings::from_settings(&settings)
+        };
+        assert_eq!(settings("").unwrap().unwrap().output, Output::Stdout);
+        assert_eq!(settings("-").unwrap().unwrap().output, Output::Stdout);
+        assert!(settings("https://influx.example.com/api/v2/write").is_err());
+        assert_eq!(
+            settings("temperatures.influx").unwrap().unwrap().output,
+            Output::File("temperatures.influx".to_string())
+        );
+    }
+
+    #[derive(Clone, Default)]
+    struct Buffer(std::rc::Rc<std::cell::RefCell<Vec<u8>>>);
+
+    impl Write for Buffer {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.borrow_mut().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_stdout_output() {
+        let settings = HashMap::from([("timeseries_format".to_string(), "csv".to_string())]);
+        let settings = TimeseriesSettings::from_settings(&settings)
+            .unwrap()
+            .unwrap();
+        assert_eq!(settings.output, Output::Stdout);
+
+        // The stdout writer, on a buffer.
+        let buffer = Buffer::default();
+        let mut timeseries = Timeseries {
+            settings,
+            writer: stream(Box::new(buffer.clone()), true).unwrap(),
+        };
+        timeseries.write(&parse(BOILER)).unwrap();
+        assert_eq!(
+            String::from_utf8(buffer.0.take()).unwrap(),
+            "timestamp,device,zone,temperature,consigne,outdoor_temperature\n\
+             2023-04-23T15:10:12.000Z,boiler,1,19.2,19,1\n"
+        );
+    }
+}
@@ -0,0 +1,299 @@
+//! Failsafe watchdog of the emulated sonde and satellites.
+//!
+//! It degrades predictably: a lost outdoor temperature source is replaced by a safe
+//! value, and the zones are kept frost-free while anything is wrong. When the sonde
+//! has nothing safe to send, the emulation halts and the radio is put to sleep.
+//! When enabled, a stalled main loop exits the process, the radio being owned by that loop.
+
+use std::collections::{BTreeSet, HashMap};
+use std::process::{self, Command};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use crate::cli::log;
+use crate::metrics;
+use crate::rf::RFClient;
+use crate::settings::parse_setting;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Fault {
+    /// The outdoor temperature source failed or is stale.
+    OutdoorLost,
+    /// The boiler stopped answering the emulated devices.
+    BoilerSilent,
+}
+
+impl Fault {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Fault::OutdoorLost => "outdoor_lost",
+            Fault::BoilerSilent => "boiler_silent",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct WatchdogSettings {
+    /// Outdoor temperature sent when the source is lost, the sonde halting when unset.
+    pub safe_outdoor_temperature: Option<f64>,
+    /// The boiler is silent when it hasn't answered for this long.
+    pub answer_timeout: Duration,
+    /// The process exits when the main loop doesn't run for this long, when set.
+    pub stall_timeout: Option<Duration>,
+    /// Shell command run on each alert, the message being in `FRISQUET_ALERT`.
+    pub alert_command: Option<String>,
+}
+
+impl WatchdogSettings {
+    /// Reads the `failsafe_*` settings.
+    pub fn from_settings(settings: &HashMap<String, String>) -> Result<WatchdogSettings, String> {
+        let stall: Option<u64> = parse_setting(settings, "failsafe_stall_secs")?;
+        Ok(WatchdogSettings {
+            safe_outdoor_temperature: parse_setting(settings, "failsafe_outdoor_temperature")?,
+            answer_timeout: Duration::from_secs(
+                parse_setting(settings, "failsafe_answer_timeout_secs")?.unwrap_or(1800),
+            ),
+            // Unset or 0, there's no stall detection.
+            stall_timeout: stall.filter(|&secs| secs > 0).map(Duration::from_secs),
+            alert_command: settings.get("failsafe_alert_command").cloned(),
+        })
+    }
+}
+
+fn unix_seconds() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+pub struct Watchdog {
+    settings: WatchdogSettings,
+    faults: BTreeSet<Fault>,
+    /// Last answer from the boiler to an emulated device, or the start.
+    last_answer: Instant,
+    /// Whether an emulated device sent something since the last answer.
+    awaiting_answer: bool,
+    halted: bool,
+    /// Last time the main loop ran, in seconds since the epoch.
+    heartbeat: Arc<AtomicU64>,
+}
+
+/// Starts the watchdog, with its stall detection thread when enabled.
+pub fn new(settings: &HashMap<String, String>) -> Result<Watchdog, String> {
+    let settings = WatchdogSettings::from_settings(settings)?;
+    let heartbeat = Arc::new(AtomicU64::new(unix_seconds()));
+    if let Some(stall_timeout) = settings.stall_timeout {
+        let (heartbeat, alert_command) = (heartbeat.clone(), settings.alert_command.clone());
+        thread::spawn(move || loop {
+            thread::sleep(Duration::from_secs(5));
+            let stalled_for = unix_seconds().saturating_sub(heartbeat.load(Ordering::Relaxed));
+            if stalled_for > stall_timeout.as_secs() {
+                alert(
+                    alert_command.as_deref(),
+                    &format!("main loop stalled for {stalled_for}s, exiting"),
+                );
+                process::exit(2);
+            }
+        });
+    }
+    Ok(Watchdog::with_settings(settings, heartbeat))
+}
+
+/// Logs an alert and runs the alert command.
+fn alert(command: Option<&str>, message: &str) {
+    log!(Error, "FAILSAFE: {message}");
+    if let Some(command) = command {
+        let spawned = Command::new("sh")
+            .arg("-c")
+            .arg(command)
+            .env("FRISQUET_ALERT", message)
+            .spawn();
+        if let Err(e) = spawned {
+            log!(Error, "Error running the alert command: {e}");
+        }
+    }
+}
+
+impl Watchdog {
+    fn with_settings(settings: WatchdogSettings, heartbeat: Arc<AtomicU64>) -> Watchdog {
+        Watchdog {
+            settings,
+            faults: BTreeSet::new(),
+            last_answer: Instant::now(),
+            awaiting_answer: false,
+            halted: false,
+            heartbeat,
+        }
+    }
+
+    fn raise(&mut self, fault: Fault, message: &str) {
+        if self.faults.insert(fault) {
+            metrics::FAILSAFE_ACTIVE
+                .with_label_values(&[fault.name()])
+                .set(1.0);
+            alert(self.settings.alert_command.as_deref(), message);
+        }
+    }
+
+    fn clear(&mut self, fault: Fault) {
+        if self.faults.remove(&fault) {
+            metrics::FAILSAFE_ACTIVE
+                .with_label_values(&[fault.name()])
+                .set(0.0);
+            log!(Info, "Failsafe: {} recovered", fault.name());
+        }
+    }
+
+    /// Tells the watchdog the main loop is running.
+    pub fn heartbeat(&self) {
+        self.heartbeat.store(unix_seconds(), Ordering::Relaxed);
+    }
+
+    pub fn faults(&self) -> &BTreeSet<Fault> {
+        &self.faults
+    }
+
+    /// Whether the zones must be kept frost-free.
+    pub fn frost_protection(&self) -> bool {
+        !self.faults.is_empty()
+    }
+
+    /// Whether the emulation halted, the radio sleeping.
+    pub fn halted(&self) -> bool {
+        self.halted
+    }
+
+    /// The outdoor temperature to send instead of the lost source's, the source's
+    /// `fallback` or the safe one, halting the emulation when there's neither.
+    pub fn outdoor_lost(
+        &mut self,
+        client: &mut dyn RFClient,
+        error: &str,
+        fallback: Option<f64>,
+    ) -> Option<f64> {
+        let safe = fallback.or(self.settings.safe_outdoor_temperature);
+        let message = match safe {
+            Some(safe) => format!("outdoor temperature lost ({error}), sending {safe}"),
+            None => format!("outdoor temperature lost ({error}), halting the emulation"),
+        };
+        self.raise(Fault::OutdoorLost, &message);
+        if safe.is_none() && !self.halted {
+            self.halted = true;
+            if let Err(e) = client.sleep() {
+                log!(Error, "Error putting the radio to sleep: {e}");
+            }
+        }
+        safe
+    }
+
+    pub fn outdoor_ok(&mut self) {
+        self.clear(Fault::OutdoorLost);
+        self.halted = false;
+    }
+
+    /// Records the outcome of a frame sent by an emulated device.
+    pub fn sent(&mut self, answered: bool) {
+        if answered {
+            self.last_answer = Instant::now();
+            self.awaiting_answer = false;
+            self.clear(Fault::BoilerSilent);
+        } else {
+            self.awaiting_answer = true;
+        }
+    }
+
+    /// Notices the boiler going silent, to be called now and then.
+    pub fn check(&mut self) {
+        self.heartbeat();
+        let silent_for = self.last_answer.elapsed();
+        if self.awaiting_answer && silent_for > self.settings.answer_timeout {
+            self.raise(
+                Fault::BoilerSilent,
+                &format!(
+                    "the boiler hasn't answered for {}s, zones in frost protection",
+                    silent_for.as_secs()
+                ),
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+pub fn for_tests(settings: WatchdogSettings) -> Watchdog {
+    Watchdog::with_settings(settings, Arc::new(AtomicU64::new(0)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rf::testing::FakeClient;
+
+    fn settings(safe: Option<f64>) -> WatchdogSettings {
+        WatchdogSettings {
+            safe_outdoor_temperature: safe,
+            answer_timeout: Duration::ZERO,
+            stall_timeout: None,
+            alert_command: None,
+        }
+    }
+
+    #[test]
+    fn test_stall_detection_is_opt_in() {
+        let stall = |settings: &[(&str, &str)]| {
+            let settings = settings
+                .iter()
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect();
+            WatchdogSettings::from_settings(&settings)
+                .unwrap()
+                .stall_timeout
+        };
+        assert_eq!(stall(&[]), None);
+        assert_eq!(stall(&[("failsafe_stall_secs", "0")]), None);
+        assert_eq!(
+            stall(&[("failsafe_stall_secs", "300")]),
+            Some(Duration::from_secs(300))
+        );
+    }
+
+    #[test]
+    fn test_outdoor_lost() {
+        let mut client = FakeClient::default();
+        let mut watchdog = for_tests(settings(Some(5.0)));
+        assert_eq!(watchdog.outdoor_lost(&mut client, "stale", None), Some(5.0));
+        assert!(watchdog.frost_protection());
+        assert!(!watchdog.halted());
+        watchdog.outdoor_ok();
+        assert!(!watchdog.frost_protection());
+
+        // The source's fallback is sent, the fault still raised.
+        let mut watchdog = for_tests(settings(None));
+        assert_eq!(
+            watchdog.outdoor_lost(&mut client, "stale", Some(10.0)),
+            Some(10.0)
+        );
+        assert!(watchdog.frost_protection());
+        assert!(!watchdog.halted());
+
+        let mut watchdog = for_tests(settings(None));
+        assert_eq!(watchdog.outdoor_lost(&mut client, "stale", None), None);
+        assert!(watchdog.halted());
+        assert!(client.radio.lock().unwrap().sleeping);
+    }
+
+    #[test]
+    fn test_boiler_silent() {
+        let mut watchdog = for_tests(settings(None));
+        // Nothing sent, nothing expected.
+        watchdog.check();
+        assert!(watchdog.faults().is_empty());
+        watchdog.sent(false);
+        watchdog.check();
+        assert!(watchdog.faults().contains(&Fault::BoilerSilent));
+        watchdog.sent(true);
+        assert!(!watchdog.frost_protection());
+    }
+}